@@ -79,6 +79,15 @@ end
 local ask = {}
 ask.default_system = "You are a helpful assistant."
 
+-- Adds the token usage, and whether the response was cut off, to footer params
+function ask.with_result(params, result)
+	if result then
+		params.tokens = result.usage and result.usage.total_tokens
+		params.truncated = result.finish_reason == "length" or nil
+	end
+	return params
+end
+
 -- ============================================================================
 -- Currency data and choices for the convert command
 -- ============================================================================
@@ -117,6 +126,22 @@ local currency_choices = map(currency_data, function(currency)
 	}
 end)
 
+-- Choices for the LLMs shown on Discord. Given to options as a function so
+-- that they're recomputed when the model list changes.
+local function visible_model_choices()
+	return map(
+		filter(llm.models, function(m)
+			return m.metadata.discord_visible
+		end),
+		function(m)
+			return {
+				name = m.id,
+				value = m.id,
+			}
+		end
+	)
+end
+
 -- Build model choices for command options (uses global IMAGE_MODELS from main.lua)
 local model_choices = map(IMAGE_MODELS, function(model)
 	return {
@@ -129,23 +154,15 @@ end)
 discord.register_command {
 	name = "ask",
 	description = "Responds to the provided instruction",
+	-- Continue the conversation in a thread on the response, where every message is a reply
+	thread = true,
 	options = {
 		{
 			name = "model",
 			description = "The model to use",
 			type = "string",
 			required = true,
-			choices = map(
-				filter(llm.models, function(m)
-					return m.metadata.discord_visible
-				end),
-				function(m)
-					return {
-						name = m.id,
-						value = m.id,
-					}
-				end
-			),
+			choices = visible_model_choices,
 		},
 		{
 			name = "prompt",
@@ -167,24 +184,114 @@ discord.register_command {
 			min_value = 0,
 			max_value = 2147483647,
 		},
+		{
+			name = "temperature",
+			description = "Sampling temperature; higher is more random",
+			type = "number",
+			required = false,
+			min_value = 0.0,
+			max_value = 2.0,
+		},
+		{
+			name = "max_tokens",
+			description = "The maximum number of tokens to generate",
+			type = "integer",
+			required = false,
+			min_value = 1,
+		},
 	},
 	execute = function(interaction)
 		local model = interaction.options.model
 		local prompt = interaction.options.prompt
 		local system = interaction.options.system or ask.default_system
 		local seed = interaction.options.seed or math.random(1, 2147483647)
+		local temperature = interaction.options.temperature
+		local max_tokens = interaction.options.max_tokens
 
-		local response = ask_llm {
+		local response, result = ask_llm {
 			prompt = prompt,
 			model = model,
 			system = system,
 			seed = seed,
+			temperature = temperature,
+			max_tokens = max_tokens,
 		}
 
-		output(response .. footer.serialize { model = model, seed = seed, system = system })
+		-- Unset sampling parameters are nil, and so are left out of the footer
+		output(response .. footer.serialize(ask.with_result({
+			model = model,
+			seed = seed,
+			system = system,
+			temperature = temperature,
+			max_tokens = max_tokens,
+		}, result)))
 	end,
 }
 
+-- Register the /askhistory command, which answers from indexed channel history.
+-- Only available when history indexing is configured.
+if history.enabled then
+	discord.register_command {
+		name = "askhistory",
+		description = "Answers a question using what was said earlier in this server",
+		options = {
+			{
+				name = "model",
+				description = "The model to use",
+				type = "string",
+				required = true,
+				choices = visible_model_choices,
+			},
+			{
+				name = "question",
+				description = "What to ask about past conversations",
+				type = "string",
+				required = true,
+			},
+			{
+				name = "results",
+				description = "How many excerpts to retrieve (default: 6)",
+				type = "integer",
+				required = false,
+				min_value = 1,
+				max_value = 20,
+			},
+		},
+		execute = function(interaction)
+			local model = interaction.options.model
+			local question = interaction.options.question
+
+			output("Searching history...")
+			local excerpts = history.search {
+				query = question,
+				k = interaction.options.results or 6,
+			}
+			if #excerpts == 0 then
+				output("I couldn't find anything relevant in the indexed history.")
+				return
+			end
+
+			local context = {}
+			local sources = {}
+			for i, excerpt in ipairs(excerpts) do
+				table.insert(context, "[" .. i .. "] (" .. excerpt.date .. ")\n" .. excerpt.text)
+				table.insert(sources, "[" .. i .. "](<" .. excerpt.link .. ">)")
+			end
+			local system = ask.default_system
+				.. " Answer the user's question using these excerpts of earlier conversations,"
+				.. " citing them by number like [1]. If they don't answer it, say so.\n\n"
+				.. table.concat(context, "\n\n")
+
+			local response = ask_llm {
+				prompt = question,
+				model = model,
+				system = system,
+			}
+			output(response .. "\n\n-# Sources: " .. table.concat(sources, " "))
+		end,
+	}
+end
+
 -- Register the /convert command
 discord.register_command {
 	name = "convert",
@@ -279,6 +386,8 @@ discord.register_command {
 discord.register_command {
 	name = "paint",
 	description = "Generate an image via ComfyUI",
+	-- Image generation ties up the ComfyUI server, so it uses more of the user's rate limit
+	cost = 3,
 	options = {
 		{
 			name = "prompt",
@@ -378,6 +487,7 @@ local askchorus_default_system = "You are a helpful assistant."
 discord.register_command {
 	name = "askchorus",
 	description = "Ask multiple models the same prompt and stream all responses",
+	cost = 2,
 	options = {
 		{
 			name = "prompt",
@@ -441,6 +551,99 @@ discord.register_command {
 	end,
 }
 
+-- ============================================================================
+-- Tools available to /agent
+-- ============================================================================
+local agent_tools = {
+	{
+		name = "convert_currency",
+		description = "Convert an amount of money from one currency to another using current exchange rates",
+		parameters = {
+			type = "object",
+			properties = {
+				amount = { type = "number", description = "The amount to convert" },
+				from = { type = "string", description = "ISO 4217 code of the source currency, e.g. USD" },
+				to = { type = "string", description = "ISO 4217 code of the target currency, e.g. EUR" },
+			},
+			required = { "amount", "from", "to" },
+		},
+		execute = function(args)
+			return currency.convert(args.amount, args.from:upper(), args.to:upper())
+		end,
+	},
+	{
+		name = "run_perchance",
+		description = "Evaluate a Perchance template, e.g. '{import:animal}' or '[a|b|c]', to get random text",
+		parameters = {
+			type = "object",
+			properties = {
+				template = { type = "string", description = "The Perchance template to evaluate" },
+			},
+			required = { "template" },
+		},
+		execute = function(args)
+			return perchance.run(args.template)
+		end,
+	},
+	{
+		name = "fetch_url",
+		description = "Fetch the contents of a web page or file by URL. Long responses are truncated.",
+		parameters = {
+			type = "object",
+			properties = {
+				url = { type = "string", description = "The URL to fetch" },
+			},
+			required = { "url" },
+		},
+		execute = function(args)
+			return fetch(args.url):sub(1, 8000)
+		end,
+	},
+}
+local agent_default_system =
+	"You are a helpful assistant. Use the tools available to you when they help answer the question."
+
+-- Register the /agent command
+discord.register_command {
+	name = "agent",
+	description = "Responds to the provided instruction, using tools (currency, Perchance, fetching URLs)",
+	cost = 2,
+	options = {
+		{
+			name = "model",
+			description = "The model to use",
+			type = "string",
+			required = true,
+			choices = visible_model_choices,
+		},
+		{
+			name = "prompt",
+			description = "The prompt to send to the AI",
+			type = "string",
+			required = true,
+		},
+	},
+	execute = function(interaction)
+		local model = interaction.options.model
+		local prompt = interaction.options.prompt
+
+		output("Generating...")
+		local response = llm.run_tools {
+			model = model,
+			messages = {
+				llm.system(agent_default_system),
+				llm.user(prompt),
+			},
+			tools = agent_tools,
+			callback = function(chunk)
+				output(chunk)
+				return true
+			end,
+		}
+		output(string.trim(response))
+	end,
+}
+
 -- Register the /paintperchance command
 discord.register_command {
 	name = "paintperchance",
@@ -510,12 +713,30 @@ discord.register_command {
 	end,
 }
 
--- Reply handler for /ask - continues the conversation
+-- Reply handler for /ask - continues the conversation, whether by replies or in its thread
+-- Image parts for a chain message's attachments that look like images, going
+-- by the extension of the URL's path
+local function image_parts(msg)
+	local parts = {}
+	for _, url in ipairs(msg.attachments) do
+		local path = url:match("^[^?]*"):lower()
+		for _, extension in ipairs({ "png", "jpg", "jpeg", "gif", "webp" }) do
+			if path:sub(-#extension - 1) == "." .. extension then
+				table.insert(parts, { type = "image", data = fetch(url) })
+				break
+			end
+		end
+	end
+	return parts
+end
+
 discord.register_reply_handler("ask", function(chain)
 	-- Try to get parameters from options first
 	local model = chain.options.model
 	local original_seed = chain.options.seed
 	local system = chain.options.system
+	local temperature = chain.options.temperature
+	local max_tokens = chain.options.max_tokens
 
 	-- If options not available, try to parse from first bot message footer
 	if not model or not original_seed then
@@ -526,6 +747,8 @@ discord.register_reply_handler("ask", function(chain)
 					model = model or parsed.model
 					original_seed = original_seed or parsed.seed
 					system = system or parsed.system
+					temperature = temperature or parsed.temperature
+					max_tokens = max_tokens or parsed.max_tokens
 					break
 				end
 			end
@@ -568,9 +791,19 @@ discord.register_reply_handler("ask", function(chain)
 
 	-- Add all messages from the chain
 	for _, msg in ipairs(chain.messages) do
+		local images = image_parts(msg)
 		if msg.is_bot then
 			-- Bot messages are assistant responses - strip footer
 			table.insert(messages, llm.assistant(footer.strip(msg.content)))
+			-- Only user messages can contain images, so any the bot attached
+			-- are replayed as a user message following its response
+			if #images > 0 then
+				table.insert(messages, llm.user(images))
+			end
+		elseif #images > 0 then
+			-- User messages, with their images
+			table.insert(images, 1, { type = "text", text = msg.content })
+			table.insert(messages, llm.user(images))
 		else
 			-- User messages
 			table.insert(messages, llm.user(msg.content))
@@ -578,15 +811,69 @@ discord.register_reply_handler("ask", function(chain)
 	end
 
 	-- Stream the response using ask_llm with pre-built messages
-	local response = ask_llm {
+	local response, result = ask_llm {
 		messages = messages,
 		model = model,
 		seed = original_seed,
+		temperature = temperature,
+		max_tokens = max_tokens,
 	}
 
-	output(response .. footer.serialize { model = model, seed = original_seed, system = system })
+	output(response .. footer.serialize(ask.with_result({
+		model = model,
+		seed = original_seed,
+		system = system,
+		temperature = temperature,
+		max_tokens = max_tokens,
+	}, result)))
 end)
 
+-- Chatting without commands: mention the bot or send it a DM, and reply to its
+-- response to keep the conversation going
+
+-- The model to chat with: the first one marked for chatting, or failing that,
+-- the first one shown on Discord
+local function chat_model()
+	for _, key in ipairs({ "discord_chat", "discord_visible" }) do
+		for _, m in ipairs(llm.models) do
+			if m.metadata[key] then
+				return m.id
+			end
+		end
+	end
+	return nil
+end
+
+local function chat(trigger)
+	local model = chat_model()
+	if not model then
+		error("No model is available to chat with")
+	end
+
+	local messages = {
+		llm.system(ask.default_system),
+	}
+	for _, msg in ipairs(trigger.messages) do
+		if msg.is_bot then
+			table.insert(messages, llm.assistant(msg.content))
+		elseif msg.id == trigger.message.id then
+			-- Leave out the mention that triggered the response
+			table.insert(messages, llm.user(trigger.text))
+		else
+			table.insert(messages, llm.user(msg.content))
+		end
+	end
+
+	local response = ask_llm {
+		messages = messages,
+		model = model,
+	}
+	output(response)
+end
+
+discord.on_mention(chat)
+discord.on_dm(chat)
+
 -- Register the /translate command
 discord.register_command {
 	name = "translate",
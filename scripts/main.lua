@@ -118,15 +118,29 @@ reduce = table.reduce
 -- Default LLM for helpers that don't take an explicit `model` argument
 -- (OCR, image description, translation). Sourced from ananke: exactly
 -- one model must carry `metadata.resident = true`.
-local resident_models = filter(llm.models, function(m)
-	return m.metadata.resident
-end)
-if #resident_models ~= 1 then
-	error(
-		"expected exactly one model with metadata.resident = true, found " .. #resident_models
-	)
+local function find_resident_model(models)
+	local resident_models = filter(models, function(m)
+		return m.metadata.resident
+	end)
+	if #resident_models ~= 1 then
+		error(
+			"expected exactly one model with metadata.resident = true, found " .. #resident_models
+		)
+	end
+	return resident_models[1].id
 end
-RESIDENT_MODEL = resident_models[1].id
+RESIDENT_MODEL = find_resident_model(llm.models)
+
+-- Follow the resident model across model list changes; if the new list
+-- doesn't have exactly one, keep using the previous one
+llm.on_models_changed(function(models)
+	local ok, model = pcall(find_resident_model, models)
+	if ok then
+		RESIDENT_MODEL = model
+	else
+		print("keeping resident model " .. RESIDENT_MODEL .. ": " .. tostring(model))
+	end
+end)
 
 -- ComfyUI lazy loading helpers
 local comfy_client = nil
@@ -223,6 +237,8 @@ end
 ---   - image_data: string (optional) Raw image data (binary)
 ---   - model: string (optional) Vision model to use (default: "qwen3.6-35b-a3b")
 ---   - seed: number (optional) Random seed
+---   - detail: string (optional) How closely to look: "low", "high" or "auto" (default)
+---   - temperature, top_p, max_tokens: number (optional) Sampling parameters
 ---   - output: function (optional) Output callback (default: output)
 --- @return string The extracted text
 function ocr(opts)
@@ -254,7 +270,7 @@ function ocr(opts)
 				type = "text",
 				text = "Perform OCR on this image. Transcribe all text visible in this image. Output only the transcribed text with no additional commentary.",
 			},
-			{ type = "image", data = image_data },
+			{ type = "image", data = image_data, detail = opts.detail },
 		},
 	}
 
@@ -263,6 +279,9 @@ function ocr(opts)
 		messages = messages,
 		model = model,
 		seed = seed,
+		temperature = opts.temperature,
+		top_p = opts.top_p,
+		max_tokens = opts.max_tokens,
 		callback = function(chunk)
 			out(chunk)
 			full_response = chunk
@@ -280,6 +299,8 @@ end
 ---   - prompt: string (optional) Custom prompt (default: "Describe this image in detail.")
 ---   - model: string (optional) Vision model to use (default: "qwen3.6-35b-a3b")
 ---   - seed: number (optional) Random seed
+---   - detail: string (optional) How closely to look: "low", "high" or "auto" (default)
+---   - temperature, top_p, max_tokens: number (optional) Sampling parameters
 ---   - output: function (optional) Output callback (default: output)
 --- @return string The description
 function describe_image(opts)
@@ -309,7 +330,7 @@ function describe_image(opts)
 	local messages = {
 		llm.user {
 			{ type = "text", text = prompt },
-			{ type = "image", data = image_data },
+			{ type = "image", data = image_data, detail = opts.detail },
 		},
 	}
 
@@ -318,6 +339,9 @@ function describe_image(opts)
 		messages = messages,
 		model = model,
 		seed = seed,
+		temperature = opts.temperature,
+		top_p = opts.top_p,
+		max_tokens = opts.max_tokens,
 		callback = function(chunk)
 			out(chunk)
 			full_response = chunk
@@ -334,9 +358,12 @@ end
 ---   - model: string (required) The model to use
 ---   - system: string (optional) System prompt (default: "You are a helpful assistant.")
 ---   - seed: number (optional) Random seed
+---   - temperature, top_p, max_tokens: number (optional) Sampling parameters
 ---   - messages: table (optional) Full message history (overrides prompt/system if provided)
 ---   - output: function (optional) Output callback (default: output)
+---   - thinking: function (optional) Reasoning callback (default: thinking, unless output is given)
 --- @return string The response text
+--- @return table The result: finish_reason, usage, model, latency_ms and reasoning
 function ask_llm(opts)
 	if type(opts) ~= "table" then
 		error("ask_llm() requires a table argument, e.g. ask_llm({prompt = '...', model = '...'})")
@@ -367,10 +394,14 @@ function ask_llm(opts)
 	out("Generating...")
 
 	local full_response = ""
-	llm.stream {
+	local _, result = llm.stream {
 		messages = messages,
 		model = model,
 		seed = seed,
+		temperature = opts.temperature,
+		top_p = opts.top_p,
+		max_tokens = opts.max_tokens,
+		reasoning_callback = opts.thinking or (opts.output == nil and thinking or nil),
 		callback = function(chunk)
 			out(chunk)
 			full_response = chunk
@@ -378,7 +409,7 @@ function ask_llm(opts)
 		end,
 	}
 
-	return string.trim(full_response)
+	return string.trim(full_response), result
 end
 
 --- Generate an image using ComfyUI
@@ -521,6 +552,11 @@ function generate_image(opts)
 
 	out("Generating image (seed: " .. seed .. ")...")
 
+	-- If the command is cancelled mid-render, stop ComfyUI from finishing the prompt
+	on_cancel(function()
+		client:interrupt()
+	end)
+
 	-- Queue the workflow and stream progress back to Discord as it renders.
 	local result = client:execute(g, {
 		on_event = function(event)
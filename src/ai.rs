@@ -1,8 +1,29 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt::Display,
+    io::ErrorKind,
+    pin::Pin,
+    sync::RwLock,
+    time::Duration,
+};
 
-use serde::Deserialize;
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        audio::CreateTranscriptionRequest,
+        embeddings::{CreateEmbeddingRequest, CreateEmbeddingResponse},
+    },
+};
+use serde::{Deserialize, Serialize};
+use serenity::futures::{Stream, StreamExt as _, future::join_all, stream};
 
-use crate::config::Configuration;
+use crate::config::{self, Configuration};
+
+pub type Client = async_openai::Client<async_openai::config::OpenAIConfig>;
+
+/// A stream of server-sent events, as returned by the streaming endpoints
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, OpenAIError>> + Send>>;
 
 /// Initial delay before the first retry. Doubles on each subsequent
 /// failure up to [`RETRY_MAX_DELAY`]. Total wait before giving up on
@@ -15,7 +36,7 @@ const RETRY_ATTEMPTS: u32 = 6;
 /// `ananke_metadata` passthrough matter to paxcord. `object`/`created`/
 /// `owned_by` exist on the wire but we don't use them, so serde drops
 /// them on deserialization by default.
-#[derive(Debug, Clone, Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Model {
     pub id: String,
     /// Passthrough entries set via `[[service]] metadata.*` in ananke's
@@ -24,6 +45,10 @@ pub struct Model {
     /// `metadata` for ergonomics on the consumer side.
     #[serde(default, rename(deserialize = "ananke_metadata"))]
     pub metadata: BTreeMap<String, serde_json::Value>,
+    /// Name of the backend requests for this model go to first. Not part
+    /// of the wire format; filled in when the lists are merged.
+    #[serde(default, skip_deserializing)]
+    pub backend: String,
 }
 
 #[derive(Deserialize)]
@@ -31,30 +56,355 @@ struct ModelsResponse {
     data: Vec<Model>,
 }
 
+/// A transcription from `/v1/audio/transcriptions`. Local servers often leave out
+/// everything but the text, so that's all that's required.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Transcription {
+    pub text: String,
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Length of the audio, in seconds
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub usage: Option<TranscriptionUsage>,
+}
+
+/// Tokens used by a transcription, for models that are billed by token
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TranscriptionUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// An OpenAI-compatible server declared in the config
+pub struct Backend {
+    pub name: String,
+    pub client: Client,
+}
+
 pub struct Ai {
-    pub client: async_openai::Client<async_openai::config::OpenAIConfig>,
-    pub models: Vec<Model>,
+    pub backends: Vec<Backend>,
+    catalog: RwLock<Catalog>,
+    /// How many more times to try a request when no backend serving the model can be reached
+    request_retries: u32,
 }
 impl Ai {
     pub async fn load(config: &Configuration) -> anyhow::Result<Self> {
-        let client = async_openai::Client::with_config({
-            let auth = &config.authentication;
-            let mut config = async_openai::config::OpenAIConfig::default();
-            if let Some(server) = auth.openai_api_server.as_deref() {
-                config = config.with_api_base(server);
-            }
-            if let Some(key) = auth.openai_api_key.as_deref() {
-                config = config.with_api_key(key);
+        let backends: Vec<_> = backend_configs(config)
+            .iter()
+            .map(|backend| Backend {
+                name: backend.name.clone(),
+                client: create_client(backend),
+            })
+            .collect();
+
+        // A backend that's down shouldn't keep the others' models from
+        // being used, so only fail if none of them could be listed
+        let responses = join_all(backends.iter().map(fetch_models_with_backoff)).await;
+        let mut lists = vec![];
+        let mut first_error = None;
+        for (backend, response) in backends.iter().zip(responses) {
+            match response {
+                Ok(response) => lists.push(response.data),
+                Err(err) => {
+                    eprintln!(
+                        "paxcord: skipping the models of backend `{}`: {err:?}",
+                        backend.name
+                    );
+                    lists.push(vec![]);
+                    first_error.get_or_insert(err);
+                }
             }
-            config
-        });
+        }
+        if let Some(err) = first_error
+            && lists.iter().all(|models| models.is_empty())
+        {
+            return Err(err);
+        }
 
-        let resp = fetch_models_with_backoff(&client).await?;
+        let catalog = Catalog::new(&backends, lists);
         Ok(Self {
-            client,
-            models: resp.data,
+            backends,
+            catalog: RwLock::new(catalog),
+            request_retries: config.llm.request_retries,
+        })
+    }
+
+    /// Every model any backend serves, listed once
+    pub fn models(&self) -> Vec<Model> {
+        self.catalog.read().unwrap().models.clone()
+    }
+
+    /// Fetches `/v1/models` from every backend again, returning whether the
+    /// merged list changed. A backend that can't be reached keeps the models
+    /// it last listed, so a blip doesn't make them disappear.
+    pub async fn refresh(&self) -> bool {
+        let responses = join_all(
+            self.backends
+                .iter()
+                .map(|backend| backend.client.models().list_byot::<ModelsResponse>()),
+        )
+        .await;
+
+        let mut lists = self.catalog.read().unwrap().lists.clone();
+        for ((backend, response), list) in self.backends.iter().zip(responses).zip(&mut lists) {
+            match response {
+                Ok(response) => *list = response.data,
+                Err(err) => eprintln!(
+                    "paxcord: failed to refresh the models of backend `{}`: {err}",
+                    backend.name
+                ),
+            }
+        }
+
+        let catalog = Catalog::new(&self.backends, lists);
+        let mut current = self.catalog.write().unwrap();
+        let changed = catalog.models != current.models;
+        *current = catalog;
+        changed
+    }
+
+    /// Sends a request to the first backend serving `model` that can be reached. If none of
+    /// them can be, the request is retried with backoff.
+    pub async fn send<T, F: Future<Output = Result<T, OpenAIError>>>(
+        &self,
+        model: &str,
+        send: impl Fn(Client) -> F,
+    ) -> Result<T, OpenAIError> {
+        self.retry_connection(&format!("request to `{model}`"), || {
+            self.send_once(model, &send)
+        })
+        .await
+    }
+
+    /// Opens a stream with the first backend serving `model` that can be reached, retrying with
+    /// backoff if none of them can be
+    pub async fn stream<T, F>(
+        &self,
+        model: &str,
+        open: impl Fn(Client) -> F,
+    ) -> Result<EventStream<T>, OpenAIError>
+    where
+        T: Send + 'static,
+        F: Future<Output = Result<EventStream<T>, OpenAIError>>,
+    {
+        self.retry_connection(&format!("stream from `{model}`"), || {
+            self.stream_once(model, &open)
+        })
+        .await
+    }
+
+    /// Calls `attempt` until it succeeds or fails with something other than a connection
+    /// error, backing off between attempts, up to the configured number of retries
+    pub async fn retry_connection<T, F: Future<Output = Result<T, OpenAIError>>>(
+        &self,
+        what: &str,
+        attempt: impl FnMut() -> F,
+    ) -> Result<T, OpenAIError> {
+        with_backoff(what, self.request_retries + 1, is_connection_error, attempt).await
+    }
+
+    async fn send_once<T, F: Future<Output = Result<T, OpenAIError>>>(
+        &self,
+        model: &str,
+        send: &impl Fn(Client) -> F,
+    ) -> Result<T, OpenAIError> {
+        let mut backends = self.backends_for(model).into_iter().peekable();
+        while let Some(backend) = backends.next() {
+            match send(backend.client.clone()).await {
+                Err(err) if is_connection_error(&err) && backends.peek().is_some() => {
+                    eprintln!(
+                        "paxcord: backend `{}` is unreachable, trying the next one: {err}",
+                        backend.name
+                    );
+                }
+                response => return response,
+            }
+        }
+        unreachable!("every model has at least one backend")
+    }
+
+    /// Streams only connect once they're polled, so a backend counts as unreachable if its
+    /// stream fails to connect before the first event
+    async fn stream_once<T, F>(
+        &self,
+        model: &str,
+        open: &impl Fn(Client) -> F,
+    ) -> Result<EventStream<T>, OpenAIError>
+    where
+        T: Send + 'static,
+        F: Future<Output = Result<EventStream<T>, OpenAIError>>,
+    {
+        let mut backends = self.backends_for(model).into_iter().peekable();
+        while let Some(backend) = backends.next() {
+            let mut events = open(backend.client.clone()).await?;
+            match events.next().await {
+                Some(Err(err)) if is_connection_error(&err) && backends.peek().is_some() => {
+                    eprintln!(
+                        "paxcord: backend `{}` is unreachable, trying the next one: {err}",
+                        backend.name
+                    );
+                }
+                Some(Err(err)) => return Err(err),
+                first => return Ok(Box::pin(stream::iter(first).chain(events))),
+            }
+        }
+        unreachable!("every model has at least one backend")
+    }
+
+    /// Embeds the request's inputs, returning the embeddings in the same order as the inputs
+    pub async fn embed(
+        &self,
+        request: CreateEmbeddingRequest,
+    ) -> Result<CreateEmbeddingResponse, OpenAIError> {
+        let model = request.model.clone();
+        let mut response = self
+            .send(&model, move |client| {
+                let request = request.clone();
+                async move { client.embeddings().create(request).await }
+            })
+            .await?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response)
+    }
+
+    /// Transcribes the request's audio into text
+    pub async fn transcribe(
+        &self,
+        request: CreateTranscriptionRequest,
+    ) -> Result<Transcription, OpenAIError> {
+        let model = request.model.clone();
+        self.send(&model, move |client| {
+            let request = request.clone();
+            async move {
+                client
+                    .audio()
+                    .transcription()
+                    .create_byot::<_, Transcription>(request)
+                    .await
+            }
         })
+        .await
     }
+
+    /// The backends to send requests for `model` to, in order of
+    /// preference. Models no backend listed go to the first backend,
+    /// which may serve them anyway.
+    pub fn backends_for(&self, model: &str) -> Vec<&Backend> {
+        self.catalog
+            .read()
+            .unwrap()
+            .routes
+            .get(model)
+            .map(Vec::as_slice)
+            .unwrap_or(&[0])
+            .iter()
+            .map(|&index| &self.backends[index])
+            .collect()
+    }
+}
+
+/// The models the backends last listed
+struct Catalog {
+    /// Each backend's own list, in the same order as the backends
+    lists: Vec<Vec<Model>>,
+    /// Every model any backend serves, listed once
+    models: Vec<Model>,
+    /// Indices into the backends of the backends serving each model, in
+    /// the order they were declared
+    routes: HashMap<String, Vec<usize>>,
+}
+impl Catalog {
+    fn new(backends: &[Backend], lists: Vec<Vec<Model>>) -> Self {
+        let (models, routes) = merge_models(
+            backends
+                .iter()
+                .map(|backend| backend.name.as_str())
+                .zip(lists.iter().cloned())
+                .collect(),
+        );
+        Self {
+            lists,
+            models,
+            routes,
+        }
+    }
+}
+
+/// Whether a request failed because the backend couldn't be reached, in
+/// which case nothing was generated and it's safe to send it elsewhere
+pub fn is_connection_error(err: &OpenAIError) -> bool {
+    match err {
+        OpenAIError::Reqwest(err) => err.is_connect() || err.is_timeout(),
+        // Streams bury the cause of the failure beneath their own error
+        OpenAIError::StreamError(err) => {
+            let err: &(dyn Error + 'static) = &**err;
+            std::iter::successors(Some(err), |err| err.source()).any(is_unreachable)
+        }
+        _ => false,
+    }
+}
+
+fn is_unreachable(err: &(dyn Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        err.is_connect() || err.is_timeout()
+    } else if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        matches!(
+            err.kind(),
+            ErrorKind::ConnectionRefused | ErrorKind::NotConnected | ErrorKind::TimedOut
+        )
+    } else {
+        false
+    }
+}
+
+/// The configured backends, or a single one using the server and key in
+/// `[authentication]` if none are
+fn backend_configs(config: &Configuration) -> Vec<config::Backend> {
+    if !config.llm.backends.is_empty() {
+        return config.llm.backends.clone();
+    }
+    let auth = &config.authentication;
+    vec![config::Backend {
+        name: "default".to_string(),
+        api_server: auth.openai_api_server.clone(),
+        api_key: auth.openai_api_key.clone(),
+    }]
+}
+
+fn create_client(backend: &config::Backend) -> Client {
+    let mut config = async_openai::config::OpenAIConfig::default();
+    if let Some(server) = backend.api_server.as_deref() {
+        config = config.with_api_base(server);
+    }
+    if let Some(key) = backend.api_key.as_deref() {
+        config = config.with_api_key(key);
+    }
+    async_openai::Client::with_config(config)
+}
+
+/// Merges the model lists of each backend, in the order the backends were
+/// declared. A model served by several backends is listed once, tagged with
+/// the first of them, and routed to each of them in turn.
+fn merge_models(lists: Vec<(&str, Vec<Model>)>) -> (Vec<Model>, HashMap<String, Vec<usize>>) {
+    let mut models = vec![];
+    let mut routes: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, (backend, list)) in lists.into_iter().enumerate() {
+        for mut model in list {
+            let route = routes.entry(model.id.clone()).or_default();
+            if route.contains(&index) {
+                continue;
+            }
+            route.push(index);
+            if route.len() == 1 {
+                model.backend = backend.to_string();
+                models.push(model);
+            }
+        }
+    }
+    (models, routes)
 }
 
 /// Poll `GET /v1/models` with exponential backoff. Ananke can take a
@@ -64,27 +414,104 @@ impl Ai {
 /// paxcord systemd unit doesn't guarantee readiness. Rather than gate
 /// paxcord's startup on a shell-level health probe, swallow the
 /// expected early failures here.
-async fn fetch_models_with_backoff(
-    client: &async_openai::Client<async_openai::config::OpenAIConfig>,
-) -> anyhow::Result<ModelsResponse> {
+async fn fetch_models_with_backoff(backend: &Backend) -> anyhow::Result<ModelsResponse> {
+    let name = &backend.name;
+    with_backoff(
+        &format!("/v1/models for `{name}`"),
+        RETRY_ATTEMPTS,
+        |_| true,
+        || backend.client.models().list_byot::<ModelsResponse>(),
+    )
+    .await
+    .map_err(|err| {
+        anyhow::Error::new(err).context(format!(
+            "failed to fetch /v1/models from `{name}` after {RETRY_ATTEMPTS} attempts"
+        ))
+    })
+}
+
+/// Calls `attempt` up to `attempts` times, for as long as it fails with an error `should_retry`
+/// accepts, waiting [`RETRY_INITIAL_DELAY`] after the first failure and twice as long after
+/// each one after that. `what` describes the request in the logs.
+async fn with_backoff<T, E: Display, F: Future<Output = Result<T, E>>>(
+    what: &str,
+    attempts: u32,
+    should_retry: impl Fn(&E) -> bool,
+    mut attempt: impl FnMut() -> F,
+) -> Result<T, E> {
     let mut delay = RETRY_INITIAL_DELAY;
-    for attempt in 1..=RETRY_ATTEMPTS {
-        match client.models().list_byot::<ModelsResponse>().await {
-            Ok(resp) => return Ok(resp),
-            Err(err) if attempt == RETRY_ATTEMPTS => {
-                return Err(anyhow::Error::new(err).context(format!(
-                    "failed to fetch /v1/models after {RETRY_ATTEMPTS} attempts"
-                )));
-            }
-            Err(err) => {
+    for n in 1.. {
+        match attempt().await {
+            Err(err) if n < attempts && should_retry(&err) => {
                 eprintln!(
-                    "paxcord: /v1/models attempt {attempt}/{RETRY_ATTEMPTS} failed ({err}); retrying in {:?}",
-                    delay
+                    "paxcord: {what} attempt {n}/{attempts} failed ({err}); retrying in {delay:?}"
                 );
                 tokio::time::sleep(delay).await;
                 delay = (delay * 2).min(RETRY_MAX_DELAY);
             }
+            response => return response,
         }
     }
     unreachable!("retry loop always returns inside the match")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(id: &str) -> Model {
+        Model {
+            id: id.to_string(),
+            metadata: BTreeMap::new(),
+            backend: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_merge_models() {
+        let (models, routes) = merge_models(vec![
+            ("local", vec![model("llama"), model("qwen")]),
+            ("remote", vec![model("qwen"), model("gpt"), model("gpt")]),
+        ]);
+
+        let listed: Vec<_> = models
+            .iter()
+            .map(|m| (m.id.as_str(), m.backend.as_str()))
+            .collect();
+        assert_eq!(
+            listed,
+            [("llama", "local"), ("qwen", "local"), ("gpt", "remote")]
+        );
+        assert_eq!(routes["qwen"], [0, 1]);
+        assert_eq!(routes["gpt"], [1]);
+    }
+
+    #[tokio::test]
+    async fn test_with_backoff() {
+        let mut calls = 0;
+        let result = with_backoff(
+            "test",
+            3,
+            |err: &&str| *err == "retry",
+            || {
+                calls += 1;
+                async move { if calls < 2 { Err("retry") } else { Ok(calls) } }
+            },
+        )
+        .await;
+        assert_eq!(result, Ok(2));
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_backoff(
+            "test",
+            3,
+            |err: &&str| *err == "retry",
+            || {
+                calls += 1;
+                async { Err("fatal") }
+            },
+        )
+        .await;
+        assert_eq!((result, calls), (Err("fatal"), 1));
+    }
+}
@@ -1,6 +1,12 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 use serenity::all::{
     ButtonStyle, CreateActionRow, CreateButton, EditMessage, Http, Message, MessageId, UserId,
 };
+use tokio::sync::watch;
 
 pub const CANCEL_ID_BASE: &str = "cancel";
 
@@ -39,3 +45,76 @@ pub async fn add_button(
         )
         .await?)
 }
+
+/// Cancellation state shared between an execution, the Lua functions it calls, and the
+/// cancel button. Cheap to clone; all clones share the same state.
+#[derive(Clone)]
+pub struct Cancellation(Arc<CancellationInner>);
+struct CancellationInner {
+    cancelled: watch::Sender<bool>,
+    /// Lua functions registered with `on_cancel`, run when the execution is cancelled
+    hooks: Mutex<Vec<mlua::Function>>,
+}
+impl Default for Cancellation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Cancellation {
+    pub fn new() -> Self {
+        Self(Arc::new(CancellationInner {
+            cancelled: watch::Sender::new(false),
+            hooks: Mutex::new(vec![]),
+        }))
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.cancelled.borrow()
+    }
+
+    /// Resolves once the execution has been cancelled
+    pub async fn cancelled(&self) {
+        let mut rx = self.0.cancelled.subscribe();
+        // The sender lives as long as `self`, so this can't fail
+        let _ = rx.wait_for(|cancelled| *cancelled).await;
+    }
+
+    pub fn add_hook(&self, hook: mlua::Function) {
+        self.0.hooks.lock().unwrap().push(hook);
+    }
+
+    pub fn take_hooks(&self) -> Vec<mlua::Function> {
+        std::mem::take(&mut *self.0.hooks.lock().unwrap())
+    }
+}
+
+/// Running executions, keyed by the ID of the first message of their response
+pub type CancellationRegistry = Arc<Mutex<HashMap<MessageId, Cancellation>>>;
+
+/// Registers an execution's cancellation for the lifetime of the returned guard
+pub fn register(
+    registry: &CancellationRegistry,
+    message_id: MessageId,
+    cancellation: Cancellation,
+) -> Registration {
+    registry.lock().unwrap().insert(message_id, cancellation);
+    Registration {
+        registry: registry.clone(),
+        message_id,
+    }
+}
+
+/// Removes an execution from the [`CancellationRegistry`] when dropped
+pub struct Registration {
+    registry: CancellationRegistry,
+    message_id: MessageId,
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.lock().unwrap().remove(&self.message_id);
+    }
+}
@@ -1,18 +1,17 @@
 use std::sync::Arc;
 
 use serenity::all::{
-    Command, CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption, Http,
-    MessageId,
+    CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption, Http,
 };
 
 use crate::{
-    ai::Ai,
+    cancel::{Cancellation, CancellationRegistry},
     commands::CommandHandler,
     config, constant,
-    currency::CurrencyConverter,
     lua::{
-        LuaOutputChannels, create_barebones_lua_state, execute_lua_thread, extensions::Attachment,
-        load_async_expression,
+        Accounting, LuaOutputChannels, Services, create_barebones_lua_state, execute_lua_thread,
+        extensions::{self, Attachment, Invoker, OutputUpdate},
+        load_async_expression, sandbox,
     },
     util::RespondableInteraction,
 };
@@ -25,9 +24,8 @@ impl Handler {
 }
 #[serenity::async_trait]
 impl CommandHandler for Handler {
-    async fn register(&self, http: &Http) -> anyhow::Result<()> {
-        Command::create_global_command(
-            http,
+    fn create_command(&self) -> Option<CreateCommand> {
+        Some(
             CreateCommand::new(constant::commands::EXECUTE)
                 .description("Execute the given Lua code snippet.")
                 .add_option(
@@ -39,8 +37,6 @@ impl CommandHandler for Handler {
                     .required(true),
                 ),
         )
-        .await?;
-        Ok(())
     }
 
     async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()> {
@@ -62,9 +58,8 @@ impl MsgHandler {
 }
 #[serenity::async_trait]
 impl CommandHandler for MsgHandler {
-    async fn register(&self, http: &Http) -> anyhow::Result<()> {
-        Command::create_global_command(
-            http,
+    fn create_command(&self) -> Option<CreateCommand> {
+        Some(
             CreateCommand::new(constant::commands::EXECUTE_MSG)
                 .description("Execute the Lua code block from the given message ID.")
                 .add_option(
@@ -76,8 +71,6 @@ impl CommandHandler for MsgHandler {
                     .required(true),
                 ),
         )
-        .await?;
-        Ok(())
     }
 
     async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()> {
@@ -99,23 +92,23 @@ impl CommandHandler for MsgHandler {
 
 pub struct SharedState {
     discord_config: config::Discord,
-    cancel_rx: flume::Receiver<MessageId>,
-    ai: Arc<Ai>,
-    currency_converter: Arc<CurrencyConverter>,
+    sandbox_config: config::Sandbox,
+    cancellations: CancellationRegistry,
+    services: Services,
 }
 
 impl SharedState {
     pub fn new(
         discord_config: config::Discord,
-        cancel_rx: flume::Receiver<MessageId>,
-        ai: Arc<Ai>,
-        currency_converter: Arc<CurrencyConverter>,
+        sandbox_config: config::Sandbox,
+        cancellations: CancellationRegistry,
+        services: Services,
     ) -> Self {
         Self {
             discord_config,
-            cancel_rx,
-            ai,
-            currency_converter,
+            sandbox_config,
+            cancellations,
+            services,
         }
     }
 
@@ -127,17 +120,22 @@ impl SharedState {
     ) -> anyhow::Result<()> {
         let code = parse_markdown_lua_block(code).unwrap_or(code);
 
-        let (output_tx, output_rx) = flume::unbounded::<String>();
+        let (output_tx, output_rx) = flume::unbounded::<OutputUpdate>();
         let (print_tx, print_rx) = flume::unbounded::<String>();
         let (attachment_tx, attachment_rx) = flume::unbounded::<Attachment>();
+        let cancellation = Cancellation::new();
+        let invoker = Invoker::from_command(cmd);
 
         let lua = create_barebones_lua_state(
-            self.ai.clone(),
-            self.currency_converter.clone(),
+            self.services.clone(),
             output_tx,
             print_tx,
             attachment_tx,
+            Some(cancellation.clone()),
+            Some(invoker.clone()),
         )?;
+        extensions::make_shared_storage_read_only(&lua)?;
+        let timeout = sandbox::apply_limits(&lua, &self.sandbox_config)?;
         let thread = match load_async_expression::<Option<String>>(&lua, code) {
             Ok(thread) => thread,
             Err(err) => {
@@ -155,8 +153,14 @@ impl SharedState {
                 output_rx,
                 print_rx,
                 attachment_rx,
+                cancellation,
+                accounting: Accounting {
+                    tracker: self.services.usage.clone(),
+                    invoker,
+                },
             },
-            Some(self.cancel_rx.clone()),
+            &self.cancellations,
+            timeout,
         )
         .await?;
 
@@ -6,19 +6,24 @@ use std::{
 use mlua::LuaSerdeExt as _;
 use serde::Serialize;
 use serenity::all::{
-    CommandDataOptionValue, CommandInteraction, CommandOptionType, CreateCommand,
-    CreateCommandOption, Http,
+    ChannelId, CommandDataOptionValue, CommandInteraction, CommandOptionType, CreateCommand,
+    CreateCommandOption, CreateThread, Http, MessageId,
 };
 
 use crate::{
+    cancel::{Cancellation, CancellationRegistry},
     config,
     interaction_context::{InteractionContext, InteractionContextStore, OptionValue},
     lua::{
-        LuaOutputChannels, execute_lua_thread,
-        extensions::{Attachment, TemporaryChannelUpdate},
+        Accounting, GlobalLuaState, LuaOutputChannels, execute_lua_thread,
+        extensions::{Attachment, Invoker, OutputUpdate, TemporaryChannelUpdate},
     },
+    permissions::PermissionRules,
 };
 
+/// The most characters Discord allows in a thread's name
+const MAX_THREAD_NAME_CHARS: usize = 100;
+
 /// Lua-serializable interaction data
 #[derive(Serialize)]
 struct LuaInteraction {
@@ -28,52 +33,51 @@ struct LuaInteraction {
 pub struct Handler {
     name: String,
     discord_config: config::Discord,
-    command_registry: LuaCommandRegistry,
-    global_lua: mlua::Lua,
+    global_lua: Arc<GlobalLuaState>,
     interaction_context_store: Arc<InteractionContextStore>,
+    cancellations: CancellationRegistry,
 }
 impl Handler {
     pub fn new(
         name: String,
         discord_config: config::Discord,
-        command_registry: LuaCommandRegistry,
-        global_lua: mlua::Lua,
+        global_lua: Arc<GlobalLuaState>,
         interaction_context_store: Arc<InteractionContextStore>,
+        cancellations: CancellationRegistry,
     ) -> Self {
         Self {
             name,
             discord_config,
-            command_registry,
             global_lua,
             interaction_context_store,
+            cancellations,
         }
     }
 }
 #[serenity::async_trait]
 impl super::CommandHandler for Handler {
-    async fn register(&self, http: &Http) -> anyhow::Result<()> {
-        let command_spec = self
+    fn create_command(&self) -> Option<CreateCommand> {
+        self.global_lua
+            .current()
             .command_registry
             .lock()
             .unwrap()
             .get(&self.name)
-            .map(|cmd| cmd.to_discord_command());
-
-        if let Some(cmd) = command_spec {
-            serenity::all::Command::create_global_command(http, cmd).await?;
-        }
-        Ok(())
+            .map(|cmd| cmd.to_discord_command())
     }
 
     #[allow(clippy::await_holding_lock)]
     async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()> {
         // Create output/print/attachment channels for this execution
-        let (output_tx, output_rx) = flume::unbounded::<String>();
+        let (output_tx, output_rx) = flume::unbounded::<OutputUpdate>();
         let (print_tx, print_rx) = flume::unbounded::<String>();
         let (attachment_tx, attachment_rx) = flume::unbounded::<Attachment>();
+        let cancellation = Cancellation::new();
 
-        // Lock the global Lua state for this execution (held for entire duration)
-        let lua = &self.global_lua;
+        // Take the current global Lua state for this execution; a reload while we're
+        // running swaps in a new state without affecting this one
+        let scripts = self.global_lua.current();
+        let lua = &scripts.lua;
 
         // Parse options from Discord interaction into context storage
         let mut context_options = HashMap::new();
@@ -103,28 +107,35 @@ impl super::CommandHandler for Handler {
             options: context_options.clone(),
         })?;
 
-        let handler = self
+        let (handler, opens_thread) = scripts
             .command_registry
             .lock()
             .unwrap()
             .get(&self.name)
-            .ok_or_else(|| anyhow::anyhow!("Command not found: {}", self.name))?
-            .handler
-            .clone();
+            .map(|command| (command.handler.clone(), command.thread))
+            .ok_or_else(|| anyhow::anyhow!("Command not found: {}", self.name))?;
 
         // Create the thread FIRST, then register channels for this specific thread
         let thread = lua.create_thread(handler)?;
 
         // Register output channels for THIS thread (keyed by thread pointer)
-        let _temporary_channel_update =
-            TemporaryChannelUpdate::new(lua.clone(), &thread, output_tx, print_tx, attachment_tx)?;
+        let invoker = Invoker::from_command(cmd);
+        let _temporary_channel_update = TemporaryChannelUpdate::new(
+            lua.clone(),
+            &thread,
+            output_tx,
+            print_tx,
+            attachment_tx,
+            cancellation.clone(),
+            Some(invoker.clone()),
+        )?;
 
         // Convert to async thread
         let thread = thread.into_async::<Option<String>>(interaction)?;
 
-        // Execute the Lua thread using the shared executor (no cancellation support)
+        // Execute the Lua thread using the shared executor
         let message_id = execute_lua_thread(
-            http,
+            http.clone(),
             cmd,
             &self.discord_config,
             thread,
@@ -132,11 +143,25 @@ impl super::CommandHandler for Handler {
                 output_rx,
                 print_rx,
                 attachment_rx,
+                cancellation,
+                accounting: Accounting {
+                    tracker: self.global_lua.services().usage.clone(),
+                    invoker,
+                },
             },
+            &self.cancellations,
             None,
         )
         .await?;
 
+        // Threads can only be opened in servers
+        let thread_id = if opens_thread && cmd.guild_id.is_some() {
+            self.open_thread(&http, cmd.channel_id, message_id, &context_options)
+                .await
+        } else {
+            None
+        };
+
         // Store the interaction context for reply handling
         let context = InteractionContext {
             command_name: self.name.clone(),
@@ -144,12 +169,54 @@ impl super::CommandHandler for Handler {
             user_id: cmd.user.id,
             channel_id: cmd.channel_id,
             guild_id: cmd.guild_id,
+            thread_id,
         };
         self.interaction_context_store.store(message_id, context);
 
         Ok(())
     }
 }
+impl Handler {
+    /// Opens a thread on the response for the conversation to continue in. Failing to open
+    /// one is logged rather than returned, as replies to the response still work without it.
+    async fn open_thread(
+        &self,
+        http: &Http,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        options: &HashMap<String, OptionValue>,
+    ) -> Option<ChannelId> {
+        let name = thread_name(&self.name, options);
+        match channel_id
+            .create_thread_from_message(http, message_id, CreateThread::new(name))
+            .await
+        {
+            Ok(thread) => Some(thread.id),
+            Err(err) => {
+                eprintln!("Failed to open a thread for '{}': {err}", self.name);
+                None
+            }
+        }
+    }
+}
+
+/// Names a conversation's thread after the first line of its prompt, or the command if it
+/// doesn't have one
+fn thread_name(command_name: &str, options: &HashMap<String, OptionValue>) -> String {
+    let prompt = options
+        .get("prompt")
+        .and_then(OptionValue::as_str)
+        .and_then(|prompt| prompt.lines().find(|line| !line.trim().is_empty()))
+        .map(str::trim);
+    match prompt {
+        Some(prompt) if prompt.chars().count() > MAX_THREAD_NAME_CHARS => {
+            let truncated: String = prompt.chars().take(MAX_THREAD_NAME_CHARS - 1).collect();
+            format!("{truncated}…")
+        }
+        Some(prompt) => prompt.to_string(),
+        None => command_name.to_string(),
+    }
+}
 
 pub type LuaCommandRegistry = Arc<Mutex<HashMap<String, LuaCommand>>>;
 
@@ -158,6 +225,11 @@ pub struct LuaCommand {
     pub name: String,
     pub description: String,
     pub options: Vec<LuaCommandOption>,
+    pub permissions: PermissionRules,
+    /// Rate limiting cost declared by the script, if any
+    pub cost: Option<f64>,
+    /// Whether the conversation continues in a thread opened on the response
+    pub thread: bool,
     pub handler: mlua::Function,
 }
 #[derive(Clone)]
@@ -172,11 +244,17 @@ pub struct LuaCommandOption {
     pub max_length: Option<u16>,
     pub autocomplete: bool,
     pub choices: Vec<(String, String)>, // (name, value) for strict string choices
+    /// The function the choices were computed with, if they were given as one
+    pub choices_source: Option<mlua::Function>,
     pub suggestions: Vec<(String, String)>, // (name, value) for autocomplete suggestions
 }
 impl LuaCommand {
     pub fn to_discord_command(&self) -> CreateCommand {
         let mut cmd = CreateCommand::new(&self.name).description(&self.description);
+        // Validated when the command was registered
+        if let Ok(Some(permissions)) = self.permissions.member_permissions() {
+            cmd = cmd.default_member_permissions(permissions);
+        }
 
         for opt in &self.options {
             let mut option = CreateCommandOption::new(opt.option_type, &opt.name, &opt.description)
@@ -209,3 +287,29 @@ impl LuaCommand {
         cmd
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(prompt: &str) -> HashMap<String, OptionValue> {
+        HashMap::from([(
+            "prompt".to_string(),
+            OptionValue::String(prompt.to_string()),
+        )])
+    }
+
+    #[test]
+    fn test_thread_name() {
+        assert_eq!(
+            thread_name("ask", &prompt("\n  what is rust?  \nmore")),
+            "what is rust?"
+        );
+        assert_eq!(thread_name("ask", &HashMap::new()), "ask");
+        assert_eq!(thread_name("ask", &prompt(" ")), "ask");
+
+        let name = thread_name("ask", &prompt(&"a".repeat(150)));
+        assert_eq!(name.chars().count(), MAX_THREAD_NAME_CHARS);
+        assert!(name.ends_with('…'));
+    }
+}
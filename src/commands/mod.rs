@@ -1,12 +1,15 @@
 use std::sync::Arc;
 
-use serenity::all::{CommandInteraction, Http};
+use serenity::all::{CommandInteraction, CreateCommand, Http};
 
 pub mod execute;
 pub mod lua_command;
+pub mod transcribe;
+pub mod usage;
 
 #[serenity::async_trait]
 pub trait CommandHandler: Send + Sync {
-    async fn register(&self, http: &Http) -> anyhow::Result<()>;
+    /// The command to register with Discord, if any
+    fn create_command(&self) -> Option<CreateCommand>;
     async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()>;
 }
@@ -0,0 +1,264 @@
+use std::{sync::Arc, time::Instant};
+
+use async_openai::types::{
+    InputSource,
+    audio::{AudioInput, CreateTranscriptionRequestArgs},
+};
+use serenity::all::{
+    Attachment, CommandInteraction, CommandOptionType, CommandType, CreateCommand,
+    CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage, GetMessages,
+    Http, Message, MessageFlags, ResolvedTarget,
+};
+
+use crate::{
+    ai::Ai,
+    commands::CommandHandler,
+    config, constant,
+    outputter::OutputterHandle,
+    usage::{Resource, Usage, UsageTracker},
+};
+
+/// How many recent messages `/transcribe` looks through for a voice message
+const RECENT_MESSAGE_LIMIT: u8 = 20;
+
+/// Extensions of audio files Discord doesn't give a content type for
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "flac", "m4a", "mp3", "mp4", "oga", "ogg", "opus", "wav", "webm",
+];
+
+pub struct Handler(Arc<SharedState>);
+impl Handler {
+    pub fn new(shared_state: Arc<SharedState>) -> Self {
+        Self(shared_state)
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn create_command(&self) -> Option<CreateCommand> {
+        self.0.is_enabled().then(|| {
+            CreateCommand::new(constant::commands::TRANSCRIBE)
+                .description(
+                    "Transcribe an audio file or the latest voice message in this channel.",
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Attachment,
+                        constant::value::ATTACHMENT,
+                        "The audio file to transcribe (default: the latest voice message here).",
+                    )
+                    .required(false),
+                )
+        })
+    }
+
+    async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        let attachment = cmd
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == constant::value::ATTACHMENT)
+            .and_then(|o| o.value.as_attachment_id())
+            .and_then(|id| cmd.data.resolved.attachments.get(&id))
+            .cloned();
+        let attachment = match attachment {
+            Some(attachment) => Some(attachment),
+            None => {
+                let messages = cmd
+                    .channel_id
+                    .messages(&*http, GetMessages::new().limit(RECENT_MESSAGE_LIMIT))
+                    .await?;
+                messages.iter().find_map(find_audio).cloned()
+            }
+        };
+        self.0.transcribe(http, cmd, attachment.as_ref()).await
+    }
+}
+
+/// The "Transcribe audio" entry in a message's Apps menu
+pub struct MsgHandler(Arc<SharedState>);
+impl MsgHandler {
+    pub fn new(shared_state: Arc<SharedState>) -> Self {
+        Self(shared_state)
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for MsgHandler {
+    fn create_command(&self) -> Option<CreateCommand> {
+        self.0.is_enabled().then(|| {
+            CreateCommand::new(constant::commands::TRANSCRIBE_MSG).kind(CommandType::Message)
+        })
+    }
+
+    async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        let attachment = match cmd.data.target() {
+            Some(ResolvedTarget::Message(message)) => find_audio(message),
+            _ => None,
+        };
+        self.0.transcribe(http, cmd, attachment).await
+    }
+}
+
+pub struct SharedState {
+    audio_config: config::Audio,
+    discord_config: config::Discord,
+    ai: Arc<Ai>,
+    usage: Arc<UsageTracker>,
+}
+
+impl SharedState {
+    pub fn new(
+        audio_config: config::Audio,
+        discord_config: config::Discord,
+        ai: Arc<Ai>,
+        usage: Arc<UsageTracker>,
+    ) -> Self {
+        Self {
+            audio_config,
+            discord_config,
+            ai,
+            usage,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.audio_config.transcription_model.is_empty()
+    }
+
+    async fn transcribe(
+        &self,
+        http: Arc<Http>,
+        cmd: &CommandInteraction,
+        attachment: Option<&Attachment>,
+    ) -> anyhow::Result<()> {
+        let Some(attachment) = attachment else {
+            return respond_privately(&http, cmd, "No audio to transcribe was found.").await;
+        };
+        let max_bytes = self.audio_config.max_transcription_bytes;
+        if u64::from(attachment.size) > max_bytes {
+            let message = format!(
+                "`{}` is too large to transcribe ({} bytes, max {max_bytes} bytes).",
+                attachment.filename, attachment.size
+            );
+            return respond_privately(&http, cmd, &message).await;
+        }
+        if let Err(exceeded) = self
+            .usage
+            .check_budget(cmd.user.id, cmd.guild_id, Resource::Tokens)
+        {
+            return respond_privately(&http, cmd, &exceeded.to_string()).await;
+        }
+
+        let outputter = OutputterHandle::new(
+            http,
+            cmd,
+            self.discord_config.message_update_interval_ms,
+            "Transcribing...",
+        )
+        .await?;
+
+        let started = Instant::now();
+        let result = async {
+            let request = CreateTranscriptionRequestArgs::default()
+                .model(self.audio_config.transcription_model.clone())
+                .file(AudioInput {
+                    source: InputSource::VecU8 {
+                        filename: attachment.filename.clone(),
+                        vec: attachment.download().await?,
+                    },
+                })
+                .build()?;
+            anyhow::Ok(self.ai.transcribe(request).await?)
+        }
+        .await;
+
+        let mut usage = Usage {
+            invocations: 1,
+            ..Default::default()
+        };
+        match result {
+            Ok(transcription) => {
+                let tokens = transcription.usage.unwrap_or_default();
+                usage.prompt_tokens = tokens.input_tokens;
+                usage.completion_tokens = tokens.output_tokens;
+
+                let text = transcription.text.trim();
+                if text.is_empty() {
+                    outputter.update("*No speech was found.*");
+                } else {
+                    outputter.update(&quote(text));
+                }
+                outputter.finish();
+            }
+            Err(err) => outputter.error(&err.to_string()),
+        }
+        usage.wall_time_ms = started.elapsed().as_millis() as u64;
+        self.usage
+            .record(cmd.user.id, cmd.guild_id, &cmd.data.name, usage);
+
+        outputter.join().await
+    }
+}
+
+async fn respond_privately(
+    http: &Http,
+    cmd: &CommandInteraction,
+    content: &str,
+) -> anyhow::Result<()> {
+    cmd.create_response(
+        http,
+        CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(content)
+                .ephemeral(true),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Returns the message's first audio attachment
+fn find_audio(message: &Message) -> Option<&Attachment> {
+    let is_voice_message = message
+        .flags
+        .is_some_and(|flags| flags.contains(MessageFlags::IS_VOICE_MESSAGE));
+    message.attachments.iter().find(|attachment| {
+        is_voice_message || is_audio(attachment.content_type.as_deref(), &attachment.filename)
+    })
+}
+
+fn is_audio(content_type: Option<&str>, filename: &str) -> bool {
+    match content_type {
+        Some(content_type) => content_type.starts_with("audio/"),
+        None => filename.rsplit_once('.').is_some_and(|(_, extension)| {
+            AUDIO_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        }),
+    }
+}
+
+/// Formats the transcription as a block quote, so it's clear the words aren't the bot's
+fn quote(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("> {line}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_audio() {
+        assert!(is_audio(Some("audio/ogg"), "voice-message.ogg"));
+        assert!(!is_audio(Some("image/png"), "image.ogg"));
+        assert!(is_audio(None, "recording.MP3"));
+        assert!(!is_audio(None, "notes.txt"));
+        assert!(!is_audio(None, "ogg"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("hello"), "> hello");
+        assert_eq!(quote("one\ntwo"), "> one\n> two");
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use serenity::all::{
+    CommandInteraction, CommandOptionType, CreateAllowedMentions, CreateCommand,
+    CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage, Http,
+};
+
+use crate::{
+    commands::CommandHandler,
+    constant,
+    usage::{Usage, UsageReport, UsageTracker},
+};
+
+pub struct Handler(Arc<UsageTracker>);
+impl Handler {
+    pub fn new(tracker: Arc<UsageTracker>) -> Self {
+        Self(tracker)
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn create_command(&self) -> Option<CreateCommand> {
+        Some(
+            CreateCommand::new(constant::commands::USAGE)
+                .description("Show how much of the bot's capacity you've used.")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::User,
+                        constant::value::USER,
+                        "The user to show the usage of (default: you).",
+                    )
+                    .required(false),
+                ),
+        )
+    }
+
+    async fn run(&self, http: Arc<Http>, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        let user_id = cmd
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == constant::value::USER)
+            .and_then(|o| o.value.as_user_id())
+            .unwrap_or(cmd.user.id);
+
+        let report = self.0.report(user_id);
+        let mut content = format!("**Usage for <@{user_id}>**\n");
+        content.push_str(&format!("Today: {}\n", describe(&report.today)));
+        let period = match report.retention_days {
+            0 => "All time".to_string(),
+            days => format!("Last {days} days"),
+        };
+        content.push_str(&format!("{period}: {}\n", describe(&report.total)));
+        if let Some(remaining) = describe_remaining(&report) {
+            content.push_str(&format!("Left today: {remaining}\n"));
+        }
+        if !report.commands.is_empty() {
+            content.push_str("\n**By command, today**\n");
+            for (command, usage) in &report.commands {
+                content.push_str(&format!("- `/{command}`: {}\n", describe(usage)));
+            }
+        }
+
+        cmd.create_response(
+            &*http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(content)
+                    .allowed_mentions(CreateAllowedMentions::new())
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+fn describe(usage: &Usage) -> String {
+    format!(
+        "{} runs, {} tokens ({} prompt, {} completion), {} image generations, {:.1}s",
+        usage.invocations,
+        usage.tokens(),
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.image_generations,
+        usage.wall_time_ms as f64 / 1000.0
+    )
+}
+
+fn describe_remaining(report: &UsageReport) -> Option<String> {
+    let remaining: Vec<_> = [
+        report.remaining_tokens.map(|n| format!("{n} tokens")),
+        report
+            .remaining_image_generations
+            .map(|n| format!("{n} image generations")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!remaining.is_empty()).then(|| remaining.join(", "))
+}
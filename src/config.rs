@@ -1,11 +1,26 @@
+use std::{collections::HashMap, path::Path};
+
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use serenity::all::ChannelId;
+
+use crate::permissions::PermissionRules;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(default)]
 pub struct Configuration {
     pub authentication: Authentication,
+    pub llm: Llm,
     pub discord: Discord,
+    pub scripts: Scripts,
+    pub sandbox: Sandbox,
+    pub permissions: CommandPermissions,
+    pub rate_limit: RateLimit,
+    pub storage: Storage,
+    pub usage: Usage,
+    pub vectors: Vectors,
+    pub history: History,
+    pub audio: Audio,
 }
 impl Configuration {
     const FILENAME: &str = "config.toml";
@@ -16,11 +31,30 @@ impl Configuration {
         } else {
             Self::default()
         };
+        config.validate()?;
         config.save()?;
 
         Ok(config)
     }
 
+    /// Checks for mistakes that would otherwise only show up once the bot is running
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // Scripts can reach anything in the storage and vector directories, so the history
+        // index must be kept out of both
+        let history = Path::new(&self.history.directory);
+        if history == Path::new(&self.vectors.directory)
+            || history == Path::new(&self.storage.directory)
+        {
+            anyhow::bail!(
+                "history.directory must differ from vectors.directory and storage.directory, \
+                 so that scripts can't read or modify the history index"
+            );
+        }
+
+        self.permissions.validate()?;
+        Ok(())
+    }
+
     fn save(&self) -> anyhow::Result<()> {
         Ok(std::fs::write(
             Self::FILENAME,
@@ -37,6 +71,41 @@ pub struct Authentication {
     pub openai_api_key: Option<String>,
 }
 
+/// Where LLM requests are sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Llm {
+    /// OpenAI-compatible servers, in order of preference. Requests go to the first backend
+    /// that lists the model, and on to the next one that does if it can't be reached.
+    /// If none are declared, the server and key in `[authentication]` are used.
+    pub backends: Vec<Backend>,
+    /// How often to fetch the backends' model lists again (0 to only fetch them at startup)
+    pub models_refresh_interval_secs: u64,
+    /// How many more times to try a request, with backoff, when no backend serving the model
+    /// can be reached
+    pub request_retries: u32,
+}
+
+impl Default for Llm {
+    fn default() -> Self {
+        Self {
+            backends: vec![],
+            models_refresh_interval_secs: 300,
+            request_retries: 2,
+        }
+    }
+}
+
+/// An OpenAI-compatible server
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Backend {
+    /// Shown in logs and as the `backend` of each model it serves
+    pub name: String,
+    pub api_server: Option<String>,
+    pub api_key: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct Discord {
@@ -46,6 +115,8 @@ pub struct Discord {
     pub replace_newlines: bool,
     /// Size of the LRU cache for interaction contexts (for reply handling)
     pub interaction_context_cache_size: usize,
+    /// File the interaction contexts are persisted to, so replies keep working across restarts
+    pub interaction_context_path: String,
 }
 
 impl Default for Discord {
@@ -54,6 +125,278 @@ impl Default for Discord {
             message_update_interval_ms: 1000,
             replace_newlines: true,
             interaction_context_cache_size: 10000,
+            interaction_context_path: "interaction_contexts.jsonl".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Scripts {
+    /// Whether to reload `scripts/main.lua` and `scripts/commands.lua` when they change on disk
+    pub hot_reload: bool,
+    /// How often to check the scripts for changes
+    pub hot_reload_poll_interval_ms: u64,
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self {
+            hot_reload: true,
+            hot_reload_poll_interval_ms: 1000,
+        }
+    }
+}
+
+/// Limits applied to `/execute` runs. A value of 0 disables the corresponding limit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Sandbox {
+    /// Maximum wall-clock time a run may take, including time spent waiting on requests
+    pub timeout_ms: u64,
+    /// Maximum number of Luau interrupts a run may trigger; these fire on function calls
+    /// and loop iterations, so this bounds how much code can run between yields
+    pub instruction_budget: u64,
+    /// Maximum amount of memory the run's Lua state may allocate
+    pub memory_limit_bytes: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 60_000,
+            instruction_budget: 50_000_000,
+            memory_limit_bytes: 64 * 1024 * 1024,
         }
     }
 }
+
+/// Permission rules for commands, checked in addition to any declared by the command's script
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CommandPermissions {
+    /// Rules applied to every command
+    pub default: PermissionRules,
+    /// Rules for individual commands, keyed by command name
+    pub commands: HashMap<String, PermissionRules>,
+}
+impl CommandPermissions {
+    /// The rules that apply to the given command, from least to most specific
+    pub fn rules_for(&self, command_name: &str) -> impl Iterator<Item = &PermissionRules> {
+        std::iter::once(&self.default).chain(self.commands.get(command_name))
+    }
+
+    /// Checks that every `default_member_permissions` names a real Discord permission
+    fn validate(&self) -> anyhow::Result<()> {
+        self.default
+            .member_permissions()
+            .context("invalid permissions.default")?;
+        for (name, rules) in &self.commands {
+            rules
+                .member_permissions()
+                .with_context(|| format!("invalid permissions for command `{name}`"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Throttling applied to command executions and replies to them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Bucket shared by everything a single user runs
+    pub user: Bucket,
+    /// Bucket shared by everything run in a single server
+    pub guild: Bucket,
+    /// Bucket shared by every run of a single command, across all users
+    pub command: Bucket,
+    /// Maximum number of executions a user may have running at once (0 to disable)
+    pub max_concurrent_per_user: usize,
+    /// How many tokens each command costs, keyed by command name. Overrides the cost
+    /// declared by the command's script; commands without either cost 1.
+    pub costs: HashMap<String, f64>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            user: Bucket {
+                capacity: 10.0,
+                refill_per_second: 0.1,
+            },
+            guild: Bucket::default(),
+            command: Bucket::default(),
+            max_concurrent_per_user: 2,
+            costs: HashMap::new(),
+        }
+    }
+}
+
+/// A token bucket: each execution takes its cost in tokens, and tokens refill over time
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Bucket {
+    /// Maximum number of tokens the bucket holds (0 to disable)
+    pub capacity: f64,
+    /// How many tokens are added back each second
+    pub refill_per_second: f64,
+}
+
+/// Persistent storage available to scripts. A value of 0 disables the corresponding limit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Storage {
+    /// Directory the data is kept in, as one JSON file per user, guild and the global scope
+    pub directory: String,
+    /// Maximum size of a single value, as JSON
+    pub max_value_bytes: usize,
+    /// Maximum size of everything stored for a single user, guild or the global scope
+    pub max_scope_bytes: usize,
+    /// Maximum size of everything stored
+    pub max_total_bytes: usize,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            directory: "storage".to_string(),
+            max_value_bytes: 64 * 1024,
+            max_scope_bytes: 1024 * 1024,
+            max_total_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Accounting of what each user consumes. A budget of 0 is unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Usage {
+    /// File the usage records are kept in
+    pub path: String,
+    /// How many days of records to keep (0 to keep them forever)
+    pub retention_days: u64,
+    /// Daily budget for each user, across every server
+    pub user_budget: Budget,
+    /// Daily budget for each server, shared by all of its users
+    pub guild_budget: Budget,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            path: "usage.json".to_string(),
+            retention_days: 30,
+            user_budget: Budget::default(),
+            guild_budget: Budget::default(),
+        }
+    }
+}
+
+/// Vector indexes available to scripts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Vectors {
+    /// Directory the indexes are kept in, as one JSON file per index
+    pub directory: String,
+    /// Maximum number of entries in a single index (0 to disable)
+    pub max_entries_per_index: usize,
+}
+
+impl Default for Vectors {
+    fn default() -> Self {
+        Self {
+            directory: "vectors".to_string(),
+            max_entries_per_index: 100_000,
+        }
+    }
+}
+
+/// Indexing of channel messages so that commands can search past conversations. Nothing is
+/// indexed unless both channels and an embedding model are given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct History {
+    /// Channels whose messages are indexed. A search from a server only covers the ones the
+    /// user searching can read. Deleted messages are forgotten, but edits to a message made
+    /// after it was indexed aren't picked up.
+    pub channels: Vec<ChannelId>,
+    /// Model used to embed the messages and the queries searching them
+    pub embedding_model: String,
+    /// Directory the index of messages is kept in. It's separate from `[vectors]` so that
+    /// scripts, including `/execute`, can only reach the history through `history.search`.
+    pub directory: String,
+    /// How many consecutive messages are embedded together
+    pub chunk_messages: usize,
+    /// How long a channel must be quiet before a partial chunk is embedded
+    pub chunk_idle_secs: u64,
+    /// Maximum number of chunks kept; the oldest are dropped to make room (0 to disable)
+    pub max_chunks: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            channels: vec![],
+            embedding_model: String::new(),
+            directory: "history".to_string(),
+            chunk_messages: 8,
+            chunk_idle_secs: 300,
+            max_chunks: 20_000,
+        }
+    }
+}
+
+/// Speech-to-text for voice messages and audio attachments. `/transcribe` is only available if
+/// a transcription model is given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Audio {
+    /// Model used by `/transcribe`
+    pub transcription_model: String,
+    /// Largest audio file `/transcribe` will download, in bytes
+    pub max_transcription_bytes: u64,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self {
+            transcription_model: String::new(),
+            max_transcription_bytes: 25 * 1024 * 1024,
+        }
+    }
+}
+
+/// How much may be consumed each day, resetting at midnight UTC
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Budget {
+    /// Prompt and completion tokens (0 for no limit)
+    pub daily_tokens: u64,
+    /// ComfyUI workflows run and images generated or edited (0 for no limit)
+    pub daily_image_generations: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_rejects_unknown_member_permissions() {
+        let mut config = Configuration::default();
+        config.validate().unwrap();
+
+        config.permissions.commands.insert(
+            "execute".to_string(),
+            PermissionRules {
+                default_member_permissions: Some(vec!["manage_guild".to_string()]),
+                ..Default::default()
+            },
+        );
+        config.validate().unwrap();
+
+        config.permissions.default.default_member_permissions =
+            Some(vec!["MANAGE_EVERYTHING".to_string()]);
+        let err = config.validate().unwrap_err();
+        assert!(format!("{err:#}").contains("MANAGE_EVERYTHING"), "{err:#}");
+    }
+}
@@ -2,10 +2,22 @@
 pub mod value {
     pub const MESSAGE_ID: &str = "message_id";
     pub const CODE: &str = "code";
+    pub const USER: &str = "user";
+    pub const ATTACHMENT: &str = "attachment";
 }
 
 /// names of non-user-configurable commands
 pub mod commands {
     pub const EXECUTE: &str = "execute";
     pub const EXECUTE_MSG: &str = "executemsg";
+    pub const USAGE: &str = "usage";
+    pub const TRANSCRIBE: &str = "transcribe";
+    /// Shown in a message's Apps menu, so it's named like a menu entry
+    pub const TRANSCRIBE_MSG: &str = "Transcribe audio";
+}
+
+/// names that message triggers are permitted, rate limited and accounted under, like commands
+pub mod triggers {
+    pub const MENTION: &str = "mention";
+    pub const DM: &str = "dm";
 }
@@ -0,0 +1,483 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use async_openai::types::embeddings::{CreateEmbeddingRequestArgs, EmbeddingInput};
+use serde::{Deserialize, Serialize};
+use serenity::all::{Cache, ChannelId, GuildId, Http, Message, MessageId, Timestamp, UserId};
+
+use crate::{ai::Ai, config, vectors::VectorIndexes};
+
+/// How often buffered messages are checked for having gone idle
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Name of the index within the history directory
+const INDEX: &str = "chunks";
+
+/// A message waiting to be indexed as part of a chunk
+struct PendingMessage {
+    id: MessageId,
+    guild_id: Option<GuildId>,
+    author: String,
+    content: String,
+    timestamp: i64,
+    received: Instant,
+}
+
+/// Messages waiting to be indexed, by channel
+#[derive(Default)]
+struct PendingChunks(HashMap<ChannelId, Vec<PendingMessage>>);
+impl PendingChunks {
+    /// Buffers a message, returning its channel's chunk if that fills it
+    fn push(
+        &mut self,
+        channel_id: ChannelId,
+        message: PendingMessage,
+        chunk_messages: usize,
+    ) -> Option<Vec<PendingMessage>> {
+        let messages = self.0.entry(channel_id).or_default();
+        messages.push(message);
+        if messages.len() < chunk_messages.max(1) {
+            return None;
+        }
+        self.0.remove(&channel_id)
+    }
+
+    /// Takes the chunks of every channel that hasn't had a message for `idle`
+    fn take_idle(&mut self, now: Instant, idle: Duration) -> Vec<(ChannelId, Vec<PendingMessage>)> {
+        let idle_channels: Vec<_> = self
+            .0
+            .iter()
+            .filter(|(_, messages)| {
+                messages
+                    .last()
+                    .is_some_and(|message| now.saturating_duration_since(message.received) >= idle)
+            })
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        idle_channels
+            .into_iter()
+            .filter_map(|channel_id| Some((channel_id, self.0.remove(&channel_id)?)))
+            .collect()
+    }
+
+    fn forget(&mut self, channel_id: ChannelId, message_ids: &[MessageId]) {
+        if let Some(messages) = self.0.get_mut(&channel_id) {
+            messages.retain(|message| !message_ids.contains(&message.id));
+            if messages.is_empty() {
+                self.0.remove(&channel_id);
+            }
+        }
+    }
+}
+
+/// What's stored alongside each chunk's vector
+#[derive(Serialize, Deserialize)]
+struct ChunkMetadata {
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    /// The first message in the chunk
+    message_id: MessageId,
+    /// Every message in the chunk, so that it can be forgotten if any of them are deleted.
+    /// Chunks indexed before this was recorded only know their first message.
+    #[serde(default)]
+    message_ids: Vec<MessageId>,
+    /// Unix timestamp of the first message in the chunk
+    timestamp: i64,
+    text: String,
+}
+
+/// A chunk of history found by [`HistoryIndexer::search`]
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryResult {
+    pub text: String,
+    /// Link to the first message in the chunk
+    pub link: String,
+    pub channel_id: String,
+    pub message_id: String,
+    pub timestamp: i64,
+    /// The timestamp, in RFC 3339 format
+    pub date: String,
+    /// Cosine similarity to the query, from -1 to 1
+    pub score: f32,
+}
+
+/// Where [`HistoryIndexer::search`] looks; `None` means anywhere
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub guild_id: Option<GuildId>,
+    pub channel_ids: Option<HashSet<ChannelId>>,
+}
+impl HistoryFilter {
+    fn matches(&self, metadata: &ChunkMetadata) -> bool {
+        self.guild_id
+            .is_none_or(|guild_id| metadata.guild_id == Some(guild_id))
+            && self
+                .channel_ids
+                .as_ref()
+                .is_none_or(|channel_ids| channel_ids.contains(&metadata.channel_id))
+    }
+}
+
+/// Indexes the messages of configured channels for semantic search.
+///
+/// Consecutive messages in a channel are grouped into chunks, which are embedded and added to
+/// a vector index once they're full or the channel has gone quiet. Only messages seen while
+/// running are indexed; there is no backfill. The index is kept apart from the ones scripts
+/// can open, so it can only be searched through [`HistoryIndexer::search`]. It's written out
+/// alongside the idle check rather than on every chunk, and the oldest chunks are dropped once
+/// it's full.
+pub struct HistoryIndexer {
+    config: config::History,
+    ai: Arc<Ai>,
+    vectors: Arc<VectorIndexes>,
+    pending: Mutex<PendingChunks>,
+    /// Used to work out which channels a searcher can see; set once the client exists
+    discord: OnceLock<(Arc<Cache>, Arc<Http>)>,
+}
+impl HistoryIndexer {
+    pub fn new(config: config::History, ai: Arc<Ai>) -> anyhow::Result<Self> {
+        Ok(Self {
+            vectors: Arc::new(open_index(&config)?),
+            config,
+            ai,
+            pending: Mutex::default(),
+            discord: OnceLock::new(),
+        })
+    }
+
+    /// Gives the indexer access to Discord, which it needs to check what searchers can see
+    pub fn connect(&self, cache: Arc<Cache>, http: Arc<Http>) {
+        let _ = self.discord.set((cache, http));
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.channels.is_empty() && !self.config.embedding_model.is_empty()
+    }
+
+    /// Buffers a message if it's from an indexed channel, indexing its chunk if that fills it
+    pub fn observe(self: &Arc<Self>, msg: &Message) {
+        if !self.is_enabled()
+            || !self.config.channels.contains(&msg.channel_id)
+            || msg.content.trim().is_empty()
+        {
+            return;
+        }
+
+        let message = PendingMessage {
+            id: msg.id,
+            guild_id: msg.guild_id,
+            author: msg.author.display_name().to_string(),
+            content: msg.content.clone(),
+            timestamp: msg.timestamp.unix_timestamp(),
+            received: Instant::now(),
+        };
+        let chunk =
+            self.pending
+                .lock()
+                .unwrap()
+                .push(msg.channel_id, message, self.config.chunk_messages);
+        if let Some(chunk) = chunk {
+            tokio::spawn(self.clone().index(msg.channel_id, chunk));
+        }
+    }
+
+    /// Indexes chunks whose channel has been quiet for a while, then writes out the index.
+    /// Runs forever.
+    pub async fn flush_idle_chunks(self: Arc<Self>) {
+        let idle = Duration::from_secs(self.config.chunk_idle_secs);
+        loop {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+            let idle_chunks = self.pending.lock().unwrap().take_idle(Instant::now(), idle);
+            for (channel_id, chunk) in idle_chunks {
+                self.clone().index(channel_id, chunk).await;
+            }
+            self.save().await;
+        }
+    }
+
+    /// Indexes every buffered chunk, however short, then writes out the index. Used on shutdown,
+    /// so that the messages buffered so far aren't lost.
+    pub async fn index_pending(self: Arc<Self>) {
+        let chunks = self
+            .pending
+            .lock()
+            .unwrap()
+            .take_idle(Instant::now(), Duration::ZERO);
+        for (channel_id, chunk) in chunks {
+            self.clone().index(channel_id, chunk).await;
+        }
+        self.save().await;
+    }
+
+    /// Writes out the index if it has changed, without blocking the runtime
+    async fn save(&self) {
+        let vectors = self.vectors.clone();
+        match tokio::task::spawn_blocking(move || vectors.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("Failed to save the history index: {err:?}"),
+            Err(err) => eprintln!("History index flush panicked: {err:?}"),
+        }
+    }
+
+    /// Forgets deleted messages, dropping any indexed chunk that contains one of them
+    pub fn forget(&self, channel_id: ChannelId, message_ids: &[MessageId]) {
+        if !self.is_enabled() || !self.config.channels.contains(&channel_id) {
+            return;
+        }
+
+        self.pending.lock().unwrap().forget(channel_id, message_ids);
+        let removed = self.vectors.remove_matching(INDEX, |metadata| {
+            ChunkMetadata::deserialize(metadata).is_ok_and(|metadata| {
+                metadata.channel_id == channel_id
+                    && (message_ids.contains(&metadata.message_id)
+                        || metadata
+                            .message_ids
+                            .iter()
+                            .any(|id| message_ids.contains(id)))
+            })
+        });
+        if let Err(err) = removed {
+            eprintln!("Failed to forget deleted messages in channel {channel_id}: {err:?}");
+        }
+    }
+
+    /// Returns the indexed channels of a server that a member can read the history of. If that
+    /// can't be worked out, only `fallback`, the channel they're searching from, is returned.
+    pub async fn channels_visible_to(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        fallback: ChannelId,
+    ) -> HashSet<ChannelId> {
+        let only_fallback = HashSet::from([fallback]);
+        let Some((cache, http)) = self.discord.get() else {
+            return only_fallback;
+        };
+        let member = match guild_id.member((cache, &**http), user_id).await {
+            Ok(member) => member,
+            Err(err) => {
+                eprintln!("Failed to look up member {user_id} of {guild_id}: {err}");
+                return only_fallback;
+            }
+        };
+        let Some(guild) = cache.guild(guild_id) else {
+            return only_fallback;
+        };
+
+        let mut visible: HashSet<_> = self
+            .config
+            .channels
+            .iter()
+            .filter(|channel_id| {
+                // Threads are readable by whoever can read their parent
+                let channel = guild.channels.get(channel_id).or_else(|| {
+                    let parent_id = guild
+                        .threads
+                        .iter()
+                        .find(|thread| thread.id == **channel_id)?
+                        .parent_id?;
+                    guild.channels.get(&parent_id)
+                });
+                channel.is_some_and(|channel| {
+                    let permissions = guild.user_permissions_in(channel, &member);
+                    permissions.view_channel() && permissions.read_message_history()
+                })
+            })
+            .copied()
+            .collect();
+        visible.insert(fallback);
+        visible
+    }
+
+    async fn index(self: Arc<Self>, channel_id: ChannelId, chunk: Vec<PendingMessage>) {
+        if let Err(err) = self.try_index(channel_id, &chunk).await {
+            eprintln!("Failed to index history of channel {channel_id}: {err:?}");
+        }
+    }
+
+    async fn try_index(
+        &self,
+        channel_id: ChannelId,
+        chunk: &[PendingMessage],
+    ) -> anyhow::Result<()> {
+        let Some(first) = chunk.first() else {
+            return Ok(());
+        };
+        let text = chunk
+            .iter()
+            .map(|message| format!("{}: {}", message.author, message.content))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let vector = self.embed(text.clone()).await?.0;
+        let metadata = ChunkMetadata {
+            guild_id: first.guild_id,
+            channel_id,
+            message_id: first.id,
+            message_ids: chunk.iter().map(|message| message.id).collect(),
+            timestamp: first.timestamp,
+            text,
+        };
+        self.vectors.add_evicting(
+            INDEX,
+            &format!("{channel_id}-{}", first.id),
+            vector,
+            serde_json::to_value(metadata)?,
+            |metadata| metadata["timestamp"].as_i64().unwrap_or_default(),
+        )
+    }
+
+    /// Returns the `k` chunks most relevant to `query`, and the tokens used to embed the query
+    pub async fn search(
+        &self,
+        query: &str,
+        k: usize,
+        filter: HistoryFilter,
+    ) -> anyhow::Result<(Vec<HistoryResult>, u64)> {
+        if !self.is_enabled() {
+            anyhow::bail!("history indexing is not enabled");
+        }
+
+        let (query, tokens) = self.embed(query.to_string()).await?;
+        // Comparing against every chunk can take a while with a large index
+        let vectors = self.vectors.clone();
+        let results = tokio::task::spawn_blocking(move || {
+            vectors.search_matching(INDEX, &query, k, |metadata| {
+                ChunkMetadata::deserialize(metadata).is_ok_and(|metadata| filter.matches(&metadata))
+            })
+        })
+        .await??
+        .into_iter()
+        .filter_map(|result| {
+            let metadata = ChunkMetadata::deserialize(&result.metadata).ok()?;
+            Some(HistoryResult {
+                link: metadata
+                    .message_id
+                    .link(metadata.channel_id, metadata.guild_id),
+                channel_id: metadata.channel_id.to_string(),
+                message_id: metadata.message_id.to_string(),
+                timestamp: metadata.timestamp,
+                date: Timestamp::from_unix_timestamp(metadata.timestamp)
+                    .map(|timestamp| timestamp.to_string())
+                    .unwrap_or_default(),
+                text: metadata.text,
+                score: result.score,
+            })
+        })
+        .collect();
+        Ok((results, tokens))
+    }
+
+    /// Embeds `text` with the configured model, returning the vector and the tokens it used
+    async fn embed(&self, text: String) -> anyhow::Result<(Vec<f32>, u64)> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(self.config.embedding_model.clone())
+            .input(EmbeddingInput::String(text))
+            .build()?;
+        let response = self.ai.embed(request).await?;
+        let tokens = response.usage.total_tokens as u64;
+        let vector = response
+            .data
+            .into_iter()
+            .next()
+            .map(|embedding| embedding.embedding)
+            .ok_or_else(|| anyhow::anyhow!("no embedding was returned"))?;
+        Ok((vector, tokens))
+    }
+}
+
+/// Opens the vector indexes kept in the history directory
+pub fn open_index(config: &config::History) -> anyhow::Result<VectorIndexes> {
+    VectorIndexes::load_batched(config::Vectors {
+        directory: config.directory.clone(),
+        max_entries_per_index: config.max_chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, received: Instant) -> PendingMessage {
+        PendingMessage {
+            id: MessageId::new(id),
+            guild_id: None,
+            author: "someone".to_string(),
+            content: format!("message {id}"),
+            timestamp: 0,
+            received,
+        }
+    }
+
+    fn ids(messages: &[PendingMessage]) -> Vec<u64> {
+        messages.iter().map(|message| message.id.get()).collect()
+    }
+
+    #[test]
+    fn test_chunking() {
+        let mut pending = PendingChunks::default();
+        let (a, b) = (ChannelId::new(1), ChannelId::new(2));
+        let now = Instant::now();
+
+        assert!(pending.push(a, message(1, now), 3).is_none());
+        assert!(pending.push(b, message(2, now), 3).is_none());
+        assert!(pending.push(a, message(3, now), 3).is_none());
+        let chunk = pending.push(a, message(4, now), 3).unwrap();
+        assert_eq!(ids(&chunk), [1, 3, 4]);
+
+        // The full chunk is gone, but the other channel's messages are still waiting
+        assert!(pending.push(a, message(5, now), 3).is_none());
+        assert_eq!(pending.0[&b].len(), 1);
+    }
+
+    #[test]
+    fn test_idle_chunks_and_deletions() {
+        let mut pending = PendingChunks::default();
+        let (quiet, busy) = (ChannelId::new(1), ChannelId::new(2));
+        let idle = Duration::from_secs(300);
+        let start = Instant::now();
+
+        pending.push(quiet, message(1, start), 8);
+        pending.push(quiet, message(2, start), 8);
+        pending.push(busy, message(3, start), 8);
+        pending.push(busy, message(4, start + idle), 8);
+        pending.forget(quiet, &[MessageId::new(1)]);
+
+        let chunks = pending.take_idle(start + idle, idle);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].0, quiet);
+        assert_eq!(ids(&chunks[0].1), [2]);
+        assert!(pending.take_idle(start + idle, idle).is_empty());
+
+        // Forgetting a channel's last message drops the channel
+        pending.forget(busy, &[MessageId::new(3), MessageId::new(4)]);
+        assert!(pending.0.is_empty());
+    }
+
+    #[test]
+    fn test_filter() {
+        let chunk = |guild_id: Option<u64>, channel_id: u64| ChunkMetadata {
+            guild_id: guild_id.map(GuildId::new),
+            channel_id: ChannelId::new(channel_id),
+            message_id: MessageId::new(1),
+            message_ids: vec![],
+            timestamp: 0,
+            text: String::new(),
+        };
+        let in_guild = HistoryFilter {
+            guild_id: Some(GuildId::new(10)),
+            channel_ids: Some(HashSet::from([ChannelId::new(1), ChannelId::new(2)])),
+        };
+
+        assert!(HistoryFilter::default().matches(&chunk(Some(20), 5)));
+        assert!(in_guild.matches(&chunk(Some(10), 1)));
+        // Channels the searcher can't read are left out, as are other servers and DMs
+        assert!(!in_guild.matches(&chunk(Some(10), 3)));
+        assert!(!in_guild.matches(&chunk(Some(20), 1)));
+        assert!(!in_guild.matches(&chunk(None, 1)));
+    }
+}
@@ -1,29 +1,41 @@
-use std::{collections::HashMap, num::NonZeroUsize, sync::Mutex};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead as _, BufReader, Write as _},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
+use anyhow::Context as _;
 use lru::LruCache;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serenity::all::{ChannelId, GuildId, MessageId, UserId};
 
 /// Context stored for an interaction response, allowing us to handle replies
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InteractionContext {
     /// The command that was invoked
     pub command_name: String,
     /// The options passed to the command (name -> value as string/number)
+    #[serde(with = "tagged_options")]
     pub options: HashMap<String, OptionValue>,
     /// The user who invoked the command
     #[allow(dead_code)]
     pub user_id: UserId,
     /// The channel where the command was invoked
-    #[allow(dead_code)]
     pub channel_id: ChannelId,
     /// The guild where the command was invoked (None for DMs)
     #[allow(dead_code)]
     pub guild_id: Option<GuildId>,
+    /// The thread opened on the response to hold the conversation, if any
+    #[serde(default)]
+    pub thread_id: Option<ChannelId>,
 }
 
-/// A command option value
-#[derive(Clone, Debug, Serialize)]
+/// A command option value. Serializes as the bare value, for Lua; see [`tagged_options`]
+/// for the lossless form used on disk.
+#[derive(Clone, Debug, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum OptionValue {
     String(String),
@@ -65,28 +77,285 @@ impl OptionValue {
     }
 }
 
-/// Thread-safe LRU cache for interaction contexts
+/// Serializes option values with their type, so that e.g. attachments don't come back as strings
+mod tagged_options {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::OptionValue;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "type", content = "value", rename_all = "snake_case")]
+    enum TaggedOptionValue {
+        String(String),
+        Integer(i64),
+        Number(f64),
+        Boolean(bool),
+        Attachment(String),
+    }
+
+    pub fn serialize<S: Serializer>(
+        options: &HashMap<String, OptionValue>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        options
+            .iter()
+            .map(|(name, value)| {
+                let value = match value.clone() {
+                    OptionValue::String(s) => TaggedOptionValue::String(s),
+                    OptionValue::Integer(i) => TaggedOptionValue::Integer(i),
+                    OptionValue::Number(n) => TaggedOptionValue::Number(n),
+                    OptionValue::Boolean(b) => TaggedOptionValue::Boolean(b),
+                    OptionValue::Attachment(url) => TaggedOptionValue::Attachment(url),
+                };
+                (name, value)
+            })
+            .collect::<HashMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, OptionValue>, D::Error> {
+        Ok(
+            HashMap::<String, TaggedOptionValue>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        TaggedOptionValue::String(s) => OptionValue::String(s),
+                        TaggedOptionValue::Integer(i) => OptionValue::Integer(i),
+                        TaggedOptionValue::Number(n) => OptionValue::Number(n),
+                        TaggedOptionValue::Boolean(b) => OptionValue::Boolean(b),
+                        TaggedOptionValue::Attachment(url) => OptionValue::Attachment(url),
+                    };
+                    (name, value)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Thread-safe LRU cache for interaction contexts, persisted to disk.
+///
+/// Changes are appended to a JSON Lines log, including lookups so that recency survives a
+/// restart. Replaying the log rebuilds the same cache, and it's compacted to one record per
+/// cached context whenever it grows to twice the capacity.
 pub struct InteractionContextStore {
-    cache: Mutex<LruCache<MessageId, InteractionContext>>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    cache: LruCache<MessageId, InteractionContext>,
+    path: PathBuf,
+    log: File,
+    /// Number of records in the log
+    records: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Store {
+        message_id: MessageId,
+        context: InteractionContext,
+    },
+    Touch {
+        message_id: MessageId,
+    },
 }
 
 impl InteractionContextStore {
-    /// Create a new store with the given capacity
-    pub fn new(capacity: usize) -> Self {
-        Self {
-            cache: Mutex::new(LruCache::new(
-                NonZeroUsize::new(capacity).expect("capacity must be non-zero"),
-            )),
+    /// Loads the store from the log at `path` (creating it if necessary), keeping at most
+    /// `capacity` contexts
+    pub fn load(capacity: usize, path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut cache = LruCache::new(
+            NonZeroUsize::new(capacity).context("interaction context capacity must be non-zero")?,
+        );
+
+        match File::open(&path) {
+            Ok(file) => {
+                for (index, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    // A crash mid-write can leave a truncated last line; skip anything unreadable
+                    match serde_json::from_str::<Record>(&line) {
+                        Ok(Record::Store {
+                            message_id,
+                            context,
+                        }) => {
+                            cache.put(message_id, context);
+                        }
+                        Ok(Record::Touch { message_id }) => {
+                            cache.get(&message_id);
+                        }
+                        Err(err) => {
+                            eprintln!("Skipping line {} of {path:?}: {err}", index + 1);
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("failed to read {path:?}")),
         }
+
+        let inner = Inner {
+            log: compact(&path, &cache)?,
+            records: cache.len(),
+            cache,
+            path,
+        };
+
+        Ok(Self {
+            inner: Mutex::new(inner),
+        })
     }
 
     /// Store context for a message ID (the bot's response message)
     pub fn store(&self, message_id: MessageId, context: InteractionContext) {
-        self.cache.lock().unwrap().put(message_id, context);
+        let mut inner = self.inner.lock().unwrap();
+        let record = Record::Store {
+            message_id,
+            context: context.clone(),
+        };
+        inner.cache.put(message_id, context);
+        inner.append(&record);
     }
 
     /// Get context for a message ID
     pub fn get(&self, message_id: &MessageId) -> Option<InteractionContext> {
-        self.cache.lock().unwrap().get(message_id).cloned()
+        let mut inner = self.inner.lock().unwrap();
+        let context = inner.cache.get(message_id).cloned()?;
+        inner.append(&Record::Touch {
+            message_id: *message_id,
+        });
+        Some(context)
+    }
+}
+
+impl Inner {
+    /// Appends a record to the log, compacting it if it's grown too large. Failures are
+    /// logged rather than returned, as the in-memory cache is still usable.
+    fn append(&mut self, record: &Record) {
+        let result = (|| -> anyhow::Result<()> {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.log.write_all(&line)?;
+            self.records += 1;
+
+            if self.records >= self.cache.cap().get() * 2 {
+                self.log = compact(&self.path, &self.cache)?;
+                self.records = self.cache.len();
+            }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            eprintln!(
+                "Failed to persist interaction context to {:?}: {err:?}",
+                self.path
+            );
+        }
+    }
+}
+
+/// Rewrites the log at `path` to contain just the cached contexts, least recently used first,
+/// and returns it opened for appending
+fn compact(path: &Path, cache: &LruCache<MessageId, InteractionContext>) -> anyhow::Result<File> {
+    let mut contents = vec![];
+    for (message_id, context) in cache.iter().rev() {
+        serde_json::to_writer(
+            &mut contents,
+            &Record::Store {
+                message_id: *message_id,
+                context: context.clone(),
+            },
+        )?;
+        contents.push(b'\n');
+    }
+    crate::storage::write_atomically(path, &contents)?;
+
+    File::options()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(command_name: &str) -> InteractionContext {
+        InteractionContext {
+            command_name: command_name.to_string(),
+            options: HashMap::from([
+                ("prompt".to_string(), OptionValue::String("hi".to_string())),
+                (
+                    "image".to_string(),
+                    OptionValue::Attachment("https://example.com/a.png".to_string()),
+                ),
+                ("seed".to_string(), OptionValue::Integer(5)),
+                ("denoise".to_string(), OptionValue::Number(1.0)),
+            ]),
+            user_id: UserId::new(1),
+            channel_id: ChannelId::new(2),
+            guild_id: None,
+            thread_id: None,
+        }
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "paxcord-interaction-contexts-{name}-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_contexts_survive_reload() {
+        let path = log_path("reload");
+        let store = InteractionContextStore::load(10, &path).unwrap();
+        store.store(MessageId::new(1), context("ask"));
+        drop(store);
+
+        let store = InteractionContextStore::load(10, &path).unwrap();
+        let loaded = store.get(&MessageId::new(1)).unwrap();
+        assert_eq!(loaded.command_name, "ask");
+        assert_eq!(loaded.options, context("ask").options);
+        assert!(store.get(&MessageId::new(2)).is_none());
+    }
+
+    #[test]
+    fn test_recency_survives_reload() {
+        let path = log_path("recency");
+        let store = InteractionContextStore::load(2, &path).unwrap();
+        store.store(MessageId::new(1), context("a"));
+        store.store(MessageId::new(2), context("b"));
+        // Touching 1 makes 2 the least recently used
+        store.get(&MessageId::new(1));
+        drop(store);
+
+        let store = InteractionContextStore::load(2, &path).unwrap();
+        store.store(MessageId::new(3), context("c"));
+        assert!(store.get(&MessageId::new(1)).is_some());
+        assert!(store.get(&MessageId::new(2)).is_none());
+        assert!(store.get(&MessageId::new(3)).is_some());
+    }
+
+    #[test]
+    fn test_log_is_compacted() {
+        let path = log_path("compact");
+        let store = InteractionContextStore::load(2, &path).unwrap();
+        for i in 1..=10 {
+            store.store(MessageId::new(i), context("ask"));
+        }
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 4, "{lines} lines");
+
+        let store = InteractionContextStore::load(2, &path).unwrap();
+        assert!(store.get(&MessageId::new(9)).is_some());
+        assert!(store.get(&MessageId::new(10)).is_some());
+        assert!(store.get(&MessageId::new(8)).is_none());
     }
 }
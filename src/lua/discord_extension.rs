@@ -7,7 +7,10 @@ use mlua::{LuaSerdeExt as _, prelude::*};
 use serde::Deserialize;
 use serenity::all::CommandOptionType;
 
-use crate::commands::lua_command::{LuaCommand, LuaCommandOption, LuaCommandRegistry};
+use crate::{
+    commands::lua_command::{LuaCommand, LuaCommandOption, LuaCommandRegistry},
+    permissions::PermissionRules,
+};
 
 /// Maximum number of choices/suggestions allowed per option (Discord limit)
 const MAX_CHOICES: usize = 25;
@@ -22,10 +25,21 @@ struct Choice {
 /// Registry for reply handlers (command name -> Lua handler function)
 pub type LuaReplyHandlerRegistry = Arc<Mutex<HashMap<String, LuaFunction>>>;
 
+/// Handlers for messages that don't continue a command's conversation
+#[derive(Default)]
+pub struct LuaTriggers {
+    /// Called when the bot is mentioned in a server
+    pub mention: Option<LuaFunction>,
+    /// Called when the bot is sent a direct message
+    pub dm: Option<LuaFunction>,
+}
+pub type LuaTriggerRegistry = Arc<Mutex<LuaTriggers>>;
+
 pub fn register(
     lua: &Lua,
     command_registry: LuaCommandRegistry,
     reply_handler_registry: LuaReplyHandlerRegistry,
+    trigger_registry: LuaTriggerRegistry,
 ) -> LuaResult<()> {
     let discord = lua.create_table()?;
 
@@ -42,6 +56,31 @@ pub fn register(
             .transpose()?
             .unwrap_or_default();
 
+        // Parse permission rules
+        let permissions: PermissionRules = spec
+            .get::<LuaValue>("permissions")
+            .ok()
+            .filter(|v| !v.is_nil())
+            .map(|v| lua.from_value(v))
+            .transpose()?
+            .unwrap_or_default();
+        permissions
+            .member_permissions()
+            .map_err(|e| LuaError::runtime(format!("Command '{name}': {e}")))?;
+
+        // Rate limiting cost (defaults to 1 token per run)
+        let cost: Option<f64> = spec.get("cost")?;
+        if let Some(cost) = cost
+            && !(cost.is_finite() && cost >= 0.0)
+        {
+            return Err(LuaError::runtime(format!(
+                "Command '{name}': cost must be a non-negative number, got {cost}"
+            )));
+        }
+
+        // Whether to continue the conversation in a thread opened on the response
+        let thread = spec.get::<Option<bool>>("thread")?.unwrap_or(false);
+
         // Get execute handler as a function and store in registry
         let handler: LuaFunction = spec.get("execute")?;
 
@@ -51,6 +90,9 @@ pub fn register(
                 name,
                 description,
                 options,
+                permissions,
+                cost,
+                thread,
                 handler,
             },
         );
@@ -68,8 +110,21 @@ pub fn register(
         },
     )?;
 
+    let mention_registry = trigger_registry.clone();
+    let on_mention = lua.create_function(move |_lua, handler: LuaFunction| {
+        mention_registry.lock().unwrap().mention = Some(handler);
+        Ok(())
+    })?;
+
+    let on_dm = lua.create_function(move |_lua, handler: LuaFunction| {
+        trigger_registry.lock().unwrap().dm = Some(handler);
+        Ok(())
+    })?;
+
     discord.set("register_command", register_command)?;
     discord.set("register_reply_handler", register_reply_handler)?;
+    discord.set("on_mention", on_mention)?;
+    discord.set("on_dm", on_dm)?;
     lua.globals().set("discord", discord)?;
 
     Ok(())
@@ -105,26 +160,15 @@ fn parse_options(lua: &Lua, opts: LuaTable) -> LuaResult<Vec<LuaCommandOption>>
         let max_length: Option<u16> = opt.get("max_length").ok();
         let autocomplete: bool = opt.get("autocomplete").unwrap_or(false);
 
-        // Parse choices (strict) and suggestions (autocomplete) - mutually exclusive
-        let choices: Vec<(String, String)> = opt
-            .get::<LuaValue>("choices")
-            .ok()
-            .filter(|v| !v.is_nil())
-            .map(|v| lua.from_value::<Vec<Choice>>(v))
-            .transpose()?
-            .unwrap_or_default()
-            .into_iter()
-            .map(|c| (c.name, c.value))
-            .collect();
-
-        if choices.len() > MAX_CHOICES {
-            return Err(LuaError::runtime(format!(
-                "Option '{}' has {} choices, but Discord allows a maximum of {}",
-                name,
-                choices.len(),
-                MAX_CHOICES
-            )));
-        }
+        // Parse choices (strict) and suggestions (autocomplete) - mutually exclusive.
+        // Choices can also be given as a function, which is called again when the model
+        // list changes so that choices derived from it stay current.
+        let choices_value = opt.get::<LuaValue>("choices").unwrap_or(LuaValue::Nil);
+        let choices_source = choices_value.as_function().cloned();
+        let choices = match &choices_source {
+            Some(source) => evaluate_choices(lua, &name, source)?,
+            None => parse_choices(lua, &name, choices_value)?,
+        };
 
         let suggestions: Vec<(String, String)> = opt
             .get::<LuaValue>("suggestions")
@@ -160,8 +204,82 @@ fn parse_options(lua: &Lua, opts: LuaTable) -> LuaResult<Vec<LuaCommandOption>>
             max_length,
             autocomplete,
             choices,
+            choices_source,
             suggestions,
         });
     }
     Ok(options)
 }
+
+fn parse_choices(
+    lua: &Lua,
+    option_name: &str,
+    value: LuaValue,
+) -> LuaResult<Vec<(String, String)>> {
+    if value.is_nil() {
+        return Ok(vec![]);
+    }
+    let choices: Vec<(String, String)> = lua
+        .from_value::<Vec<Choice>>(value)?
+        .into_iter()
+        .map(|c| (c.name, c.value))
+        .collect();
+
+    if choices.len() > MAX_CHOICES {
+        return Err(LuaError::runtime(format!(
+            "Option '{}' has {} choices, but Discord allows a maximum of {}",
+            option_name,
+            choices.len(),
+            MAX_CHOICES
+        )));
+    }
+    Ok(choices)
+}
+
+fn evaluate_choices(
+    lua: &Lua,
+    option_name: &str,
+    source: &LuaFunction,
+) -> LuaResult<Vec<(String, String)>> {
+    parse_choices(lua, option_name, source.call(())?)
+}
+
+/// Calls the choice functions of every registered command again, so that the commands can be
+/// re-registered with up-to-date choices. A command whose choices fail to evaluate keeps its
+/// previous ones.
+pub fn refresh_choices(lua: &Lua, command_registry: &LuaCommandRegistry) {
+    // The functions are called without holding the lock, in case they use the registry
+    let sources: Vec<_> = command_registry
+        .lock()
+        .unwrap()
+        .values()
+        .flat_map(|command| {
+            command
+                .options
+                .iter()
+                .enumerate()
+                .filter_map(|(index, option)| {
+                    let source = option.choices_source.clone()?;
+                    Some((command.name.clone(), index, option.name.clone(), source))
+                })
+        })
+        .collect();
+
+    for (command_name, index, option_name, source) in sources {
+        match evaluate_choices(lua, &option_name, &source) {
+            Ok(choices) => {
+                if let Some(option) = command_registry
+                    .lock()
+                    .unwrap()
+                    .get_mut(&command_name)
+                    .and_then(|command| command.options.get_mut(index))
+                {
+                    option.choices = choices;
+                }
+            }
+            Err(err) => eprintln!(
+                "Failed to refresh the choices of '{command_name}' option '{option_name}': {err}"
+            ),
+        }
+    }
+}
@@ -1,28 +1,54 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use serenity::{
     all::{CommandInteraction, Http, Message, MessageId, UserId},
     futures::StreamExt as _,
 };
 
-use crate::{config, lua::extensions::Attachment, outputter::OutputterHandle};
+use crate::{
+    cancel::{self, Cancellation, CancellationRegistry},
+    config,
+    lua::extensions::{Attachment, AttachmentKind, Invoker, OutputUpdate},
+    outputter::OutputterHandle,
+    usage::{Usage, UsageTracker},
+};
+
+/// How long a cancelled thread is given to run its `on_cancel` hooks and unwind
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How much of the end of a model's reasoning is shown while it thinks
+const THINKING_PREVIEW_CHARS: usize = 1000;
 
 /// Channels for receiving output from Lua execution
 pub struct LuaOutputChannels {
-    pub output_rx: flume::Receiver<String>,
+    pub output_rx: flume::Receiver<OutputUpdate>,
     pub print_rx: flume::Receiver<String>,
     pub attachment_rx: flume::Receiver<Attachment>,
+    /// The cancellation state the thread's Lua functions observe
+    pub cancellation: Cancellation,
+    /// Who the execution's wall time is charged to
+    pub accounting: Accounting,
 }
 
-/// Executes a Lua async thread with output handling and optional cancellation support.
-/// Returns the message ID of the bot's response.
+/// Where an execution's usage is recorded, and who it's charged to
+pub struct Accounting {
+    pub tracker: Arc<UsageTracker>,
+    pub invoker: Invoker,
+}
+
+/// Executes a Lua async thread with output handling, cancellation support, and an optional
+/// timeout. Returns the message ID of the bot's response.
 pub async fn execute_lua_thread(
     http: Arc<Http>,
     cmd: &CommandInteraction,
     discord_config: &config::Discord,
     thread: mlua::AsyncThread<Option<String>>,
     channels: LuaOutputChannels,
-    cancel_rx: Option<flume::Receiver<MessageId>>,
+    cancellations: &CancellationRegistry,
+    timeout: Option<Duration>,
 ) -> anyhow::Result<MessageId> {
     let outputter = OutputterHandle::new(
         http,
@@ -32,7 +58,7 @@ pub async fn execute_lua_thread(
     )
     .await?;
 
-    execute_lua_thread_impl(outputter, thread, channels, cancel_rx).await
+    execute_lua_thread_impl(outputter, thread, channels, cancellations, timeout).await
 }
 
 /// Executes a Lua async thread in response to a message reply
@@ -43,7 +69,7 @@ pub async fn execute_lua_reply_thread(
     discord_config: &config::Discord,
     thread: mlua::AsyncThread<Option<String>>,
     channels: LuaOutputChannels,
-    cancel_rx: Option<flume::Receiver<MessageId>>,
+    cancellations: &CancellationRegistry,
 ) -> anyhow::Result<MessageId> {
     let outputter = OutputterHandle::new_reply(
         http,
@@ -54,7 +80,7 @@ pub async fn execute_lua_reply_thread(
     )
     .await?;
 
-    execute_lua_thread_impl(outputter, thread, channels, cancel_rx).await
+    execute_lua_thread_impl(outputter, thread, channels, cancellations, None).await
 }
 
 /// Common implementation for executing Lua threads with output handling
@@ -62,15 +88,20 @@ async fn execute_lua_thread_impl(
     outputter: OutputterHandle,
     mut thread: mlua::AsyncThread<Option<String>>,
     channels: LuaOutputChannels,
-    mut cancel_rx: Option<flume::Receiver<MessageId>>,
+    cancellations: &CancellationRegistry,
+    timeout: Option<Duration>,
 ) -> anyhow::Result<MessageId> {
     struct Output {
         output: String,
+        thinking: Option<String>,
         print_log: Vec<String>,
     }
     impl Output {
         pub fn to_final_output(&self) -> String {
-            let mut output = self.output.clone();
+            let mut output = match &self.thinking {
+                Some(thinking) => format!("{}\n\n{}", format_thinking(thinking), self.output),
+                None => self.output.clone(),
+            };
             if !self.print_log.is_empty() {
                 output.push_str("\n**Print Log**\n");
                 for print in self.print_log.iter() {
@@ -83,34 +114,68 @@ async fn execute_lua_thread_impl(
     }
     let mut output = Output {
         output: String::new(),
+        thinking: None,
         print_log: vec![],
     };
 
+    let started = Instant::now();
     let mut errored = false;
+    let mut cancelled = false;
     let mut thread_result: Option<String> = None;
     let mut output_stream = channels.output_rx.stream();
     let mut print_stream = channels.print_rx.stream();
     let mut attachment_stream = channels.attachment_rx.stream();
 
     let starting_message_id = outputter.starting_message_id();
+    let _registration = cancel::register(
+        cancellations,
+        starting_message_id,
+        channels.cancellation.clone(),
+    );
+
+    // Resolves with the timeout once it has elapsed - pending forever if None
+    let timed_out = async move {
+        match timeout {
+            Some(timeout) => {
+                tokio::time::sleep(timeout).await;
+                timeout
+            }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(timed_out);
 
     loop {
         tokio::select! {
             biased;
 
-            // Check for cancellation (highest priority) - pending forever if None
-            Some(cancel_message_id) = next_if_some(&mut cancel_rx) => {
-                if cancel_message_id == starting_message_id {
-                    outputter.cancelled();
-                    errored = true;
-                    break;
-                }
+            // Check for cancellation (highest priority)
+            () = channels.cancellation.cancelled() => {
+                outputter.cancelled();
+                errored = true;
+                cancelled = true;
+                break;
+            }
+
+            // Stop the thread if it has run for too long
+            timeout = &mut timed_out => {
+                outputter.error(&format!("Execution timed out after {timeout:?}"));
+                errored = true;
                 break;
             }
 
-            // Handle values from output stream
-            Some(value) = output_stream.next() => {
-                output.output = value;
+            // Handle values from output stream. The reasoning is only shown until the answer
+            // starts to arrive.
+            Some(update) = output_stream.next() => {
+                match update {
+                    OutputUpdate::Content(value) => {
+                        output.output = value;
+                        output.thinking = None;
+                    }
+                    OutputUpdate::Thinking(thinking) => {
+                        output.thinking = (!thinking.trim().is_empty()).then_some(thinking);
+                    }
+                }
                 outputter.update(&output.to_final_output());
             }
 
@@ -122,10 +187,10 @@ async fn execute_lua_thread_impl(
 
             // Handle attachments
             Some(attachment) = attachment_stream.next() => {
-                if attachment.is_preview {
-                    outputter.set_preview(attachment);
-                } else {
-                    outputter.add_attachment(attachment);
+                match attachment.kind {
+                    AttachmentKind::File => outputter.add_attachment(attachment),
+                    AttachmentKind::Preview => outputter.set_preview(attachment),
+                    AttachmentKind::VoiceMessage => outputter.add_voice_message(attachment),
                 }
             }
 
@@ -140,7 +205,7 @@ async fn execute_lua_thread_impl(
                         outputter.update(&output.to_final_output());
                     }
                     Some(Err(err)) => {
-                        outputter.error(&err.to_string());
+                        outputter.error(&describe_error(&err));
                         errored = true;
                         break;
                     }
@@ -153,6 +218,10 @@ async fn execute_lua_thread_impl(
         }
     }
 
+    if cancelled {
+        tear_down(&mut thread, &channels.cancellation).await;
+    }
+
     if !errored {
         // If no explicit output was set but the thread returned a value, use that as output
         if output.output.is_empty()
@@ -164,16 +233,95 @@ async fn execute_lua_thread_impl(
         outputter.finish();
     }
 
+    let Accounting { tracker, invoker } = &channels.accounting;
+    tracker.record(
+        invoker.user_id,
+        invoker.guild_id,
+        &invoker.command_name,
+        Usage {
+            invocations: 1,
+            wall_time_ms: started.elapsed().as_millis() as u64,
+            ..Default::default()
+        },
+    );
+
     outputter.join().await?;
 
     Ok(starting_message_id)
 }
 
-/// Helper to get next item from an optional receiver by creating a temporary stream.
-/// If the receiver is None, this future will never resolve (pending forever).
-async fn next_if_some<T>(rx: &mut Option<flume::Receiver<T>>) -> Option<T> {
-    match rx.as_ref() {
-        Some(receiver) => receiver.stream().next().await,
-        None => std::future::pending().await,
+/// Formats a model's reasoning as a spoilered section, keeping only its end so that it doesn't
+/// crowd out the output
+fn format_thinking(thinking: &str) -> String {
+    let thinking = thinking.trim();
+    let start = thinking
+        .char_indices()
+        .rev()
+        .nth(THINKING_PREVIEW_CHARS - 1)
+        .map_or(0, |(i, _)| i);
+    let ellipsis = if start > 0 { "…" } else { "" };
+    // A `||` in the reasoning would end the spoiler early
+    let thinking = thinking[start..].replace("||", "|\u{200b}|");
+    format!("-# Thinking...\n||{ellipsis}{thinking}||")
+}
+
+/// Describes an error from a Lua thread. Luau reports exceeding the memory limit as a bare
+/// "not enough memory", so call out that case explicitly.
+fn describe_error(err: &mlua::Error) -> String {
+    let is_memory_error = match err {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } => matches!(**cause, mlua::Error::MemoryError(_)),
+        _ => false,
+    };
+    if is_memory_error {
+        format!("Execution exceeded its memory limit ({err})")
+    } else {
+        err.to_string()
+    }
+}
+
+/// Runs the `on_cancel` hooks of a cancelled thread, then keeps driving it so that the Rust
+/// futures it is waiting on observe the cancellation and unwind (dropping any in-flight
+/// requests) instead of being left suspended.
+async fn tear_down(thread: &mut mlua::AsyncThread<Option<String>>, cancellation: &Cancellation) {
+    let teardown = async {
+        for hook in cancellation.take_hooks() {
+            if let Err(err) = hook.call_async::<()>(()).await {
+                eprintln!("Error in cancellation hook: {err}");
+            }
+        }
+        while let Some(Ok(_)) = thread.next().await {}
+    };
+    if tokio::time::timeout(CANCEL_GRACE_PERIOD, teardown)
+        .await
+        .is_err()
+    {
+        eprintln!("Cancelled thread did not finish within {CANCEL_GRACE_PERIOD:?}; dropping it");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_thinking() {
+        assert_eq!(
+            format_thinking("  hmm, let me see  "),
+            "-# Thinking...\n||hmm, let me see||"
+        );
+        assert_eq!(
+            format_thinking("a || b"),
+            "-# Thinking...\n||a |\u{200b}| b||"
+        );
+
+        let long = format!("{}end", "é".repeat(THINKING_PREVIEW_CHARS));
+        let formatted = format_thinking(&long);
+        assert!(formatted.starts_with("-# Thinking...\n||…é"));
+        assert!(formatted.ends_with("end||"));
+        assert_eq!(
+            formatted.chars().count(),
+            "-# Thinking...\n||…||".chars().count() + THINKING_PREVIEW_CHARS
+        );
     }
 }
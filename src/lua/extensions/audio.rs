@@ -0,0 +1,162 @@
+use std::{sync::Arc, time::Instant};
+
+use async_openai::types::{
+    InputSource,
+    audio::{
+        AudioInput, CreateSpeechRequestArgs, CreateTranscriptionRequestArgs, SpeechModel,
+        SpeechResponseFormat, Voice,
+    },
+};
+use serde::Serialize;
+
+use super::{
+    get_enum,
+    globals::{current_cancellation, until_cancelled},
+    to_lua_value,
+    usage::{charge, check_budget},
+};
+use crate::{
+    ai::{Ai, Transcription},
+    usage::{Resource, Usage, UsageTracker},
+};
+
+/// The speech endpoint doesn't report usage, so its input is charged at roughly this many
+/// characters per token
+const SPEECH_CHARS_PER_TOKEN: u64 = 4;
+
+/// Register speech-to-text and text-to-speech through OpenAI-compatible servers with Lua
+pub fn register(lua: &mlua::Lua, ai: Arc<Ai>, tracker: Arc<UsageTracker>) -> mlua::Result<()> {
+    let audio = lua.create_table()?;
+
+    // Transcribes audio data, returning the text and a result
+    audio.set(
+        "transcribe",
+        lua.create_async_function({
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, (data, opts): (mlua::String, mlua::Table)| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
+                async move {
+                    until_cancelled(cancellation?, async move {
+                        let model: String = opts.get("model")?;
+                        let filename = opts
+                            .get::<Option<String>>("filename")?
+                            .unwrap_or_else(|| "audio.ogg".to_string());
+                        check_budget(&lua, &tracker, Resource::Tokens)?;
+
+                        let mut request = CreateTranscriptionRequestArgs::default();
+                        request.model(model).file(AudioInput {
+                            source: InputSource::VecU8 {
+                                filename,
+                                vec: data.as_bytes().to_vec(),
+                            },
+                        });
+                        if let Some(language) = opts.get::<Option<String>>("language")? {
+                            request.language(language);
+                        }
+                        if let Some(prompt) = opts.get::<Option<String>>("prompt")? {
+                            request.prompt(prompt);
+                        }
+                        if let Some(temperature) = opts.get::<Option<f32>>("temperature")? {
+                            request.temperature(temperature);
+                        }
+                        let request = request.build().map_err(mlua::Error::external)?;
+
+                        let started = Instant::now();
+                        let transcription = ai
+                            .transcribe(request)
+                            .await
+                            .map_err(mlua::Error::external)?;
+                        let usage = transcription.usage.unwrap_or_default();
+                        charge(
+                            &lua,
+                            &tracker,
+                            Usage {
+                                prompt_tokens: usage.input_tokens,
+                                completion_tokens: usage.output_tokens,
+                                ..Default::default()
+                            },
+                        )?;
+
+                        let text = transcription.text.clone();
+                        let result = TranscriptionResult {
+                            transcription,
+                            latency_ms: started.elapsed().as_millis() as u64,
+                        };
+                        Ok((text, to_lua_value(&lua, &result)?))
+                    })
+                    .await
+                }
+            }
+        })?,
+    )?;
+
+    // Speaks text aloud, returning the audio data. It's Ogg Opus unless another format is
+    // asked for, so that it can be sent with `attach_voice`.
+    audio.set(
+        "speak",
+        lua.create_async_function(move |lua, args: mlua::Table| {
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            let cancellation = current_cancellation(&lua);
+            async move {
+                until_cancelled(cancellation?, async move {
+                    let model: String = args.get("model")?;
+                    let text: String = args.get("text")?;
+                    let prompt_tokens =
+                        (text.chars().count() as u64).div_ceil(SPEECH_CHARS_PER_TOKEN);
+                    check_budget(&lua, &tracker, Resource::Tokens)?;
+                    let voice: Voice = get_enum(&lua, &args, "voice")?.unwrap_or_default();
+                    let format =
+                        get_enum(&lua, &args, "format")?.unwrap_or(SpeechResponseFormat::Opus);
+
+                    let mut request = CreateSpeechRequestArgs::default();
+                    request
+                        .model(SpeechModel::Other(model.clone()))
+                        .voice(voice)
+                        .input(text)
+                        .response_format(format);
+                    if let Some(instructions) = args.get::<Option<String>>("instructions")? {
+                        request.instructions(instructions);
+                    }
+                    if let Some(speed) = args.get::<Option<f32>>("speed")? {
+                        request.speed(speed);
+                    }
+                    let request = request.build().map_err(mlua::Error::external)?;
+
+                    let response = ai
+                        .send(&model, move |client| {
+                            let request = request.clone();
+                            async move { client.audio().speech().create(request).await }
+                        })
+                        .await
+                        .map_err(mlua::Error::external)?;
+                    charge(
+                        &lua,
+                        &tracker,
+                        Usage {
+                            prompt_tokens,
+                            ..Default::default()
+                        },
+                    )?;
+                    lua.create_string(&response.bytes)
+                })
+                .await
+            }
+        })?,
+    )?;
+
+    lua.globals().set("audio", audio)?;
+
+    Ok(())
+}
+
+/// Metadata about a transcription, returned to Lua alongside the text
+#[derive(Serialize)]
+struct TranscriptionResult {
+    #[serde(flatten)]
+    transcription: Transcription,
+    latency_ms: u64,
+}
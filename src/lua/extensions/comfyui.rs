@@ -1,8 +1,61 @@
+use std::sync::Arc;
+
 use mlua::{Lua, Result};
 
-pub fn register(lua: &Lua) -> Result<()> {
+use super::usage::{charge, check_budget};
+use crate::usage::{Resource, Usage, UsageTracker};
+
+/// Wraps `comfy.client` so that each workflow a client executes is checked against the
+/// image generation budget and recorded. It's done in Lua as the clients' methods are async.
+const ACCOUNTING_WRAPPER: &str = r#"
+local comfy, before_execute, after_execute = ...
+local create_client = comfy.client
+comfy.client = function(...)
+	local client = create_client(...)
+	return setmetatable({}, {
+		__index = function(_, key)
+			local value = client[key]
+			if type(value) ~= "function" then
+				return value
+			end
+			if key == "execute" then
+				return function(_, ...)
+					before_execute()
+					local result = client:execute(...)
+					after_execute()
+					return result
+				end
+			end
+			return function(_, ...)
+				return value(client, ...)
+			end
+		end,
+	})
+end
+"#;
+
+pub fn register(lua: &Lua, tracker: Arc<UsageTracker>) -> Result<()> {
     let config = rucomfyui_mlua::IntegrationConfig::all();
     let comfy = rucomfyui_mlua::module(lua, &config)?;
+
+    let before_execute = lua.create_function({
+        let tracker = tracker.clone();
+        move |lua, ()| check_budget(lua, &tracker, Resource::ImageGenerations)
+    })?;
+    let after_execute = lua.create_function(move |lua, ()| {
+        charge(
+            lua,
+            &tracker,
+            Usage {
+                image_generations: 1,
+                ..Default::default()
+            },
+        )
+    })?;
+    lua.load(ACCOUNTING_WRAPPER)
+        .set_name("comfyui_accounting")
+        .call::<()>((comfy.clone(), before_execute, after_execute))?;
+
     lua.globals().set("comfy", comfy)?;
     Ok(())
 }
@@ -1,5 +1,9 @@
 use std::{collections::HashMap, sync::Arc};
 
+use serenity::all::{ChannelId, CommandInteraction, GuildId, Message, UserId};
+
+use crate::cancel::Cancellation;
+
 const OUTPUT_CHANNELS_MAP_KEY: &str = "_output_channels_map";
 const DEFAULT_CHANNELS_KEY: usize = 0;
 
@@ -8,23 +12,78 @@ const DEFAULT_CHANNELS_KEY: usize = 0;
 pub struct Attachment {
     pub filename: String,
     pub data: Vec<u8>,
-    /// If true, this is a live preview that replaces the message's current
-    /// preview image (e.g. an in-progress render) rather than a final
-    /// attachment appended when the command finishes.
-    pub is_preview: bool,
+    pub kind: AttachmentKind,
+}
+
+/// How an attachment is shown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachmentKind {
+    /// Appended to the output when the command finishes
+    File,
+    /// A live preview that replaces the message's current preview image
+    /// (e.g. an in-progress render)
+    Preview,
+    /// Ogg Opus audio sent as its own voice message when the command finishes
+    VoiceMessage,
+}
+
+/// An update to what an execution is showing
+#[derive(Clone, Debug)]
+pub enum OutputUpdate {
+    /// Replaces the output
+    Content(String),
+    /// Replaces the reasoning shown alongside the output, which is cleared the next time the
+    /// output changes
+    Thinking(String),
+}
+
+/// Who started an execution, and where
+#[derive(Clone, Debug)]
+pub struct Invoker {
+    pub user_id: UserId,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: ChannelId,
+    pub command_name: String,
+}
+impl Invoker {
+    pub fn from_command(cmd: &CommandInteraction) -> Self {
+        Self {
+            user_id: cmd.user.id,
+            guild_id: cmd.guild_id,
+            channel_id: cmd.channel_id,
+            command_name: cmd.data.name.clone(),
+        }
+    }
+
+    pub fn from_message(msg: &Message, command_name: String) -> Self {
+        Self {
+            user_id: msg.author.id,
+            guild_id: msg.guild_id,
+            channel_id: msg.channel_id,
+            command_name,
+        }
+    }
 }
 
 pub fn register(
     lua: &mlua::Lua,
-    output_tx: flume::Sender<String>,
+    output_tx: flume::Sender<OutputUpdate>,
     print_tx: flume::Sender<String>,
     attachment_tx: flume::Sender<Attachment>,
+    cancellation: Option<Cancellation>,
+    invoker: Option<Invoker>,
 ) -> mlua::Result<()> {
     lua.globals().set(
         "sleep",
-        lua.create_async_function(|_lua, ms: u32| async move {
-            tokio::time::sleep(std::time::Duration::from_millis(ms as u64)).await;
-            Ok(())
+        lua.create_async_function(|lua, ms: u32| {
+            let cancellation = current_cancellation(&lua);
+            async move {
+                until_cancelled(cancellation?, async {
+                    tokio::time::sleep(std::time::Duration::from_millis(ms as u64)).await;
+                    Ok(())
+                })
+                .await
+            }
         })?,
     )?;
 
@@ -45,17 +104,28 @@ pub fn register(
     let mut channels_map = OutputChannelsMap::new();
     channels_map.insert(
         DEFAULT_CHANNELS_KEY,
-        OutputChannels::new(output_tx, print_tx, attachment_tx),
+        OutputChannels::new(output_tx, print_tx, attachment_tx, cancellation, invoker),
     );
     lua.set_named_registry_value(OUTPUT_CHANNELS_MAP_KEY, channels_map)?;
     lua.globals().set(
         "output",
         lua.create_function(move |lua, values: mlua::Variadic<String>| {
             let output = values.into_iter().collect::<Vec<_>>().join("\t");
-            with_current_channels(lua, |channels| channels.send_output(output.clone()))?;
+            with_current_channels(lua, |channels| {
+                channels.send_output(OutputUpdate::Content(output.clone()))
+            })?;
             Ok(output)
         })?,
     )?;
+    lua.globals().set(
+        "thinking",
+        lua.create_function(move |lua, thinking: String| {
+            with_current_channels(lua, |channels| {
+                channels.send_output(OutputUpdate::Thinking(thinking.clone()))
+            })?;
+            Ok(())
+        })?,
+    )?;
     lua.globals().set(
         "print",
         lua.create_function(move |lua, values: mlua::Variadic<String>| {
@@ -72,7 +142,7 @@ pub fn register(
                 channels.send_attachment(Attachment {
                     filename: filename.clone(),
                     data,
-                    is_preview: false,
+                    kind: AttachmentKind::File,
                 })
             })?;
             Ok(())
@@ -86,51 +156,114 @@ pub fn register(
                 channels.send_attachment(Attachment {
                     filename: filename.clone(),
                     data,
-                    is_preview: true,
+                    kind: AttachmentKind::Preview,
                 })
             })?;
             Ok(())
         })?,
     )?;
+    lua.globals().set(
+        "attach_voice",
+        lua.create_function(move |lua, data: mlua::String| {
+            let data = data.as_bytes().to_vec();
+            with_current_channels(lua, |channels| {
+                channels.send_attachment(Attachment {
+                    filename: "voice-message.ogg".to_string(),
+                    data,
+                    kind: AttachmentKind::VoiceMessage,
+                })
+            })?;
+            Ok(())
+        })?,
+    )?;
+    lua.globals().set(
+        "on_cancel",
+        lua.create_function(|lua, hook: mlua::Function| {
+            current_cancellation(lua)?
+                .ok_or_else(|| {
+                    mlua::Error::runtime("on_cancel can only be used while running a command")
+                })?
+                .add_hook(hook);
+            Ok(())
+        })?,
+    )?;
+    lua.globals().set(
+        "is_cancelled",
+        lua.create_function(|lua, ()| {
+            Ok(current_cancellation(lua)?.is_some_and(|c| c.is_cancelled()))
+        })?,
+    )?;
     lua.globals().set(
         "fetch",
-        lua.create_async_function(|lua, url: String| async move {
-            const MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
+        lua.create_async_function(|lua, url: String| {
+            let cancellation = current_cancellation(&lua);
+            async move { until_cancelled(cancellation?, fetch(&lua, url)).await }
+        })?,
+    )?;
 
-            let client = reqwest::Client::new();
-            let response = client
-                .get(&url)
-                .send()
-                .await
-                .map_err(mlua::Error::external)?;
-
-            // Check Content-Length header before downloading
-            if let Some(content_length) = response.content_length()
-                && content_length > MAX_SIZE
-            {
-                return Err(mlua::Error::external(format!(
-                    "File too large: {} bytes (max {} bytes)",
-                    content_length, MAX_SIZE
-                )));
-            }
+    Ok(())
+}
 
-            let bytes = response.bytes().await.map_err(mlua::Error::external)?;
+/// Downloads the given URL, returning its body as a binary-safe Lua string
+pub(super) async fn fetch(lua: &mlua::Lua, url: String) -> mlua::Result<mlua::String> {
+    const MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
 
-            // Check actual size after download (in case Content-Length wasn't present)
-            if bytes.len() as u64 > MAX_SIZE {
-                return Err(mlua::Error::external(format!(
-                    "File too large: {} bytes (max {} bytes)",
-                    bytes.len(),
-                    MAX_SIZE
-                )));
-            }
+    let client = reqwest::Client::new();
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(mlua::Error::external)?;
 
-            // Return as Lua string (binary safe)
-            lua.create_string(&bytes)
-        })?,
-    )?;
+    // Check Content-Length header before downloading
+    if let Some(content_length) = response.content_length()
+        && content_length > MAX_SIZE
+    {
+        return Err(mlua::Error::external(format!(
+            "File too large: {} bytes (max {} bytes)",
+            content_length, MAX_SIZE
+        )));
+    }
 
-    Ok(())
+    let bytes = response.bytes().await.map_err(mlua::Error::external)?;
+
+    // Check actual size after download (in case Content-Length wasn't present)
+    if bytes.len() as u64 > MAX_SIZE {
+        return Err(mlua::Error::external(format!(
+            "File too large: {} bytes (max {} bytes)",
+            bytes.len(),
+            MAX_SIZE
+        )));
+    }
+
+    // Return as Lua string (binary safe)
+    lua.create_string(&bytes)
+}
+
+/// Returns the cancellation state of the execution the current thread belongs to, if it
+/// belongs to one
+pub fn current_cancellation(lua: &mlua::Lua) -> mlua::Result<Option<Cancellation>> {
+    Ok(with_current_channels(lua, |channels| Ok(channels.cancellation.clone()))?.flatten())
+}
+
+/// Returns who started the execution the current thread belongs to, if anyone did
+pub fn current_invoker(lua: &mlua::Lua) -> mlua::Result<Option<Invoker>> {
+    Ok(with_current_channels(lua, |channels| Ok(channels.invoker.clone()))?.flatten())
+}
+
+/// Runs `future` unless the execution is cancelled first, in which case the future is dropped
+/// (aborting any in-flight request) and an error is raised in its place
+pub async fn until_cancelled<T>(
+    cancellation: Option<Cancellation>,
+    future: impl Future<Output = mlua::Result<T>>,
+) -> mlua::Result<T> {
+    let Some(cancellation) = cancellation else {
+        return future.await;
+    };
+    tokio::select! {
+        result = future => result,
+        () = cancellation.cancelled() => Err(mlua::Error::runtime("The execution was cancelled.")),
+    }
 }
 
 pub struct TemporaryChannelUpdate {
@@ -152,9 +285,11 @@ impl TemporaryChannelUpdate {
     pub fn new(
         lua: mlua::Lua,
         thread: &mlua::Thread,
-        output_tx: flume::Sender<String>,
+        output_tx: flume::Sender<OutputUpdate>,
         print_tx: flume::Sender<String>,
         attachment_tx: flume::Sender<Attachment>,
+        cancellation: Cancellation,
+        invoker: Option<Invoker>,
     ) -> mlua::Result<Self> {
         let thread_key = thread.to_pointer() as usize;
         let channels_map_ud: mlua::AnyUserData =
@@ -162,10 +297,36 @@ impl TemporaryChannelUpdate {
         let mut channels_map = channels_map_ud.borrow_mut::<OutputChannelsMap>()?;
         channels_map.insert(
             thread_key,
-            OutputChannels::new(output_tx, print_tx, attachment_tx),
+            OutputChannels::new(
+                output_tx,
+                print_tx,
+                attachment_tx,
+                Some(cancellation),
+                invoker,
+            ),
         );
         Ok(Self { lua, thread_key })
     }
+
+    /// Makes `thread` use the same channels, cancellation and invoker as `parent`, for threads
+    /// spawned to run Lua callbacks on an execution's behalf
+    pub fn inherit(
+        lua: mlua::Lua,
+        parent: &mlua::Thread,
+        thread: &mlua::Thread,
+    ) -> mlua::Result<Self> {
+        let thread_key = thread.to_pointer() as usize;
+        let channels_map_ud: mlua::AnyUserData =
+            lua.named_registry_value(OUTPUT_CHANNELS_MAP_KEY)?;
+        let mut channels_map = channels_map_ud.borrow_mut::<OutputChannelsMap>()?;
+        if let Some(channels) = channels_map
+            .get_for_thread(parent.to_pointer() as usize)
+            .cloned()
+        {
+            channels_map.insert(thread_key, channels);
+        }
+        Ok(Self { lua, thread_key })
+    }
 }
 
 /// Map from thread pointer to output channels
@@ -211,27 +372,34 @@ fn with_current_channels<T>(
     }
 }
 
-/// Userdata containing output and print channels
+/// Userdata containing output and print channels, and the execution's cancellation state and invoker
 #[derive(Clone)]
 struct OutputChannels {
-    pub output_tx: Option<flume::Sender<String>>,
+    pub output_tx: Option<flume::Sender<OutputUpdate>>,
     pub print_tx: Option<flume::Sender<String>>,
     pub attachment_tx: Option<flume::Sender<Attachment>>,
+    /// Absent for the global state's default channels, which no execution owns
+    pub cancellation: Option<Cancellation>,
+    pub invoker: Option<Invoker>,
 }
 impl OutputChannels {
     pub fn new(
-        output_tx: flume::Sender<String>,
+        output_tx: flume::Sender<OutputUpdate>,
         print_tx: flume::Sender<String>,
         attachment_tx: flume::Sender<Attachment>,
+        cancellation: Option<Cancellation>,
+        invoker: Option<Invoker>,
     ) -> Self {
         Self {
             output_tx: Some(output_tx),
             print_tx: Some(print_tx),
             attachment_tx: Some(attachment_tx),
+            cancellation,
+            invoker,
         }
     }
 
-    pub fn send_output(&self, msg: String) -> mlua::Result<()> {
+    pub fn send_output(&self, msg: OutputUpdate) -> mlua::Result<()> {
         if let Some(tx) = self.output_tx.as_ref() {
             tx.send(msg)
                 .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?;
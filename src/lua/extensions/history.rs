@@ -0,0 +1,87 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    history::{HistoryFilter, HistoryIndexer},
+    usage::{Resource, Usage, UsageTracker},
+};
+
+use super::{
+    globals::{current_cancellation, current_invoker, until_cancelled},
+    usage::{charge, check_budget},
+};
+
+/// How many chunks `history.search` returns if not told otherwise
+const DEFAULT_SEARCH_RESULTS: usize = 5;
+
+/// Register the channel history search with Lua
+pub fn register(
+    lua: &mlua::Lua,
+    indexer: Arc<HistoryIndexer>,
+    tracker: Arc<UsageTracker>,
+) -> mlua::Result<()> {
+    let module = lua.create_table()?;
+
+    module.set("enabled", indexer.is_enabled())?;
+
+    // Returns the chunks of indexed history most relevant to `query`, as
+    // `{ text, link, channel_id, message_id, timestamp, date, score }`. While running a command,
+    // only the history of the server it's run in (or the DM it's run in) is searched, and within
+    // a server only the channels the user running it can read.
+    module.set(
+        "search",
+        lua.create_async_function(move |lua, args: mlua::Table| {
+            let indexer = indexer.clone();
+            let tracker = tracker.clone();
+            let cancellation = current_cancellation(&lua);
+            async move {
+                until_cancelled(cancellation?, async move {
+                    let query = args.get::<String>("query")?;
+                    let k = args
+                        .get::<Option<usize>>("k")?
+                        .unwrap_or(DEFAULT_SEARCH_RESULTS);
+                    let filter = match current_invoker(&lua)? {
+                        Some(invoker) => match invoker.guild_id {
+                            Some(guild_id) => HistoryFilter {
+                                guild_id: Some(guild_id),
+                                channel_ids: Some(
+                                    indexer
+                                        .channels_visible_to(
+                                            guild_id,
+                                            invoker.user_id,
+                                            invoker.channel_id,
+                                        )
+                                        .await,
+                                ),
+                            },
+                            None => HistoryFilter {
+                                guild_id: None,
+                                channel_ids: Some(HashSet::from([invoker.channel_id])),
+                            },
+                        },
+                        None => HistoryFilter::default(),
+                    };
+                    check_budget(&lua, &tracker, Resource::Tokens)?;
+
+                    let (results, tokens) = indexer
+                        .search(&query, k, filter)
+                        .await
+                        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                    charge(
+                        &lua,
+                        &tracker,
+                        Usage {
+                            prompt_tokens: tokens,
+                            ..Default::default()
+                        },
+                    )?;
+                    super::to_lua_value(&lua, &results)
+                })
+                .await
+            }
+        })?,
+    )?;
+
+    lua.globals().set("history", module)?;
+
+    Ok(())
+}
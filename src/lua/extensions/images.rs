@@ -0,0 +1,418 @@
+use std::{sync::Arc, time::Instant};
+
+use async_openai::{
+    error::OpenAIError,
+    types::{
+        InputSource,
+        images::{
+            CreateImageEditRequestArgs, CreateImageRequestArgs, Image, ImageEditInput,
+            ImageEditStreamEvent, ImageGenStreamEvent, ImageGenUsage, ImageInput, ImageModel,
+            ImageOutputFormat, ImageQuality, ImageResponseFormat, ImageSize, ImagesResponse,
+        },
+    },
+};
+use serde::Serialize;
+use serenity::futures::StreamExt as _;
+
+use super::{
+    get_enum,
+    globals::{current_cancellation, fetch, until_cancelled},
+    to_lua_value,
+    usage::{charge, check_budget},
+};
+use crate::{
+    ai::{Ai, Client, EventStream},
+    usage::{Resource, Usage, UsageTracker},
+};
+
+/// Register image generation and editing through OpenAI-compatible servers with Lua
+pub fn register(lua: &mlua::Lua, ai: Arc<Ai>, tracker: Arc<UsageTracker>) -> mlua::Result<()> {
+    let images = lua.create_table()?;
+
+    // Generates images from a prompt, returning a list of the images' data and a result
+    images.set(
+        "generate",
+        lua.create_async_function({
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
+                async move {
+                    until_cancelled(cancellation?, async move {
+                        let args = ImageArgs::parse(&lua, &args)?;
+                        check_budget(&lua, &tracker, Resource::ImageGenerations)?;
+
+                        let mut request = CreateImageRequestArgs::default();
+                        request.apply(&args);
+                        let request = request.build().map_err(mlua::Error::external)?;
+
+                        let started = Instant::now();
+                        let output = request_images(
+                            &lua,
+                            &ai,
+                            &args,
+                            |client| {
+                                let request = request.clone();
+                                async move { client.images().generate_stream(request).await }
+                            },
+                            |client| {
+                                let request = request.clone();
+                                async move { client.images().generate(request).await }
+                            },
+                        )
+                        .await?;
+                        output.finish(&lua, &tracker, started)
+                    })
+                    .await
+                }
+            }
+        })?,
+    )?;
+
+    // Edits one or more images according to a prompt, returning a list of the images' data
+    // and a result
+    images.set(
+        "edit",
+        lua.create_async_function({
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
+                async move {
+                    until_cancelled(cancellation?, async move {
+                        let image_args = ImageArgs::parse(&lua, &args)?;
+                        let images = match args.get::<mlua::Value>("image")? {
+                            mlua::Value::String(image) => {
+                                ImageEditInput::Image(image_input("image.png", &image))
+                            }
+                            mlua::Value::Table(images) => ImageEditInput::Images(
+                                images
+                                    .sequence_values::<mlua::String>()
+                                    .enumerate()
+                                    .map(|(i, image)| {
+                                        Ok(image_input(&format!("image{i}.png"), &image?))
+                                    })
+                                    .collect::<mlua::Result<_>>()?,
+                            ),
+                            _ => {
+                                return Err(mlua::Error::runtime(
+                                    "images.edit requires `image`, as image data or a list of it",
+                                ));
+                            }
+                        };
+                        check_budget(&lua, &tracker, Resource::ImageGenerations)?;
+
+                        let mut request = CreateImageEditRequestArgs::default();
+                        request.apply(&image_args).image(images);
+                        if let Some(mask) = args.get::<Option<mlua::String>>("mask")? {
+                            request.mask(image_input("mask.png", &mask));
+                        }
+                        let request = request.build().map_err(mlua::Error::external)?;
+
+                        let started = Instant::now();
+                        let output = request_images(
+                            &lua,
+                            &ai,
+                            &image_args,
+                            |client| {
+                                let request = request.clone();
+                                async move { client.images().edit_stream(request).await }
+                            },
+                            |client| {
+                                let request = request.clone();
+                                async move { client.images().edit(request).await }
+                            },
+                        )
+                        .await?;
+                        output.finish(&lua, &tracker, started)
+                    })
+                    .await
+                }
+            }
+        })?,
+    )?;
+
+    lua.globals().set("images", images)?;
+
+    Ok(())
+}
+
+/// The arguments shared by `images.generate` and `images.edit`
+struct ImageArgs {
+    model: String,
+    prompt: String,
+    n: Option<u8>,
+    size: Option<ImageSize>,
+    quality: Option<ImageQuality>,
+    output_format: Option<ImageOutputFormat>,
+    /// How many partial images to stream into `preview` before the final image (1-3)
+    partial_images: Option<u8>,
+}
+impl ImageArgs {
+    fn parse(lua: &mlua::Lua, args: &mlua::Table) -> mlua::Result<Self> {
+        let n = args.get::<Option<u8>>("n")?;
+        if let Some(n) = n
+            && !(1..=10).contains(&n)
+        {
+            return Err(mlua::Error::runtime(
+                "invalid `n`: must be between 1 and 10",
+            ));
+        }
+        let partial_images = args.get::<Option<u8>>("partial_images")?.filter(|&p| p > 0);
+        if let Some(partial_images) = partial_images {
+            if partial_images > 3 {
+                return Err(mlua::Error::runtime(
+                    "invalid `partial_images`: must be between 0 and 3",
+                ));
+            }
+            if n.is_some_and(|n| n > 1) {
+                return Err(mlua::Error::runtime(
+                    "invalid `partial_images`: only one image can be streamed at a time",
+                ));
+            }
+        }
+
+        Ok(Self {
+            model: args.get("model")?,
+            prompt: args.get("prompt")?,
+            n,
+            size: get_enum(lua, args, "size")?,
+            quality: get_enum(lua, args, "quality")?,
+            output_format: get_enum(lua, args, "output_format")?,
+            partial_images,
+        })
+    }
+}
+
+/// Applies [`ImageArgs`] to a request builder. The generation and edit builders have the same
+/// methods, but no trait in common.
+trait ApplyImageArgs {
+    fn apply(&mut self, args: &ImageArgs) -> &mut Self;
+}
+macro_rules! implement_apply_image_args {
+    ($builder:ident) => {
+        impl ApplyImageArgs for $builder {
+            fn apply(&mut self, args: &ImageArgs) -> &mut Self {
+                self.model(ImageModel::Other(args.model.clone()))
+                    .prompt(args.prompt.clone());
+                if let Some(n) = args.n {
+                    self.n(n);
+                }
+                if let Some(size) = args.size {
+                    self.size(size);
+                }
+                if let Some(quality) = args.quality {
+                    self.quality(quality);
+                }
+                if let Some(output_format) = args.output_format {
+                    self.output_format(output_format);
+                }
+                if let Some(partial_images) = args.partial_images {
+                    self.stream(true).partial_images(partial_images);
+                } else {
+                    self.response_format(ImageResponseFormat::B64Json);
+                }
+                self
+            }
+        }
+    };
+}
+implement_apply_image_args!(CreateImageRequestArgs);
+implement_apply_image_args!(CreateImageEditRequestArgs);
+
+/// Sends a request with `send`, or with `stream` if partial images were asked for, in which
+/// case they're shown in the live preview as they arrive
+async fn request_images<E, S, R>(
+    lua: &mlua::Lua,
+    ai: &Ai,
+    args: &ImageArgs,
+    stream: impl Fn(Client) -> S,
+    send: impl Fn(Client) -> R,
+) -> mlua::Result<ImageOutput>
+where
+    E: Into<ImageStreamEvent> + Send + 'static,
+    S: Future<Output = Result<EventStream<E>, OpenAIError>>,
+    R: Future<Output = Result<ImagesResponse, OpenAIError>>,
+{
+    if args.partial_images.is_none() {
+        let response = ai
+            .send(&args.model, send)
+            .await
+            .map_err(mlua::Error::external)?;
+        return ImageOutput::from_response(lua, &response.data, response.usage).await;
+    }
+
+    let mut stream = ai
+        .stream(&args.model, stream)
+        .await
+        .map_err(mlua::Error::external)?;
+    let mut output = ImageOutput::default();
+    while let Some(event) = stream.next().await {
+        let event: ImageStreamEvent = event.map_err(mlua::Error::external)?.into();
+        match event {
+            ImageStreamEvent::PartialImage { b64_json } => show_partial_image(lua, &b64_json)?,
+            ImageStreamEvent::Completed { b64_json, usage } => {
+                output.images.push(decode(lua, &b64_json)?);
+                output.usage = Some(usage);
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// The parts of a generation or edit stream's events that are used
+enum ImageStreamEvent {
+    PartialImage {
+        b64_json: String,
+    },
+    Completed {
+        b64_json: String,
+        usage: ImageGenUsage,
+    },
+}
+impl From<ImageGenStreamEvent> for ImageStreamEvent {
+    fn from(event: ImageGenStreamEvent) -> Self {
+        match event {
+            ImageGenStreamEvent::PartialImage(partial) => Self::PartialImage {
+                b64_json: partial.b64_json,
+            },
+            ImageGenStreamEvent::Completed(completed) => Self::Completed {
+                b64_json: completed.b64_json,
+                usage: completed.usage,
+            },
+        }
+    }
+}
+impl From<ImageEditStreamEvent> for ImageStreamEvent {
+    fn from(event: ImageEditStreamEvent) -> Self {
+        match event {
+            ImageEditStreamEvent::PartialImage(partial) => Self::PartialImage {
+                b64_json: partial.b64_json,
+            },
+            ImageEditStreamEvent::Completed(completed) => Self::Completed {
+                b64_json: completed.b64_json,
+                usage: completed.usage,
+            },
+        }
+    }
+}
+
+fn image_input(filename: &str, data: &mlua::String) -> ImageInput {
+    ImageInput {
+        source: InputSource::VecU8 {
+            filename: filename.to_string(),
+            vec: data.as_bytes().to_vec(),
+        },
+    }
+}
+
+/// Sends a partial image to the execution's live preview
+fn show_partial_image(lua: &mlua::Lua, b64_json: &str) -> mlua::Result<()> {
+    let preview: mlua::Function = lua.globals().get("preview")?;
+    preview.call(("preview.png", decode(lua, b64_json)?))
+}
+
+fn decode(lua: &mlua::Lua, b64_json: &str) -> mlua::Result<mlua::String> {
+    let data = data_encoding::BASE64
+        .decode(b64_json.as_bytes())
+        .map_err(|e| mlua::Error::runtime(format!("the server sent an invalid image: {e}")))?;
+    lua.create_string(data)
+}
+
+/// The images a request produced
+#[derive(Default)]
+struct ImageOutput {
+    images: Vec<mlua::String>,
+    usage: Option<ImageGenUsage>,
+}
+impl ImageOutput {
+    /// Collects the images of a non-streamed response, downloading any the server only
+    /// returned a URL for
+    async fn from_response(
+        lua: &mlua::Lua,
+        data: &[Arc<Image>],
+        usage: Option<ImageGenUsage>,
+    ) -> mlua::Result<Self> {
+        let mut images = vec![];
+        for image in data {
+            images.push(match image.as_ref() {
+                Image::B64Json { b64_json, .. } => decode(lua, b64_json)?,
+                Image::Url { url, .. } => fetch(lua, url.clone()).await?,
+            });
+        }
+        Ok(Self { images, usage })
+    }
+
+    /// Charges for the images, and returns them along with the result to Lua
+    fn finish(
+        self,
+        lua: &mlua::Lua,
+        tracker: &UsageTracker,
+        started: Instant,
+    ) -> mlua::Result<(Vec<mlua::String>, mlua::Value)> {
+        charge(
+            lua,
+            tracker,
+            Usage {
+                image_generations: self.images.len() as u64,
+                prompt_tokens: self.usage.as_ref().map_or(0, |u| u.input_tokens as u64),
+                completion_tokens: self.usage.as_ref().map_or(0, |u| u.output_tokens as u64),
+                ..Default::default()
+            },
+        )?;
+
+        let result = ImageResult {
+            usage: self.usage,
+            latency_ms: started.elapsed().as_millis() as u64,
+        };
+        Ok((self.images, to_lua_value(lua, &result)?))
+    }
+}
+
+/// Metadata about an image request, returned to Lua alongside the images
+#[derive(Serialize)]
+struct ImageResult {
+    usage: Option<ImageGenUsage>,
+    latency_ms: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let lua = mlua::Lua::new();
+        let parse = |fields: &str| {
+            let args = lua
+                .load(format!("return {{ model = 'm', prompt = 'p', {fields} }}"))
+                .eval()
+                .unwrap();
+            ImageArgs::parse(&lua, &args)
+        };
+
+        let args = parse("n = 10").ok().unwrap();
+        assert_eq!(args.n, Some(10));
+        assert_eq!(args.partial_images, None);
+        let args = parse("n = 1, partial_images = 3").ok().unwrap();
+        assert_eq!(args.partial_images, Some(3));
+        // No partial images means no streaming
+        let args = parse("n = 4, partial_images = 0").ok().unwrap();
+        assert_eq!(args.partial_images, None);
+
+        for (fields, error) in [
+            ("n = 0", "invalid `n`"),
+            ("n = 11", "invalid `n`"),
+            ("partial_images = 4", "invalid `partial_images`"),
+            (
+                "n = 2, partial_images = 1",
+                "only one image can be streamed",
+            ),
+        ] {
+            let err = parse(fields).err().unwrap();
+            assert!(err.to_string().contains(error), "{fields}: {err}");
+        }
+    }
+}
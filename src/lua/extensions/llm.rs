@@ -1,51 +1,109 @@
-use std::sync::Arc;
-
-use async_openai::types::chat::{
-    ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
-    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
-    ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
-    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
-    CreateChatCompletionRequestArgs, ImageDetail, ImageUrl,
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_openai::{
+    types::chat::{
+        ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+        ChatCompletionMessageToolCalls, ChatCompletionRequestAssistantMessage,
+        ChatCompletionRequestAssistantMessageContent,
+        ChatCompletionRequestAssistantMessageContentPart, ChatCompletionRequestDeveloperMessage,
+        ChatCompletionRequestDeveloperMessageContent,
+        ChatCompletionRequestDeveloperMessageContentPart, ChatCompletionRequestMessage,
+        ChatCompletionRequestMessageContentPartImage,
+        ChatCompletionRequestMessageContentPartRefusal,
+        ChatCompletionRequestMessageContentPartText, ChatCompletionRequestSystemMessage,
+        ChatCompletionRequestSystemMessageContent, ChatCompletionRequestSystemMessageContentPart,
+        ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent,
+        ChatCompletionRequestToolMessageContentPart, ChatCompletionRequestUserMessage,
+        ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+        ChatCompletionStreamOptions, ChatCompletionTool, ChatCompletionTools, CompletionUsage,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+        FunctionCall, FunctionObject, ImageDetail, ImageUrl, ResponseFormat,
+        ResponseFormatJsonSchema, StopConfiguration,
+    },
+    types::embeddings::{CreateEmbeddingRequestArgs, EmbeddingInput},
 };
 use mlua::LuaSerdeExt as _;
+use serde::{Deserialize, Serialize};
 use serenity::futures::StreamExt as _;
 
-use crate::ai::Ai;
+use super::{
+    get_enum,
+    globals::{TemporaryChannelUpdate, current_cancellation, until_cancelled},
+    to_lua_value,
+    usage::{charge, check_budget},
+};
+use crate::{
+    ai::{Ai, EventStream, Model},
+    usage::{Resource, Usage, UsageTracker},
+};
+
+/// How many rounds of tool calls `llm.run_tools` allows before giving up, by default
+const DEFAULT_MAX_TOOL_ROUNDS: usize = 8;
 
-pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
+/// Registry key of the functions registered with `llm.on_models_changed`
+const MODELS_CHANGED_HOOKS_KEY: &str = "llm_models_changed_hooks";
+
+pub fn register(lua: &mlua::Lua, ai: Arc<Ai>, tracker: Arc<UsageTracker>) -> mlua::Result<()> {
     let llm = lua.create_table()?;
 
-    llm.set("models", lua.to_value(&ai.models)?)?;
+    llm.set("models", lua.to_value(&ai.models())?)?;
+
+    // Calls `hook(models, previous_models)` whenever the model list changes, after `llm.models`
+    // has been updated. Hooks can't yield.
+    lua.set_named_registry_value(MODELS_CHANGED_HOOKS_KEY, lua.create_table()?)?;
+    llm.set(
+        "on_models_changed",
+        lua.create_function(|lua, hook: mlua::Function| {
+            lua.named_registry_value::<mlua::Table>(MODELS_CHANGED_HOOKS_KEY)?
+                .push(hook)
+        })?,
+    )?;
 
     register_message(lua, &llm, "system")?;
+    register_message(lua, &llm, "developer")?;
     register_message(lua, &llm, "user")?;
     register_message(lua, &llm, "assistant")?;
+    register_message(lua, &llm, "tool")?;
 
     llm.set(
         "by_token",
         lua.create_async_function({
-            let client = ai.client.clone();
-            move |_lua, args: mlua::Table| {
-                let client = client.clone();
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
                 async move {
-                    let (model, seed, messages, callback) = parse_llm_args(&args)?;
-                    let callback = callback.expect("by_token requires a callback");
-
-                    let mut stream = create_chat_stream(&client, &model, seed, messages).await?;
+                    until_cancelled(cancellation?, async move {
+                        let args = parse_llm_args(&lua, &args)?;
+                        let callback = args
+                            .callback
+                            .clone()
+                            .ok_or_else(|| mlua::Error::runtime("by_token requires a callback"))?;
+                        check_budget(&lua, &tracker, Resource::Tokens)?;
 
-                    while let Some(response) = stream.next().await {
-                        let Ok(response) = response else { continue };
-                        let Some(content) = &response.choices[0].delta.content else {
-                            continue;
-                        };
-                        let value = callback.call::<mlua::Value>(content.clone())?;
-                        if value.as_boolean().is_some_and(|b| !b) {
-                            // Allow the user to cancel the stream by returning false
-                            break;
-                        }
-                    }
+                        let completion = stream_completion(
+                            &ai,
+                            args.request(true)?,
+                            args.timeout,
+                            |token, _, result| call_callback(&lua, &callback, token, result),
+                            |token, _| call_reasoning_callback(&args.reasoning_callback, token),
+                        )
+                        .await?;
+                        charge_tokens(&lua, &tracker, completion.result.usage.as_ref())?;
 
-                    Ok(())
+                        Ok((
+                            tool_calls_to_lua(&lua, &completion.tool_calls)?,
+                            completion.result.to_lua(&lua)?,
+                        ))
+                    })
+                    .await
                 }
             }
         })?,
@@ -54,63 +112,292 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
     llm.set(
         "stream",
         lua.create_async_function({
-            let client = ai.client.clone();
-            move |_lua, args: mlua::Table| {
-                let client = client.clone();
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
+                async move {
+                    until_cancelled(cancellation?, async move {
+                        let args = parse_llm_args(&lua, &args)?;
+                        let callback = args
+                            .callback
+                            .clone()
+                            .ok_or_else(|| mlua::Error::runtime("stream requires a callback"))?;
+                        check_budget(&lua, &tracker, Resource::Tokens)?;
+
+                        let completion = stream_completion(
+                            &ai,
+                            args.request(true)?,
+                            args.timeout,
+                            |_, output, result| call_callback(&lua, &callback, output, result),
+                            |_, reasoning| {
+                                call_reasoning_callback(&args.reasoning_callback, reasoning)
+                            },
+                        )
+                        .await?;
+                        charge_tokens(&lua, &tracker, completion.result.usage.as_ref())?;
+
+                        Ok((
+                            tool_calls_to_lua(&lua, &completion.tool_calls)?,
+                            completion.result.to_lua(&lua)?,
+                        ))
+                    })
+                    .await
+                }
+            }
+        })?,
+    )?;
+
+    llm.set(
+        "response",
+        lua.create_async_function({
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
                 async move {
-                    let (model, seed, messages, callback) = parse_llm_args(&args)?;
-                    let callback = callback.expect("stream requires a callback");
+                    until_cancelled(cancellation?, async move {
+                        let args = parse_llm_args(&lua, &args)?;
+                        check_budget(&lua, &tracker, Resource::Tokens)?;
+
+                        let started = Instant::now();
+                        let response = with_timeout(
+                            args.timeout,
+                            started,
+                            create_completion(&ai, args.request(false)?),
+                        )
+                        .await?;
+                        let result = CompletionResult::from_response(&response, started);
+                        charge_tokens(&lua, &tracker, result.usage.as_ref())?;
+
+                        let Some(choice) = response.choices.first() else {
+                            return Err(mlua::Error::runtime(
+                                "llm.response: the response had no choices",
+                            ));
+                        };
+                        let message = &choice.message;
+                        let tool_calls = message
+                            .tool_calls
+                            .iter()
+                            .flatten()
+                            .filter_map(|call| match call {
+                                ChatCompletionMessageToolCalls::Function(call) => Some(ToolCall {
+                                    id: call.id.clone(),
+                                    name: call.function.name.clone(),
+                                    arguments: call.function.arguments.clone(),
+                                }),
+                                ChatCompletionMessageToolCalls::Custom(_) => None,
+                            })
+                            .collect::<Vec<_>>();
+
+                        Ok((
+                            message.content.clone(),
+                            tool_calls_to_lua(&lua, &tool_calls)?,
+                            result.to_lua(&lua)?,
+                        ))
+                    })
+                    .await
+                }
+            }
+        })?,
+    )?;
 
-                    let mut stream = create_chat_stream(&client, &model, seed, messages).await?;
+    // Like `response`, but asks for JSON (unless another response_format is given) and returns
+    // the parsed result as a Lua value
+    llm.set(
+        "json",
+        lua.create_async_function({
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
+                async move {
+                    until_cancelled(cancellation?, async move {
+                        let mut args = parse_llm_args(&lua, &args)?;
+                        check_budget(&lua, &tracker, Resource::Tokens)?;
+                        args.response_format
+                            .get_or_insert(ResponseFormat::JsonObject);
 
-                    let mut output = String::new();
+                        let started = Instant::now();
+                        let response = with_timeout(
+                            args.timeout,
+                            started,
+                            create_completion(&ai, args.request(false)?),
+                        )
+                        .await?;
+                        let result = CompletionResult::from_response(&response, started);
+                        charge_tokens(&lua, &tracker, result.usage.as_ref())?;
 
-                    while let Some(response) = stream.next().await {
-                        let Ok(response) = response else { continue };
-                        let Some(content) = response
+                        let text = response
                             .choices
                             .first()
-                            .and_then(|c| c.delta.content.as_ref())
-                        else {
-                            continue;
-                        };
-                        output.push_str(content);
-                        let value = callback.call::<mlua::Value>(output.clone())?;
-                        if value.as_boolean().is_some_and(|b| !b) {
-                            // Allow the user to cancel the stream by returning false
-                            break;
+                            .and_then(|c| c.message.content.clone())
+                            .unwrap_or_default();
+                        let value = parse_json_response(&text).map_err(|e| {
+                            mlua::Error::runtime(format!(
+                                "llm.json: the response was not valid JSON ({e}). Raw response:\n{text}"
+                            ))
+                        })?;
+                        Ok((to_lua_value(&lua, &value)?, result.to_lua(&lua)?))
+                    })
+                    .await
+                }
+            }
+        })?,
+    )?;
+
+    // Streams a completion, runs any tools the model calls and feeds their results back,
+    // until the model gives a final answer. Returns the final answer, and the result of the
+    // last round with the usage of every round added up.
+    llm.set(
+        "run_tools",
+        lua.create_async_function({
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
+                let parent = lua.current_thread();
+                async move {
+                    until_cancelled(cancellation?, async move {
+                        let mut args = parse_llm_args(&lua, &args)?;
+                        let max_rounds = args.max_tool_rounds.unwrap_or(DEFAULT_MAX_TOOL_ROUNDS);
+                        let started = Instant::now();
+                        let mut usage: Option<CompletionUsage> = None;
+
+                        for _ in 0..max_rounds {
+                            check_budget(&lua, &tracker, Resource::Tokens)?;
+                            let callback = args.callback.clone();
+                            let mut completion = stream_completion(
+                                &ai,
+                                args.request(true)?,
+                                args.timeout,
+                                |_, output, result| match &callback {
+                                    Some(callback) => call_callback(&lua, callback, output, result),
+                                    None => Ok(mlua::Value::Nil),
+                                },
+                                |_, reasoning| {
+                                    call_reasoning_callback(&args.reasoning_callback, reasoning)
+                                },
+                            )
+                            .await?;
+                            charge_tokens(&lua, &tracker, completion.result.usage.as_ref())?;
+                            usage = add_usage(usage, completion.result.usage.take());
+
+                            if completion.tool_calls.is_empty() {
+                                let result = CompletionResult {
+                                    usage,
+                                    latency_ms: started.elapsed().as_millis() as u64,
+                                    ..completion.result
+                                };
+                                return Ok((completion.content, result.to_lua(&lua)?));
+                            }
+
+                            args.messages.push(ChatCompletionRequestMessage::Assistant(
+                                ChatCompletionRequestAssistantMessage {
+                                    content: (!completion.content.is_empty())
+                                        .then(|| completion.content.into()),
+                                    tool_calls: Some(
+                                        completion
+                                            .tool_calls
+                                            .iter()
+                                            .map(ToolCall::to_request)
+                                            .collect(),
+                                    ),
+                                    ..Default::default()
+                                },
+                            ));
+                            for call in &completion.tool_calls {
+                                let result = run_tool(&lua, &parent, &args.tools, call).await?;
+                                args.messages.push(ChatCompletionRequestMessage::Tool(
+                                    ChatCompletionRequestToolMessage {
+                                        content: ChatCompletionRequestToolMessageContent::Text(
+                                            result,
+                                        ),
+                                        tool_call_id: call.id.clone(),
+                                    },
+                                ));
+                            }
                         }
-                    }
 
-                    Ok(())
+                        Err(mlua::Error::runtime(format!(
+                            "llm.run_tools: the model was still calling tools after {max_rounds} rounds"
+                        )))
+                    })
+                    .await
                 }
             }
         })?,
     )?;
 
+    // Embeds one input (returning its vector) or a list of them (returning a list of vectors)
     llm.set(
-        "response",
+        "embed",
         lua.create_async_function({
-            let client = ai.client.clone();
-            move |_lua, args: mlua::Table| {
-                let client = client.clone();
+            let ai = ai.clone();
+            let tracker = tracker.clone();
+            move |lua, args: mlua::Table| {
+                let ai = ai.clone();
+                let tracker = tracker.clone();
+                let cancellation = current_cancellation(&lua);
                 async move {
-                    let (model, seed, messages, _) = parse_llm_args(&args)?;
-
-                    let response = client
-                        .chat()
-                        .create(
-                            CreateChatCompletionRequestArgs::default()
-                                .model(model)
-                                .seed(seed)
-                                .messages(messages)
-                                .build()
-                                .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?,
-                        )
-                        .await
-                        .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?;
+                    until_cancelled(cancellation?, async move {
+                        let model = args.get::<String>("model")?;
+                        let (input, single) = match args.get::<mlua::Value>("input")? {
+                            mlua::Value::String(input) => {
+                                (EmbeddingInput::String(input.to_str()?.to_owned()), true)
+                            }
+                            value => (EmbeddingInput::StringArray(lua.from_value(value)?), false),
+                        };
+                        let mut request = CreateEmbeddingRequestArgs::default();
+                        request.model(model.clone()).input(input);
+                        if let Some(dimensions) = args.get::<Option<u32>>("dimensions")? {
+                            request.dimensions(dimensions);
+                        }
+                        let request = request
+                            .build()
+                            .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?;
+                        check_budget(&lua, &tracker, Resource::Tokens)?;
+
+                        let started = Instant::now();
+                        let response = ai
+                            .embed(request)
+                            .await
+                            .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?;
+                        let result = CompletionResult {
+                            usage: Some(CompletionUsage {
+                                prompt_tokens: response.usage.prompt_tokens,
+                                completion_tokens: 0,
+                                total_tokens: response.usage.total_tokens,
+                                prompt_tokens_details: None,
+                                completion_tokens_details: None,
+                            }),
+                            model: Some(response.model.clone()),
+                            latency_ms: started.elapsed().as_millis() as u64,
+                            ..Default::default()
+                        };
+                        charge_tokens(&lua, &tracker, result.usage.as_ref())?;
 
-                    Ok(response.choices[0].message.content.clone())
+                        let vectors = if single {
+                            let vector = response.data.into_iter().next().ok_or_else(|| {
+                                mlua::Error::runtime("llm.embed: no embedding was returned")
+                            })?;
+                            lua.to_value(&vector.embedding)?
+                        } else {
+                            let vectors: Vec<_> =
+                                response.data.into_iter().map(|e| e.embedding).collect();
+                            lua.to_value(&vectors)?
+                        };
+                        Ok((vectors, result.to_lua(&lua)?))
+                    })
+                    .await
                 }
             }
         })?,
@@ -121,14 +408,88 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
     Ok(())
 }
 
-fn parse_llm_args(
-    args: &mlua::Table,
-) -> mlua::Result<(
-    String,
-    u32,
-    Vec<ChatCompletionRequestMessage>,
-    Option<mlua::Function>,
-)> {
+/// Arguments shared by all of the completion functions
+struct LlmArgs {
+    model: String,
+    seed: u32,
+    messages: Vec<ChatCompletionRequestMessage>,
+    callback: Option<mlua::Function>,
+    /// Called with the model's reasoning, for models that stream it separately
+    reasoning_callback: Option<mlua::Function>,
+    tools: Vec<LuaTool>,
+    max_tool_rounds: Option<usize>,
+    response_format: Option<ResponseFormat>,
+    sampling: SamplingParams,
+    /// How long each request may take before giving up
+    timeout: Option<Duration>,
+}
+impl LlmArgs {
+    fn request(&self, stream: bool) -> mlua::Result<CreateChatCompletionRequest> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
+            .model(self.model.clone())
+            .seed(self.seed)
+            .messages(self.messages.clone());
+        if stream {
+            // Streamed chunks are read as a single choice, so more would be interleaved
+            if self.sampling.n.is_some_and(|n| n > 1) {
+                return Err(field_error(
+                    "n",
+                    "streamed completions only support a single choice; use llm.response instead",
+                ));
+            }
+            // Ask for a final chunk with the token usage
+            request
+                .stream(true)
+                .stream_options(ChatCompletionStreamOptions {
+                    include_usage: Some(true),
+                    include_obfuscation: None,
+                });
+        }
+        if let Some(response_format) = &self.response_format {
+            request.response_format(response_format.clone());
+        }
+        self.sampling.apply(&mut request);
+        if !self.tools.is_empty() {
+            request.tools(
+                self.tools
+                    .iter()
+                    .map(|tool| ChatCompletionTools::Function(tool.definition.clone()))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        request
+            .build()
+            .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))
+    }
+}
+
+/// A tool the model may call, implemented by a Lua function
+struct LuaTool {
+    definition: ChatCompletionTool,
+    execute: Option<mlua::Function>,
+}
+
+/// A tool call requested by the model. `arguments` is the JSON the model generated.
+#[derive(Default, Serialize)]
+struct ToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+impl ToolCall {
+    fn to_request(&self) -> ChatCompletionMessageToolCalls {
+        ChatCompletionMessageToolCalls::Function(ChatCompletionMessageToolCall {
+            id: self.id.clone(),
+            function: FunctionCall {
+                name: self.name.clone(),
+                arguments: self.arguments.clone(),
+            },
+        })
+    }
+}
+
+fn parse_llm_args(lua: &mlua::Lua, args: &mlua::Table) -> mlua::Result<LlmArgs> {
     let model = args.get::<String>("model")?;
     let seed = if args.contains_key("seed")? {
         args.get::<u32>("seed")?
@@ -144,38 +505,631 @@ fn parse_llm_args(
 
     let messages: Vec<ChatCompletionRequestMessage> = messages
         .sequence_values::<mlua::Table>()
-        .map(|table| from_message_table_to_message(table?))
+        .map(|table| from_message_table_to_message(lua, table?))
         .collect::<mlua::Result<Vec<_>>>()?;
 
-    Ok((model, seed, messages, callback))
+    let tools = match args.get::<Option<mlua::Table>>("tools")? {
+        Some(tools) => tools
+            .sequence_values::<mlua::Table>()
+            .map(|tool| parse_tool(lua, tool?))
+            .collect::<mlua::Result<Vec<_>>>()?,
+        None => vec![],
+    };
+    let max_tool_rounds = args.get::<Option<usize>>("max_tool_rounds")?;
+    let response_format = match args.get::<mlua::Value>("response_format")? {
+        mlua::Value::Nil => None,
+        value => Some(parse_response_format(lua, value)?),
+    };
+
+    Ok(LlmArgs {
+        model,
+        seed,
+        messages,
+        callback,
+        reasoning_callback: args.get("reasoning_callback")?,
+        tools,
+        max_tool_rounds,
+        response_format,
+        sampling: SamplingParams::parse(args)?,
+        timeout: get_number(args, "timeout", 1.0..=86_400.0)?.map(Duration::from_secs_f32),
+    })
 }
 
-async fn create_chat_stream(
-    client: &async_openai::Client<async_openai::config::OpenAIConfig>,
-    model: &str,
-    seed: u32,
-    messages: Vec<ChatCompletionRequestMessage>,
-) -> mlua::Result<
-    impl serenity::futures::Stream<
-        Item = Result<
-            async_openai::types::chat::CreateChatCompletionStreamResponse,
-            async_openai::error::OpenAIError,
-        >,
-    >,
-> {
-    client
-        .chat()
-        .create_stream(
-            CreateChatCompletionRequestArgs::default()
-                .model(model)
-                .seed(seed)
-                .messages(messages)
-                .stream(true)
-                .build()
-                .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?,
-        )
+/// Optional sampling parameters; anything left unset uses the server's default
+#[derive(Default)]
+struct SamplingParams {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    stop: Option<Vec<String>>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    logit_bias: Option<HashMap<String, i8>>,
+    n: Option<u8>,
+}
+impl SamplingParams {
+    fn parse(args: &mlua::Table) -> mlua::Result<Self> {
+        let stop = match get_field::<mlua::Value>(args, "stop")? {
+            None | Some(mlua::Value::Nil) => None,
+            Some(mlua::Value::String(stop)) => Some(vec![stop.to_string_lossy().to_string()]),
+            Some(mlua::Value::Table(stops)) => Some(
+                stops
+                    .sequence_values::<String>()
+                    .collect::<mlua::Result<Vec<_>>>()
+                    .map_err(|e| field_error("stop", e))?,
+            ),
+            Some(other) => {
+                return Err(field_error(
+                    "stop",
+                    format!(
+                        "expected a string or a list of strings, got {}",
+                        other.type_name()
+                    ),
+                ));
+            }
+        };
+        if let Some(stop) = &stop
+            && !(1..=4).contains(&stop.len())
+        {
+            return Err(field_error(
+                "stop",
+                format!(
+                    "expected between 1 and 4 stop sequences, got {}",
+                    stop.len()
+                ),
+            ));
+        }
+
+        let logit_bias = get_field::<HashMap<String, f64>>(args, "logit_bias")?
+            .map(|biases| {
+                biases
+                    .into_iter()
+                    .map(|(token, bias)| {
+                        let bias = in_range("logit_bias", bias, -100.0..=100.0)?;
+                        Ok((token, bias as i8))
+                    })
+                    .collect::<mlua::Result<HashMap<_, _>>>()
+            })
+            .transpose()?;
+
+        Ok(Self {
+            temperature: get_number(args, "temperature", 0.0..=2.0)?,
+            top_p: get_number(args, "top_p", 0.0..=1.0)?,
+            max_tokens: get_integer(args, "max_tokens", 1..=u32::MAX as i64)?,
+            stop,
+            presence_penalty: get_number(args, "presence_penalty", -2.0..=2.0)?,
+            frequency_penalty: get_number(args, "frequency_penalty", -2.0..=2.0)?,
+            logit_bias,
+            n: get_integer(args, "n", 1..=128)?,
+        })
+    }
+
+    fn apply(&self, request: &mut CreateChatCompletionRequestArgs) {
+        if let Some(temperature) = self.temperature {
+            request.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            request.top_p(top_p);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            request.max_completion_tokens(max_tokens);
+        }
+        if let Some(stop) = &self.stop {
+            request.stop(StopConfiguration::StringArray(stop.clone()));
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            request.presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            request.frequency_penalty(frequency_penalty);
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            request.logit_bias(logit_bias.clone());
+        }
+        if let Some(n) = self.n {
+            request.n(n);
+        }
+    }
+}
+
+fn field_error(field: &str, error: impl std::fmt::Display) -> mlua::Error {
+    mlua::Error::runtime(format!("invalid `{field}`: {error}"))
+}
+
+fn get_field<T: mlua::FromLua>(args: &mlua::Table, field: &str) -> mlua::Result<Option<T>> {
+    args.get::<Option<T>>(field)
+        .map_err(|e| field_error(field, e))
+}
+
+/// Gets an optional number, checking that it's within `range`
+fn get_number(
+    args: &mlua::Table,
+    field: &str,
+    range: RangeInclusive<f64>,
+) -> mlua::Result<Option<f32>> {
+    get_field::<f64>(args, field)?
+        .map(|value| in_range(field, value, range))
+        .transpose()
+}
+
+/// Gets an optional whole number, checking that it's within `range`
+fn get_integer<T: TryFrom<i64>>(
+    args: &mlua::Table,
+    field: &str,
+    range: RangeInclusive<i64>,
+) -> mlua::Result<Option<T>> {
+    get_field::<f64>(args, field)?
+        .map(|value| {
+            let integer = value as i64;
+            if value.fract() == 0.0 && range.contains(&integer) {
+                T::try_from(integer).map_err(|_| field_error(field, "out of range"))
+            } else {
+                Err(field_error(
+                    field,
+                    format!(
+                        "expected a whole number between {} and {}, got {value}",
+                        range.start(),
+                        range.end()
+                    ),
+                ))
+            }
+        })
+        .transpose()
+}
+
+fn in_range(field: &str, value: f64, range: RangeInclusive<f64>) -> mlua::Result<f32> {
+    if range.contains(&value) {
+        Ok(value as f32)
+    } else {
+        Err(field_error(
+            field,
+            format!(
+                "expected a number between {} and {}, got {value}",
+                range.start(),
+                range.end()
+            ),
+        ))
+    }
+}
+
+/// Parses a response format, given either as the name of the type (`"text"` or `"json_object"`)
+/// or as a table: `{ type = "json_schema", name, schema, description, strict }`
+fn parse_response_format(lua: &mlua::Lua, value: mlua::Value) -> mlua::Result<ResponseFormat> {
+    let (kind, table) = match value {
+        mlua::Value::String(kind) => (kind.to_string_lossy().to_string(), None),
+        mlua::Value::Table(table) => (table.get::<String>("type")?, Some(table)),
+        other => {
+            return Err(mlua::Error::runtime(format!(
+                "response_format must be a string or a table, not {}",
+                other.type_name()
+            )));
+        }
+    };
+
+    match (kind.as_str(), table) {
+        ("text", _) => Ok(ResponseFormat::Text),
+        ("json_object", _) => Ok(ResponseFormat::JsonObject),
+        ("json_schema", Some(table)) => {
+            let schema = match table.get::<mlua::Value>("schema")? {
+                mlua::Value::Nil => None,
+                schema => Some(lua.from_value::<serde_json::Value>(schema)?),
+            };
+            Ok(ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    name: table
+                        .get::<Option<String>>("name")?
+                        .unwrap_or_else(|| "response".to_string()),
+                    description: table.get("description")?,
+                    schema,
+                    strict: table.get("strict")?,
+                },
+            })
+        }
+        ("json_schema", None) => Err(mlua::Error::runtime(
+            "response_format `json_schema` must be a table with a `schema`",
+        )),
+        (kind, _) => Err(mlua::Error::runtime(format!(
+            "unknown response_format type `{kind}` (expected text, json_object or json_schema)"
+        ))),
+    }
+}
+
+/// Parses a model's JSON response, ignoring a Markdown code fence around it if there is one
+fn parse_json_response(text: &str) -> Result<serde_json::Value, serde_json::Error> {
+    let trimmed = text.trim();
+    let unfenced = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(trimmed);
+    serde_json::from_str(unfenced.trim())
+}
+
+/// Parses a tool table of the form `{ name, description, parameters, execute }`, where
+/// `parameters` is a JSON schema for the tool's arguments
+fn parse_tool(lua: &mlua::Lua, tool: mlua::Table) -> mlua::Result<LuaTool> {
+    let name = tool.get::<String>("name")?;
+    let parameters = match tool.get::<mlua::Value>("parameters")? {
+        mlua::Value::Nil => None,
+        value => Some(lua.from_value::<serde_json::Value>(value)?),
+    };
+
+    Ok(LuaTool {
+        definition: ChatCompletionTool {
+            function: FunctionObject {
+                name,
+                description: tool.get::<Option<String>>("description")?,
+                parameters,
+                strict: None,
+            },
+        },
+        execute: tool.get::<Option<mlua::Function>>("execute")?,
+    })
+}
+
+/// Runs the tool the model asked for on its own thread, which shares the calling execution's
+/// channels. Problems are reported back to the model rather than raised, so it can recover.
+async fn run_tool(
+    lua: &mlua::Lua,
+    parent: &mlua::Thread,
+    tools: &[LuaTool],
+    call: &ToolCall,
+) -> mlua::Result<String> {
+    let Some(tool) = tools
+        .iter()
+        .find(|tool| tool.definition.function.name == call.name)
+    else {
+        return Ok(format!("Error: there is no tool named `{}`", call.name));
+    };
+    let Some(execute) = &tool.execute else {
+        return Ok(format!("Error: the tool `{}` can't be run", call.name));
+    };
+
+    let arguments = if call.arguments.trim().is_empty() {
+        serde_json::Value::Object(Default::default())
+    } else {
+        match serde_json::from_str::<serde_json::Value>(&call.arguments) {
+            Ok(arguments) => arguments,
+            Err(err) => return Ok(format!("Error: the arguments are not valid JSON: {err}")),
+        }
+    };
+    let arguments = to_lua_value(lua, &arguments)?;
+
+    let thread = lua.create_thread(execute.clone())?;
+    let _channels = TemporaryChannelUpdate::inherit(lua.clone(), parent, &thread)?;
+    match thread.into_async::<mlua::Value>(arguments)?.await {
+        Ok(mlua::Value::String(s)) => Ok(s.to_string_lossy().to_string()),
+        Ok(mlua::Value::Nil) => Ok(String::new()),
+        Ok(value) => Ok(lua.from_value::<serde_json::Value>(value)?.to_string()),
+        Err(err) => Ok(format!("Error: {err}")),
+    }
+}
+
+/// Metadata about a completion, returned to Lua alongside its content
+#[derive(Default, Serialize)]
+struct CompletionResult {
+    finish_reason: Option<String>,
+    usage: Option<CompletionUsage>,
+    /// The model that actually served the request, which may differ from the one asked for
+    model: Option<String>,
+    latency_ms: u64,
+    /// The content of every choice, when more than one was asked for with `n`
+    choices: Option<Vec<String>>,
+    /// The model's reasoning, for models that stream it separately from the content
+    reasoning: Option<String>,
+    /// Why the stream stopped partway through, if it failed
+    error: Option<String>,
+}
+impl CompletionResult {
+    fn from_response(response: &CreateChatCompletionResponse, started: Instant) -> Self {
+        Self {
+            finish_reason: response
+                .choices
+                .first()
+                .and_then(|c| serde_json::to_value(c.finish_reason?).ok())
+                .and_then(|reason| reason.as_str().map(str::to_owned)),
+            usage: response.usage.clone(),
+            model: Some(response.model.clone()),
+            latency_ms: started.elapsed().as_millis() as u64,
+            choices: (response.choices.len() > 1).then(|| {
+                response
+                    .choices
+                    .iter()
+                    .map(|c| c.message.content.clone().unwrap_or_default())
+                    .collect()
+            }),
+            reasoning: None,
+            error: None,
+        }
+    }
+
+    fn to_lua(&self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        to_lua_value(lua, self)
+    }
+}
+
+/// Records the tokens a request used against whoever started the current execution
+fn charge_tokens(
+    lua: &mlua::Lua,
+    tracker: &UsageTracker,
+    usage: Option<&CompletionUsage>,
+) -> mlua::Result<()> {
+    let Some(usage) = usage else {
+        return Ok(());
+    };
+    charge(
+        lua,
+        tracker,
+        Usage {
+            prompt_tokens: usage.prompt_tokens as u64,
+            completion_tokens: usage.completion_tokens as u64,
+            ..Default::default()
+        },
+    )
+}
+
+/// Adds up the usage of several requests
+fn add_usage(
+    total: Option<CompletionUsage>,
+    usage: Option<CompletionUsage>,
+) -> Option<CompletionUsage> {
+    match (total, usage) {
+        (Some(total), Some(usage)) => Some(CompletionUsage {
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+            total_tokens: total.total_tokens + usage.total_tokens,
+            ..Default::default()
+        }),
+        (total, usage) => total.or(usage),
+    }
+}
+
+/// Calls a streaming callback with a piece of content. Once the stream has finished, the
+/// callback is called one last time with the completion's result as a second argument.
+fn call_callback(
+    lua: &mlua::Lua,
+    callback: &mlua::Function,
+    content: &str,
+    result: Option<&CompletionResult>,
+) -> mlua::Result<mlua::Value> {
+    match result {
+        Some(result) => callback.call((content, result.to_lua(lua)?)),
+        None => callback.call(content),
+    }
+}
+
+fn call_reasoning_callback(callback: &Option<mlua::Function>, reasoning: &str) -> mlua::Result<()> {
+    match callback {
+        Some(callback) => callback.call(reasoning),
+        None => Ok(()),
+    }
+}
+
+/// The accumulated result of a streamed completion
+#[derive(Default)]
+struct StreamedCompletion {
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    result: CompletionResult,
+}
+
+/// A streamed chunk of a completion. async-openai's own types drop the reasoning content that
+/// some OpenAI-compatible servers stream, so the parts we use are deserialized here instead.
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<CompletionUsage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+    // Servers disagree on what to call the reasoning
+    reasoning_content: Option<String>,
+    reasoning: Option<String>,
+    tool_calls: Option<Vec<ChatCompletionMessageToolCallChunk>>,
+}
+
+/// Creates a completion with the first backend serving the model that can be reached
+async fn create_completion(
+    ai: &Ai,
+    request: CreateChatCompletionRequest,
+) -> mlua::Result<CreateChatCompletionResponse> {
+    let model = request.model.clone();
+    ai.send(&model, move |client| {
+        let request = request.clone();
+        async move { client.chat().create(request).await }
+    })
+    .await
+    .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))
+}
+
+/// Streams a completion, calling `on_content` with each new piece of content and the content
+/// so far. Returning `false` from `on_content` stops the stream early. If the stream runs to
+/// completion, `on_content` is called a final time with no new content and the result.
+/// Reasoning is passed to `on_reasoning` in the same way.
+///
+/// If the stream fails or times out partway through, `on_content` is still called a final time,
+/// with the content so far and the error in the result, before the error is returned.
+async fn stream_completion(
+    ai: &Ai,
+    request: CreateChatCompletionRequest,
+    timeout: Option<Duration>,
+    mut on_content: impl FnMut(&str, &str, Option<&CompletionResult>) -> mlua::Result<mlua::Value>,
+    mut on_reasoning: impl FnMut(&str, &str) -> mlua::Result<()>,
+) -> mlua::Result<StreamedCompletion> {
+    let started = Instant::now();
+    let mut stream = with_timeout(timeout, started, open_stream(ai, request)).await?;
+
+    let mut content = String::new();
+    // Tool calls arrive in fragments, identified by their index
+    let mut tool_calls = BTreeMap::<u32, ToolCall>::new();
+    let mut reasoning = String::new();
+    let mut result = CompletionResult::default();
+    let mut finished = true;
+    let mut failure = None;
+
+    loop {
+        let next = with_timeout(timeout, started, async {
+            stream
+                .next()
+                .await
+                .transpose()
+                .map_err(mlua::Error::external)
+        })
+        .await;
+        let response = match next {
+            Ok(Some(response)) => response,
+            Ok(None) => break,
+            Err(err) => {
+                failure = Some(err);
+                break;
+            }
+        };
+        if result.model.is_none() && !response.model.is_empty() {
+            result.model = Some(response.model);
+        }
+        // The usage arrives in a final chunk with no choices
+        if response.usage.is_some() {
+            result.usage = response.usage;
+        }
+        let Some(choice) = response.choices.first() else {
+            continue;
+        };
+        if choice.finish_reason.is_some() {
+            result.finish_reason = choice.finish_reason.clone();
+        }
+
+        if let Some(delta) = choice
+            .delta
+            .reasoning_content
+            .as_ref()
+            .or(choice.delta.reasoning.as_ref())
+        {
+            reasoning.push_str(delta);
+            on_reasoning(delta, &reasoning)?;
+        }
+
+        for chunk in choice.delta.tool_calls.iter().flatten() {
+            let call = tool_calls.entry(chunk.index).or_default();
+            if let Some(id) = &chunk.id {
+                call.id.push_str(id);
+            }
+            if let Some(function) = &chunk.function {
+                if let Some(name) = &function.name {
+                    call.name.push_str(name);
+                }
+                if let Some(arguments) = &function.arguments {
+                    call.arguments.push_str(arguments);
+                }
+            }
+        }
+
+        let Some(delta) = &choice.delta.content else {
+            continue;
+        };
+        content.push_str(delta);
+        let value = on_content(delta, &content, None)?;
+        if value.as_boolean().is_some_and(|b| !b) {
+            // Allow the user to cancel the stream by returning false
+            finished = false;
+            break;
+        }
+    }
+
+    result.latency_ms = started.elapsed().as_millis() as u64;
+    result.reasoning = (!reasoning.is_empty()).then_some(reasoning);
+    if let Some(err) = failure {
+        // Hand over what did arrive, so that it isn't lost along with the error
+        result.finish_reason = Some("error".to_string());
+        result.error = Some(err.to_string());
+        on_content("", &content, Some(&result))?;
+        return Err(mlua::Error::runtime(format!(
+            "the stream failed after {} characters of output: {err}",
+            content.chars().count()
+        )));
+    }
+    if finished {
+        on_content("", &content, Some(&result))?;
+    }
+
+    Ok(StreamedCompletion {
+        content,
+        tool_calls: tool_calls.into_values().collect(),
+        result,
+    })
+}
+
+/// Waits for `request`, failing if it hasn't finished once `timeout` has passed since `started`
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    started: Instant,
+    request: impl Future<Output = mlua::Result<T>>,
+) -> mlua::Result<T> {
+    let Some(timeout) = timeout else {
+        return request.await;
+    };
+    tokio::time::timeout_at((started + timeout).into(), request)
         .await
-        .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))
+        .map_err(|_| {
+            mlua::Error::runtime(format!(
+                "the request took longer than {}s",
+                timeout.as_secs_f32()
+            ))
+        })?
+}
+
+/// Opens a stream with the first backend serving the model that can be reached
+async fn open_stream(
+    ai: &Ai,
+    request: CreateChatCompletionRequest,
+) -> mlua::Result<EventStream<StreamChunk>> {
+    let model = request.model.clone();
+    ai.stream(&model, move |client| {
+        let request = request.clone();
+        async move {
+            client
+                .chat()
+                .create_stream_byot::<_, StreamChunk>(request)
+                .await
+        }
+    })
+    .await
+    .map_err(mlua::Error::external)
+}
+
+/// Replaces `llm.models` and calls the `llm.on_models_changed` hooks. A hook that fails is
+/// logged rather than keeping the others from running.
+pub fn notify_models_changed(lua: &mlua::Lua, models: &[Model]) -> mlua::Result<()> {
+    let llm: mlua::Table = lua.globals().get("llm")?;
+    let previous: mlua::Value = llm.get("models")?;
+    let models = lua.to_value(models)?;
+    llm.set("models", models.clone())?;
+
+    let hooks: mlua::Table = lua.named_registry_value(MODELS_CHANGED_HOOKS_KEY)?;
+    for hook in hooks.sequence_values::<mlua::Function>() {
+        if let Err(err) = hook?.call::<()>((models.clone(), previous.clone())) {
+            eprintln!("llm.on_models_changed hook failed: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// Converts tool calls to a Lua array of `{ id, name, arguments }`, or nil if there are none
+fn tool_calls_to_lua(lua: &mlua::Lua, tool_calls: &[ToolCall]) -> mlua::Result<mlua::Value> {
+    if tool_calls.is_empty() {
+        return Ok(mlua::Value::Nil);
+    }
+    lua.to_value(tool_calls)
 }
 
 fn register_message(lua: &mlua::Lua, table: &mlua::Table, role: &str) -> mlua::Result<()> {
@@ -190,9 +1144,11 @@ fn register_message(lua: &mlua::Lua, table: &mlua::Table, role: &str) -> mlua::R
                     // This is an array of parts - store as "parts" for multimodal messages
                     output.set("parts", table.clone())?;
                 } else {
-                    output.set("content", table.get::<String>("content")?)?;
-                    if let Ok(name) = table.get::<String>("name") {
-                        output.set("name", name)?;
+                    // Assistant messages that call tools may have no content, and messages
+                    // that need other fields too can give their parts as `parts`
+                    output.set("content", table.get::<Option<String>>("content")?)?;
+                    for key in ["parts", "name", "tool_call_id", "tool_calls"] {
+                        output.set(key, table.get::<mlua::Value>(key)?)?;
                     }
                 }
             } else if let Some(text) = value.as_string() {
@@ -207,85 +1163,125 @@ fn register_message(lua: &mlua::Lua, table: &mlua::Table, role: &str) -> mlua::R
     table.set(role, f)
 }
 
-fn from_message_table_to_message(table: mlua::Table) -> mlua::Result<ChatCompletionRequestMessage> {
+fn from_message_table_to_message(
+    lua: &mlua::Lua,
+    table: mlua::Table,
+) -> mlua::Result<ChatCompletionRequestMessage> {
     let role = table.get::<String>("role")?;
     let name = if table.contains_key("name")? {
         Some(table.get::<String>("name")?)
     } else {
         None
     };
+    let parts = table
+        .get::<Option<mlua::Table>>("parts")?
+        .map(|parts| parse_content_parts(lua, parts))
+        .transpose()?;
 
     match role.as_str() {
         "system" => {
-            let content = table.get::<String>("content")?;
+            let content = match parts {
+                Some(parts) => ChatCompletionRequestSystemMessageContent::Array(
+                    text_parts(&role, parts)?
+                        .into_iter()
+                        .map(ChatCompletionRequestSystemMessageContentPart::Text)
+                        .collect(),
+                ),
+                None => table.get::<String>("content")?.into(),
+            };
             Ok(ChatCompletionRequestMessage::System(
-                ChatCompletionRequestSystemMessage {
-                    content: content.into(),
-                    name,
-                },
+                ChatCompletionRequestSystemMessage { content, name },
+            ))
+        }
+        "developer" => {
+            let content = match parts {
+                Some(parts) => ChatCompletionRequestDeveloperMessageContent::Array(
+                    text_parts(&role, parts)?
+                        .into_iter()
+                        .map(ChatCompletionRequestDeveloperMessageContentPart::Text)
+                        .collect(),
+                ),
+                None => ChatCompletionRequestDeveloperMessageContent::Text(table.get("content")?),
+            };
+            Ok(ChatCompletionRequestMessage::Developer(
+                ChatCompletionRequestDeveloperMessage { content, name },
             ))
         }
         "user" => {
-            let content = if table.contains_key("parts")? {
-                // Multimodal message with parts
-                let parts_table = table.get::<mlua::Table>("parts")?;
-                let mut content_parts = Vec::new();
-
-                for part in parts_table.sequence_values::<mlua::Table>() {
-                    let part = part?;
-                    let part_type = part.get::<String>("type")?;
-
-                    match part_type.as_str() {
-                        "text" => {
-                            let text = part.get::<String>("text")?;
-                            content_parts.push(ChatCompletionRequestUserMessageContentPart::Text(
-                                ChatCompletionRequestMessageContentPartText { text },
-                            ));
-                        }
-                        "image" => {
-                            let data = part.get::<mlua::String>("data")?;
-                            let encoded = data_encoding::BASE64.encode(&data.as_bytes());
-                            let data_url = format!("data:image/png;base64,{encoded}");
-                            content_parts.push(
-                                ChatCompletionRequestUserMessageContentPart::ImageUrl(
-                                    ChatCompletionRequestMessageContentPartImage {
-                                        image_url: ImageUrl {
-                                            url: data_url,
-                                            detail: Some(ImageDetail::Auto),
-                                        },
-                                    },
-                                ),
-                            );
-                        }
-                        _ => {
-                            return Err(mlua::Error::FromLuaConversionError {
-                                from: "table",
-                                to: "ChatCompletionRequestUserMessageContentPart".to_string(),
-                                message: Some(format!("unknown part type `{part_type}`")),
-                            });
-                        }
-                    }
-                }
-
-                ChatCompletionRequestUserMessageContent::Array(content_parts)
-            } else {
-                table.get::<String>("content")?.into()
+            let content = match parts {
+                Some(parts) => ChatCompletionRequestUserMessageContent::Array(
+                    parts
+                        .into_iter()
+                        .map(|part| match part {
+                            ContentPart::Text(text) => {
+                                Ok(ChatCompletionRequestUserMessageContentPart::Text(text))
+                            }
+                            ContentPart::Image(image) => {
+                                Ok(ChatCompletionRequestUserMessageContentPart::ImageUrl(image))
+                            }
+                            part => Err(part.unsupported_by(&role)),
+                        })
+                        .collect::<mlua::Result<_>>()?,
+                ),
+                None => table.get::<String>("content")?.into(),
             };
-
             Ok(ChatCompletionRequestMessage::User(
                 ChatCompletionRequestUserMessage { content, name },
             ))
         }
         "assistant" => {
-            let content = table.get::<String>("content")?;
+            let content = match parts {
+                Some(parts) => Some(ChatCompletionRequestAssistantMessageContent::Array(
+                    parts
+                        .into_iter()
+                        .map(|part| match part {
+                            ContentPart::Text(text) => {
+                                Ok(ChatCompletionRequestAssistantMessageContentPart::Text(text))
+                            }
+                            ContentPart::Refusal(refusal) => Ok(
+                                ChatCompletionRequestAssistantMessageContentPart::Refusal(refusal),
+                            ),
+                            part => Err(part.unsupported_by(&role)),
+                        })
+                        .collect::<mlua::Result<_>>()?,
+                )),
+                None => table.get::<Option<String>>("content")?.map(Into::into),
+            };
+            let tool_calls = table
+                .get::<Option<mlua::Table>>("tool_calls")?
+                .map(|calls| {
+                    calls
+                        .sequence_values::<mlua::Table>()
+                        .map(|call| Ok(parse_tool_call(lua, call?)?.to_request()))
+                        .collect::<mlua::Result<Vec<_>>>()
+                })
+                .transpose()?;
             Ok(ChatCompletionRequestMessage::Assistant(
                 ChatCompletionRequestAssistantMessage {
-                    content: Some(content.into()),
+                    content,
                     name,
+                    tool_calls,
                     ..Default::default()
                 },
             ))
         }
+        "tool" => {
+            let content = match parts {
+                Some(parts) => ChatCompletionRequestToolMessageContent::Array(
+                    text_parts(&role, parts)?
+                        .into_iter()
+                        .map(ChatCompletionRequestToolMessageContentPart::Text)
+                        .collect(),
+                ),
+                None => ChatCompletionRequestToolMessageContent::Text(table.get("content")?),
+            };
+            Ok(ChatCompletionRequestMessage::Tool(
+                ChatCompletionRequestToolMessage {
+                    content,
+                    tool_call_id: table.get::<String>("tool_call_id")?,
+                },
+            ))
+        }
         _ => Err(mlua::Error::FromLuaConversionError {
             from: "table",
             to: "ChatCompletionRequestMessage".to_string(),
@@ -293,3 +1289,274 @@ fn from_message_table_to_message(table: mlua::Table) -> mlua::Result<ChatComplet
         }),
     }
 }
+
+/// A part of a multipart message. Each role accepts different kinds of part, so parts are
+/// parsed into this first and then checked against the message's role.
+enum ContentPart {
+    Text(ChatCompletionRequestMessageContentPartText),
+    Refusal(ChatCompletionRequestMessageContentPartRefusal),
+    Image(ChatCompletionRequestMessageContentPartImage),
+}
+impl ContentPart {
+    fn unsupported_by(&self, role: &str) -> mlua::Error {
+        let part_type = match self {
+            ContentPart::Text(_) => "text",
+            ContentPart::Refusal(_) => "refusal",
+            ContentPart::Image(_) => "image",
+        };
+        mlua::Error::FromLuaConversionError {
+            from: "table",
+            to: "ChatCompletionRequestMessage".to_string(),
+            message: Some(format!(
+                "`{role}` messages can't contain `{part_type}` parts"
+            )),
+        }
+    }
+}
+
+/// Parses a list of parts of the form `{ type = "text", text }`, `{ type = "refusal", refusal }`
+/// or `{ type = "image", data or url, detail? }`
+fn parse_content_parts(lua: &mlua::Lua, parts: mlua::Table) -> mlua::Result<Vec<ContentPart>> {
+    parts
+        .sequence_values::<mlua::Table>()
+        .map(|part| {
+            let part = part?;
+            let part_type = part.get::<String>("type")?;
+            match part_type.as_str() {
+                "text" => Ok(ContentPart::Text(
+                    ChatCompletionRequestMessageContentPartText {
+                        text: part.get("text")?,
+                    },
+                )),
+                "refusal" => Ok(ContentPart::Refusal(
+                    ChatCompletionRequestMessageContentPartRefusal {
+                        refusal: part.get("refusal")?,
+                    },
+                )),
+                "image" => {
+                    // Either a URL, which is passed through for the server to fetch, or the
+                    // image's data, which is sent inline
+                    let url = match part.get::<Option<String>>("url")? {
+                        Some(url) => url,
+                        None => {
+                            let data = part.get::<mlua::String>("data")?;
+                            crate::vision::to_data_url(&data.as_bytes())
+                                .map_err(|e| mlua::Error::runtime(e.to_string()))?
+                        }
+                    };
+                    let detail =
+                        get_enum::<ImageDetail>(lua, &part, "detail")?.unwrap_or(ImageDetail::Auto);
+                    Ok(ContentPart::Image(
+                        ChatCompletionRequestMessageContentPartImage {
+                            image_url: ImageUrl {
+                                url,
+                                detail: Some(detail),
+                            },
+                        },
+                    ))
+                }
+                _ => Err(mlua::Error::FromLuaConversionError {
+                    from: "table",
+                    to: "ChatCompletionRequestMessageContentPart".to_string(),
+                    message: Some(format!("unknown part type `{part_type}`")),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Checks that a role that only takes text was only given text parts
+fn text_parts(
+    role: &str,
+    parts: Vec<ContentPart>,
+) -> mlua::Result<Vec<ChatCompletionRequestMessageContentPartText>> {
+    parts
+        .into_iter()
+        .map(|part| match part {
+            ContentPart::Text(text) => Ok(text),
+            part => Err(part.unsupported_by(role)),
+        })
+        .collect()
+}
+
+/// Parses a tool call table of the form `{ id, name, arguments }`, as returned by the completion
+/// functions. `arguments` may also be given as a table, which is encoded as JSON.
+fn parse_tool_call(lua: &mlua::Lua, call: mlua::Table) -> mlua::Result<ToolCall> {
+    let arguments = match call.get::<mlua::Value>("arguments")? {
+        mlua::Value::String(s) => s.to_string_lossy().to_string(),
+        mlua::Value::Nil => "{}".to_string(),
+        value => lua.from_value::<serde_json::Value>(value)?.to_string(),
+    };
+    Ok(ToolCall {
+        id: call.get("id")?,
+        name: call.get("name")?,
+        arguments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json_response() {
+        assert_eq!(
+            parse_json_response(r#"{"a": 1}"#).unwrap(),
+            serde_json::json!({"a": 1})
+        );
+        assert_eq!(
+            parse_json_response("```json\n{\"a\": [1, 2]}\n```\n").unwrap(),
+            serde_json::json!({"a": [1, 2]})
+        );
+        assert_eq!(
+            parse_json_response("```\n[true]\n```").unwrap(),
+            serde_json::json!([true])
+        );
+        assert!(parse_json_response("Sure! Here's the JSON: {}").is_err());
+    }
+
+    #[test]
+    fn test_sampling_params_validation() {
+        let lua = mlua::Lua::new();
+        let parse = |fields: &str| {
+            SamplingParams::parse(&lua.load(format!("return {{ {fields} }}")).eval().unwrap())
+        };
+
+        let params = parse("max_tokens = 4294967295, n = 128, temperature = 0.5, stop = 'x'")
+            .ok()
+            .unwrap();
+        assert_eq!(params.max_tokens, Some(u32::MAX));
+        assert_eq!(params.n, Some(128));
+        assert_eq!(params.temperature, Some(0.5));
+        assert_eq!(params.stop, Some(vec!["x".to_string()]));
+
+        for (fields, field) in [
+            ("max_tokens = 1.5", "max_tokens"),
+            ("max_tokens = 0", "max_tokens"),
+            ("max_tokens = 4294967296", "max_tokens"),
+            ("max_tokens = 0/0", "max_tokens"),
+            ("n = 2.5", "n"),
+            ("n = 129", "n"),
+            ("temperature = 3", "temperature"),
+            ("top_p = -0.1", "top_p"),
+            ("stop = {}", "stop"),
+            ("stop = { 'a', 'b', 'c', 'd', 'e' }", "stop"),
+            ("logit_bias = { ['1'] = 101 }", "logit_bias"),
+        ] {
+            let err = parse(fields).err().unwrap();
+            assert!(
+                err.to_string().contains(&format!("invalid `{field}`")),
+                "{fields}: {err}"
+            );
+        }
+    }
+
+    const TEXT_PART: &str = "{ type = 'text', text = 'hi' }";
+    const IMAGE_PART: &str = "{ type = 'image', url = 'https://example.com/a.png' }";
+    const REFUSAL_PART: &str = "{ type = 'refusal', refusal = 'no' }";
+
+    /// Every role, and whether it accepts image and refusal parts
+    const ROLES: [(&str, bool, bool); 5] = [
+        ("system", false, false),
+        ("developer", false, false),
+        ("user", true, false),
+        ("assistant", false, true),
+        ("tool", false, false),
+    ];
+
+    /// Parses a message of the given role, with `tool_call_id` filled in for tool messages
+    fn parse_message(lua: &mlua::Lua, role: &str, fields: &str) -> mlua::Result<serde_json::Value> {
+        let table = lua
+            .load(format!(
+                "return {{ role = '{role}', tool_call_id = 'call', {fields} }}"
+            ))
+            .eval()?;
+        let message = from_message_table_to_message(lua, table)?;
+        Ok(serde_json::to_value(message).unwrap())
+    }
+
+    #[test]
+    fn test_message_content_for_every_role() {
+        let lua = mlua::Lua::new();
+        for (role, _, _) in ROLES {
+            let message = parse_message(&lua, role, "content = 'plain'").unwrap();
+            assert_eq!(message["content"], "plain", "{role}");
+
+            let message = parse_message(&lua, role, &format!("parts = {{ {TEXT_PART} }}")).unwrap();
+            assert_eq!(
+                message["content"],
+                serde_json::json!([{ "type": "text", "text": "hi" }]),
+                "{role}"
+            );
+
+            // Parts take priority over content
+            let message = parse_message(
+                &lua,
+                role,
+                &format!("content = 'ignored', parts = {{ {TEXT_PART} }}"),
+            )
+            .unwrap();
+            assert_eq!(message["content"][0]["text"], "hi", "{role}");
+        }
+    }
+
+    #[test]
+    fn test_message_parts_accepted_by_role() {
+        let lua = mlua::Lua::new();
+        for (role, images, refusals) in ROLES {
+            for (part, part_type, accepted) in [
+                (IMAGE_PART, "image", images),
+                (REFUSAL_PART, "refusal", refusals),
+            ] {
+                let result =
+                    parse_message(&lua, role, &format!("parts = {{ {TEXT_PART}, {part} }}"));
+                match result {
+                    Ok(message) => {
+                        assert!(accepted, "{role} accepted a {part_type} part");
+                        assert!(message["content"][1].is_object(), "{role}");
+                    }
+                    Err(err) => {
+                        assert!(!accepted, "{role} rejected a {part_type} part: {err}");
+                        assert!(
+                            err.to_string().contains(&format!(
+                                "`{role}` messages can't contain `{part_type}` parts"
+                            )),
+                            "{err}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_content_parts() {
+        let lua = mlua::Lua::new();
+        let parts = lua
+            .load(format!(
+                "return {{ {TEXT_PART}, {IMAGE_PART}, {REFUSAL_PART} }}"
+            ))
+            .eval()
+            .unwrap();
+        let parts = parse_content_parts(&lua, parts).unwrap();
+        assert!(matches!(
+            parts.as_slice(),
+            [
+                ContentPart::Text(_),
+                ContentPart::Image(_),
+                ContentPart::Refusal(_)
+            ]
+        ));
+        assert!(text_parts("system", parts).is_err());
+
+        let parts = lua.load("return { { type = 'audio' } }").eval().unwrap();
+        let err = parse_content_parts(&lua, parts).err().unwrap();
+        assert!(
+            err.to_string().contains("unknown part type `audio`"),
+            "{err}"
+        );
+
+        let err = parse_message(&lua, "narrator", "content = 'hi'").unwrap_err();
+        assert!(err.to_string().contains("unknown role `narrator`"), "{err}");
+    }
+}
@@ -1,28 +1,76 @@
-use std::sync::Arc;
-
-use crate::ai::Ai;
-use crate::currency::CurrencyConverter;
+use crate::cancel::Cancellation;
+use crate::lua::Services;
 
+mod audio;
 mod comfyui;
 pub mod currency;
 mod globals;
+mod history;
+mod images;
 mod llm;
 mod perchance;
+mod storage;
+mod usage;
+mod vectors;
 
-pub use globals::{Attachment, TemporaryChannelUpdate};
+pub use globals::{Attachment, AttachmentKind, Invoker, OutputUpdate, TemporaryChannelUpdate};
+pub use llm::notify_models_changed;
+pub use storage::make_shared_storage_read_only;
 
 pub fn register(
     lua: &mlua::Lua,
-    ai: Arc<Ai>,
-    currency_converter: Arc<CurrencyConverter>,
-    output_tx: flume::Sender<String>,
+    services: Services,
+    output_tx: flume::Sender<OutputUpdate>,
     print_tx: flume::Sender<String>,
     attachment_tx: flume::Sender<Attachment>,
+    cancellation: Option<Cancellation>,
+    invoker: Option<Invoker>,
 ) -> mlua::Result<()> {
-    globals::register(lua, output_tx, print_tx, attachment_tx)?;
-    llm::register(lua, ai)?;
+    globals::register(
+        lua,
+        output_tx,
+        print_tx,
+        attachment_tx,
+        cancellation,
+        invoker,
+    )?;
+    llm::register(lua, services.ai.clone(), services.usage.clone())?;
+    images::register(lua, services.ai.clone(), services.usage.clone())?;
+    audio::register(lua, services.ai, services.usage.clone())?;
     perchance::register(lua)?;
-    currency::register(lua, currency_converter)?;
-    comfyui::register(lua)?;
+    currency::register(lua, services.currency_converter)?;
+    comfyui::register(lua, services.usage.clone())?;
+    storage::register(lua, services.storage)?;
+    vectors::register(lua, services.vectors)?;
+    history::register(lua, services.history, services.usage.clone())?;
+    usage::register(lua, services.usage)?;
     Ok(())
 }
+
+/// Converts a value to Lua, leaving out fields that are `None` rather than setting them to a
+/// null sentinel
+fn to_lua_value(lua: &mlua::Lua, value: &impl serde::Serialize) -> mlua::Result<mlua::Value> {
+    use mlua::LuaSerdeExt as _;
+    lua.to_value_with(
+        value,
+        mlua::SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false),
+    )
+}
+
+/// Reads an optional string field into one of async-openai's enums
+fn get_enum<T: serde::de::DeserializeOwned>(
+    lua: &mlua::Lua,
+    args: &mlua::Table,
+    field: &str,
+) -> mlua::Result<Option<T>> {
+    use mlua::LuaSerdeExt as _;
+    match args.get::<mlua::Value>(field)? {
+        mlua::Value::Nil => Ok(None),
+        value => lua
+            .from_value(value)
+            .map(Some)
+            .map_err(|e| mlua::Error::runtime(format!("invalid `{field}`: {e}"))),
+    }
+}
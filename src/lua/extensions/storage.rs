@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use mlua::LuaSerdeExt as _;
+
+use crate::storage::{Scope, Storage};
+
+use super::globals::current_invoker;
+
+const SHARED_READ_ONLY_KEY: &str = "storage_shared_read_only";
+
+/// Register the persistent key-value storage with Lua
+pub fn register(lua: &mlua::Lua, storage: Arc<Storage>) -> mlua::Result<()> {
+    let module = lua.create_table()?;
+
+    for (name, kind) in [
+        ("global", ScopeKind::Global),
+        ("user", ScopeKind::User),
+        ("guild", ScopeKind::Guild),
+    ] {
+        module.set(
+            name,
+            lua.create_function({
+                let storage = storage.clone();
+                move |_lua, namespace: String| {
+                    Ok(Namespace {
+                        storage: storage.clone(),
+                        kind,
+                        namespace,
+                    })
+                }
+            })?,
+        )?;
+    }
+
+    lua.globals().set("storage", module)?;
+
+    Ok(())
+}
+
+/// Stops the state from changing global and guild storage, which other users' commands rely on.
+/// Used for states that run arbitrary code, where user storage remains writable.
+pub fn make_shared_storage_read_only(lua: &mlua::Lua) -> mlua::Result<()> {
+    lua.set_named_registry_value(SHARED_READ_ONLY_KEY, true)
+}
+
+/// Which scope a namespace handle refers to. User and guild scopes are resolved against the
+/// current execution each time the handle is used, so handles can't leak across users.
+#[derive(Clone, Copy)]
+enum ScopeKind {
+    Global,
+    User,
+    Guild,
+}
+
+struct Namespace {
+    storage: Arc<Storage>,
+    kind: ScopeKind,
+    namespace: String,
+}
+impl Namespace {
+    fn scope(&self, lua: &mlua::Lua) -> mlua::Result<Scope> {
+        if let ScopeKind::Global = self.kind {
+            return Ok(Scope::Global);
+        }
+
+        let invoker = current_invoker(lua)?.ok_or_else(|| {
+            mlua::Error::runtime("user and guild storage can only be used while running a command")
+        })?;
+        match self.kind {
+            ScopeKind::Global => Ok(Scope::Global),
+            ScopeKind::User => Ok(Scope::User(invoker.user_id)),
+            ScopeKind::Guild => invoker.guild_id.map(Scope::Guild).ok_or_else(|| {
+                mlua::Error::runtime("guild storage can't be used outside of a server")
+            }),
+        }
+    }
+
+    fn writable_scope(&self, lua: &mlua::Lua) -> mlua::Result<Scope> {
+        if !matches!(self.kind, ScopeKind::User)
+            && lua.named_registry_value::<bool>(SHARED_READ_ONLY_KEY)?
+        {
+            return Err(mlua::Error::runtime(
+                "global and guild storage are read-only here",
+            ));
+        }
+        self.scope(lua)
+    }
+}
+impl mlua::UserData for Namespace {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get", |lua, this, key: String| {
+            let value = this
+                .storage
+                .get(this.scope(lua)?, &this.namespace, &key)
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+            match value {
+                Some(value) => lua.to_value_with(
+                    &value,
+                    mlua::SerializeOptions::new()
+                        .serialize_none_to_null(false)
+                        .serialize_unit_to_null(false),
+                ),
+                None => Ok(mlua::Value::Nil),
+            }
+        });
+
+        // Setting a key to nil deletes it
+        methods.add_method("set", |lua, this, (key, value): (String, mlua::Value)| {
+            let scope = this.writable_scope(lua)?;
+            let result = if value.is_nil() {
+                this.storage
+                    .delete(scope, &this.namespace, &key)
+                    .map(|_| ())
+            } else {
+                let value: serde_json::Value = lua.from_value(value)?;
+                this.storage.set(scope, &this.namespace, &key, value)
+            };
+            result.map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+
+        methods.add_method("delete", |lua, this, key: String| {
+            this.storage
+                .delete(this.writable_scope(lua)?, &this.namespace, &key)
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+
+        // Returns the keys starting with the prefix (or all keys), in sorted order
+        methods.add_method("list", |lua, this, prefix: Option<String>| {
+            this.storage
+                .list(
+                    this.scope(lua)?,
+                    &this.namespace,
+                    prefix.as_deref().unwrap_or_default(),
+                )
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn test_shared_storage_read_only() {
+        let directory = std::env::temp_dir().join(format!(
+            "paxcord-storage-extension-test-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = Arc::new(
+            Storage::load(config::Storage {
+                directory: directory.to_string_lossy().into_owned(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        storage
+            .set(Scope::Global, "ns", "a", serde_json::json!(1))
+            .unwrap();
+
+        let lua = mlua::Lua::new();
+        register(&lua, storage.clone()).unwrap();
+        make_shared_storage_read_only(&lua).unwrap();
+
+        let value: i64 = lua
+            .load("return storage.global('ns'):get('a')")
+            .eval()
+            .unwrap();
+        assert_eq!(value, 1);
+        for code in [
+            "storage.global('ns'):set('a', 2)",
+            "storage.global('ns'):set('a', nil)",
+            "storage.global('ns'):delete('a')",
+            "storage.guild('ns'):set('a', 2)",
+        ] {
+            let err = lua.load(code).exec().unwrap_err();
+            assert!(err.to_string().contains("read-only"), "{code}: {err}");
+        }
+        assert_eq!(
+            storage.get(Scope::Global, "ns", "a").unwrap(),
+            Some(serde_json::json!(1))
+        );
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use serenity::all::UserId;
+
+use crate::usage::{Resource, Usage, UsageTracker};
+
+use super::globals::current_invoker;
+
+/// Register the usage accounting API with Lua
+pub fn register(lua: &mlua::Lua, tracker: Arc<UsageTracker>) -> mlua::Result<()> {
+    let module = lua.create_table()?;
+
+    // Returns a user's usage report; the user running the command if no ID is given
+    module.set(
+        "get",
+        lua.create_function(move |lua, user_id: Option<String>| {
+            let user_id = match user_id {
+                Some(user_id) => user_id
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|id| *id != 0)
+                    .map(UserId::new)
+                    .ok_or_else(|| {
+                        mlua::Error::runtime(format!("usage.get: invalid user ID `{user_id}`"))
+                    })?,
+                None => {
+                    current_invoker(lua)?
+                        .ok_or_else(|| {
+                            mlua::Error::runtime(
+                                "usage.get needs a user ID when not running a command",
+                            )
+                        })?
+                        .user_id
+                }
+            };
+            super::to_lua_value(lua, &tracker.report(user_id))
+        })?,
+    )?;
+
+    lua.globals().set("usage", module)?;
+
+    Ok(())
+}
+
+/// Raises an error if whoever started the current execution has used up their daily budget
+/// for `resource`. Executions nobody started aren't limited.
+pub fn check_budget(
+    lua: &mlua::Lua,
+    tracker: &UsageTracker,
+    resource: Resource,
+) -> mlua::Result<()> {
+    let Some(invoker) = current_invoker(lua)? else {
+        return Ok(());
+    };
+    tracker
+        .check_budget(invoker.user_id, invoker.guild_id, resource)
+        .map_err(|e| mlua::Error::runtime(e.to_string()))
+}
+
+/// Records usage against whoever started the current execution, if anyone did
+pub fn charge(lua: &mlua::Lua, tracker: &UsageTracker, usage: Usage) -> mlua::Result<()> {
+    if let Some(invoker) = current_invoker(lua)? {
+        tracker.record(
+            invoker.user_id,
+            invoker.guild_id,
+            &invoker.command_name,
+            usage,
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use mlua::LuaSerdeExt as _;
+
+use crate::vectors::VectorIndexes;
+
+/// How many results `index:search` returns if not told otherwise
+const DEFAULT_SEARCH_RESULTS: usize = 5;
+
+/// Register the vector indexes with Lua
+pub fn register(lua: &mlua::Lua, indexes: Arc<VectorIndexes>) -> mlua::Result<()> {
+    let module = lua.create_table()?;
+
+    module.set(
+        "open",
+        lua.create_function(move |_lua, name: String| {
+            Ok(Index {
+                indexes: indexes.clone(),
+                name,
+            })
+        })?,
+    )?;
+
+    lua.globals().set("vectors", module)?;
+
+    Ok(())
+}
+
+/// A handle to a named index; the index itself is loaded when first used
+struct Index {
+    indexes: Arc<VectorIndexes>,
+    name: String,
+}
+impl mlua::UserData for Index {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method(
+            "add",
+            |lua, this, (id, vector, metadata): (String, Vec<f32>, mlua::Value)| {
+                let metadata: serde_json::Value = lua.from_value(metadata)?;
+                this.indexes
+                    .add(&this.name, &id, vector, metadata)
+                    .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+            },
+        );
+
+        methods.add_method("remove", |_lua, this, id: String| {
+            this.indexes
+                .remove(&this.name, &id)
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+
+        // Returns up to `k` entries as `{ id, score, metadata }`, most similar first
+        methods.add_method(
+            "search",
+            |lua, this, (query, k): (Vec<f32>, Option<usize>)| {
+                let results = this
+                    .indexes
+                    .search(&this.name, &query, k.unwrap_or(DEFAULT_SEARCH_RESULTS))
+                    .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                super::to_lua_value(lua, &results)
+            },
+        );
+
+        methods.add_method("len", |_lua, this, ()| {
+            this.indexes
+                .len(&this.name)
+                .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, history};
+
+    #[test]
+    fn test_scripts_cannot_open_the_history_index() {
+        let root = std::env::temp_dir().join(format!(
+            "paxcord-vectors-extension-test-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut config = config::Configuration::default();
+        for directory in [&mut config.vectors.directory, &mut config.history.directory] {
+            *directory = root.join(&*directory).to_string_lossy().into_owned();
+        }
+        config.validate().unwrap();
+
+        let history = history::open_index(&config.history).unwrap();
+        history
+            .add("chunks", "1-1", vec![1.0, 0.0], serde_json::json!("secret"))
+            .unwrap();
+        history.flush().unwrap();
+
+        let lua = mlua::Lua::new();
+        register(
+            &lua,
+            Arc::new(VectorIndexes::load(config.vectors.clone()).unwrap()),
+        )
+        .unwrap();
+        for name in ["chunks", "history"] {
+            let len: usize = lua
+                .load(format!("return vectors.open('{name}'):len()"))
+                .eval()
+                .unwrap();
+            assert_eq!(len, 0, "{name}");
+        }
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
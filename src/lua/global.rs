@@ -0,0 +1,125 @@
+use std::{sync::Mutex, time::SystemTime};
+
+use crate::{
+    commands::lua_command::LuaCommandRegistry,
+    lua::{
+        COMMANDS_SCRIPT_PATH, LuaReplyHandlerRegistry, LuaTriggerRegistry, MAIN_SCRIPT_PATH,
+        Services, create_global_lua_state,
+        extensions::{Attachment, OutputUpdate, notify_models_changed},
+        refresh_choices,
+    },
+};
+
+/// A global Lua state together with the commands, reply handlers and triggers its scripts
+/// registered.
+///
+/// The registered handler functions belong to `lua`, so they must always be used together.
+#[derive(Clone)]
+pub struct LoadedScripts {
+    pub lua: mlua::Lua,
+    pub command_registry: LuaCommandRegistry,
+    pub reply_handler_registry: LuaReplyHandlerRegistry,
+    pub trigger_registry: LuaTriggerRegistry,
+}
+
+/// Owner of the global Lua state that `scripts/commands.lua` is loaded into.
+///
+/// Reloading builds an entirely new state and swaps it in at once; executions that have
+/// already taken a [`LoadedScripts`] keep running against the old state until they finish.
+pub struct GlobalLuaState {
+    services: Services,
+    output_tx: flume::Sender<OutputUpdate>,
+    print_tx: flume::Sender<String>,
+    attachment_tx: flume::Sender<Attachment>,
+    current: Mutex<LoadedScripts>,
+}
+impl GlobalLuaState {
+    pub fn new(
+        services: Services,
+        output_tx: flume::Sender<OutputUpdate>,
+        print_tx: flume::Sender<String>,
+        attachment_tx: flume::Sender<Attachment>,
+    ) -> mlua::Result<Self> {
+        let current = load_scripts(
+            services.clone(),
+            output_tx.clone(),
+            print_tx.clone(),
+            attachment_tx.clone(),
+        )?;
+
+        Ok(Self {
+            services,
+            output_tx,
+            print_tx,
+            attachment_tx,
+            current: Mutex::new(current),
+        })
+    }
+
+    pub fn services(&self) -> &Services {
+        &self.services
+    }
+
+    /// Returns the currently loaded scripts
+    pub fn current(&self) -> LoadedScripts {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Rebuilds the global Lua state from the scripts on disk. If loading fails,
+    /// the previous state is kept and the error is returned.
+    pub fn reload(&self) -> mlua::Result<()> {
+        let scripts = load_scripts(
+            self.services.clone(),
+            self.output_tx.clone(),
+            self.print_tx.clone(),
+            self.attachment_tx.clone(),
+        )?;
+        *self.current.lock().unwrap() = scripts;
+        Ok(())
+    }
+
+    /// Brings the current state up to date with the AI service's model list: updates
+    /// `llm.models`, runs the scripts' `llm.on_models_changed` hooks, and recomputes any
+    /// command choices that were given as functions
+    pub fn models_changed(&self) -> mlua::Result<()> {
+        let scripts = self.current();
+        notify_models_changed(&scripts.lua, &self.services.ai.models())?;
+        refresh_choices(&scripts.lua, &scripts.command_registry);
+        Ok(())
+    }
+}
+
+fn load_scripts(
+    services: Services,
+    output_tx: flume::Sender<OutputUpdate>,
+    print_tx: flume::Sender<String>,
+    attachment_tx: flume::Sender<Attachment>,
+) -> mlua::Result<LoadedScripts> {
+    let command_registry = LuaCommandRegistry::default();
+    let reply_handler_registry = LuaReplyHandlerRegistry::default();
+    let trigger_registry = LuaTriggerRegistry::default();
+    let lua = create_global_lua_state(
+        services,
+        output_tx,
+        print_tx,
+        attachment_tx,
+        command_registry.clone(),
+        reply_handler_registry.clone(),
+        trigger_registry.clone(),
+    )?;
+
+    Ok(LoadedScripts {
+        lua,
+        command_registry,
+        reply_handler_registry,
+        trigger_registry,
+    })
+}
+
+/// Returns the modification times of the scripts loaded into the global state, for change detection
+pub fn script_modification_times() -> Vec<Option<SystemTime>> {
+    [MAIN_SCRIPT_PATH, COMMANDS_SCRIPT_PATH]
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
@@ -1,21 +1,47 @@
 use std::sync::Arc;
 
-use crate::{ai::Ai, commands::lua_command::LuaCommandRegistry, currency::CurrencyConverter};
+use crate::{
+    ai::Ai, cancel::Cancellation, commands::lua_command::LuaCommandRegistry,
+    currency::CurrencyConverter, history::HistoryIndexer, storage::Storage, usage::UsageTracker,
+    vectors::VectorIndexes,
+};
 
 mod discord_extension;
-pub use discord_extension::LuaReplyHandlerRegistry;
+pub use discord_extension::{LuaReplyHandlerRegistry, LuaTriggerRegistry, refresh_choices};
 
 mod executor;
-pub use executor::{LuaOutputChannels, execute_lua_reply_thread, execute_lua_thread};
+pub use executor::{Accounting, LuaOutputChannels, execute_lua_reply_thread, execute_lua_thread};
 
 pub mod extensions;
 
+pub mod sandbox;
+
+mod global;
+pub use global::{GlobalLuaState, LoadedScripts, script_modification_times};
+
+/// Shared helpers, loaded into every Lua state
+pub const MAIN_SCRIPT_PATH: &str = "scripts/main.lua";
+/// Command definitions, loaded into the global Lua state only
+pub const COMMANDS_SCRIPT_PATH: &str = "scripts/commands.lua";
+
+/// Shared services that Lua states are given access to
+#[derive(Clone)]
+pub struct Services {
+    pub ai: Arc<Ai>,
+    pub currency_converter: Arc<CurrencyConverter>,
+    pub storage: Arc<Storage>,
+    pub usage: Arc<UsageTracker>,
+    pub vectors: Arc<VectorIndexes>,
+    pub history: Arc<HistoryIndexer>,
+}
+
 pub fn create_barebones_lua_state(
-    ai: Arc<Ai>,
-    currency_converter: Arc<CurrencyConverter>,
-    output_tx: flume::Sender<String>,
+    services: Services,
+    output_tx: flume::Sender<extensions::OutputUpdate>,
     print_tx: flume::Sender<String>,
     attachment_tx: flume::Sender<extensions::Attachment>,
+    cancellation: Option<Cancellation>,
+    invoker: Option<extensions::Invoker>,
 ) -> mlua::Result<mlua::Lua> {
     let lua = mlua::Lua::new_with(
         {
@@ -27,30 +53,37 @@ pub fn create_barebones_lua_state(
 
     extensions::register(
         &lua,
-        ai,
-        currency_converter,
+        services,
         output_tx,
         print_tx,
         attachment_tx,
+        cancellation,
+        invoker,
     )?;
-    load_lua_file(&lua, "scripts/main.lua")?;
+    load_lua_file(&lua, MAIN_SCRIPT_PATH)?;
 
     Ok(lua)
 }
 
 pub fn create_global_lua_state(
-    ai: Arc<Ai>,
-    currency_converter: Arc<CurrencyConverter>,
-    output_tx: flume::Sender<String>,
+    services: Services,
+    output_tx: flume::Sender<extensions::OutputUpdate>,
     print_tx: flume::Sender<String>,
     attachment_tx: flume::Sender<extensions::Attachment>,
     lua_command_registry: LuaCommandRegistry,
     lua_reply_handler_registry: LuaReplyHandlerRegistry,
+    lua_trigger_registry: LuaTriggerRegistry,
 ) -> mlua::Result<mlua::Lua> {
-    let lua =
-        create_barebones_lua_state(ai, currency_converter, output_tx, print_tx, attachment_tx)?;
-    discord_extension::register(&lua, lua_command_registry, lua_reply_handler_registry)?;
-    load_lua_file(&lua, "scripts/commands.lua")?;
+    // Nothing runs on the global state's default channels that could be cancelled, and
+    // commands register their own cancellation and invoker for the threads they run
+    let lua = create_barebones_lua_state(services, output_tx, print_tx, attachment_tx, None, None)?;
+    discord_extension::register(
+        &lua,
+        lua_command_registry,
+        lua_reply_handler_registry,
+        lua_trigger_registry,
+    )?;
+    load_lua_file(&lua, COMMANDS_SCRIPT_PATH)?;
 
     Ok(lua)
 }
@@ -0,0 +1,116 @@
+use std::{
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// Applies the configured sandbox limits to a freshly created Lua state.
+///
+/// The memory limit and instruction budget are enforced by Luau itself, and the timeout is
+/// checked on every interrupt so that tight loops can't outrun it. The timeout starts with the
+/// first interrupt, once the thread is resumed, so time spent setting up the execution isn't
+/// counted. Interrupts don't fire while the thread is waiting on Rust futures, so the returned
+/// timeout must also be enforced by whoever drives the thread.
+pub fn apply_limits(lua: &mlua::Lua, config: &config::Sandbox) -> mlua::Result<Option<Duration>> {
+    if config.memory_limit_bytes > 0 {
+        lua.set_memory_limit(config.memory_limit_bytes)?;
+    }
+
+    let timeout = (config.timeout_ms > 0).then(|| Duration::from_millis(config.timeout_ms));
+    let instruction_budget = config.instruction_budget;
+    if timeout.is_none() && instruction_budget == 0 {
+        return Ok(None);
+    }
+
+    let interrupts = AtomicU64::new(0);
+    let deadline = OnceLock::new();
+    lua.set_interrupt(move |_lua| {
+        let count = interrupts.fetch_add(1, Ordering::Relaxed) + 1;
+        if instruction_budget > 0 && count > instruction_budget {
+            return Err(mlua::Error::runtime(format!(
+                "Execution exceeded the instruction budget of {instruction_budget}"
+            )));
+        }
+        if let Some(timeout) = timeout
+            && Instant::now() >= *deadline.get_or_init(|| Instant::now() + timeout)
+        {
+            return Err(mlua::Error::runtime(format!(
+                "Execution timed out after {timeout:?}"
+            )));
+        }
+        Ok(mlua::VmState::Continue)
+    });
+
+    Ok(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(
+        timeout_ms: u64,
+        instruction_budget: u64,
+        memory_limit_bytes: usize,
+    ) -> config::Sandbox {
+        config::Sandbox {
+            timeout_ms,
+            instruction_budget,
+            memory_limit_bytes,
+        }
+    }
+
+    #[test]
+    fn test_instruction_budget_stops_tight_loop() {
+        let lua = mlua::Lua::new();
+        apply_limits(&lua, &sandbox(0, 10_000, 0)).unwrap();
+        let err = lua.load("while true do end").exec().unwrap_err();
+        assert!(err.to_string().contains("instruction budget"), "{err}");
+    }
+
+    #[test]
+    fn test_timeout_stops_tight_loop() {
+        let lua = mlua::Lua::new();
+        let timeout = apply_limits(&lua, &sandbox(50, 0, 0)).unwrap();
+        assert_eq!(timeout, Some(Duration::from_millis(50)));
+        let err = lua.load("while true do end").exec().unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+    }
+
+    #[test]
+    fn test_timeout_starts_when_execution_does() {
+        let lua = mlua::Lua::new();
+        apply_limits(&lua, &sandbox(50, 0, 0)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        lua.load("local n = 0 for i = 1, 1000 do n = n + i end")
+            .exec()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_memory_limit_stops_allocation() {
+        let lua = mlua::Lua::new();
+        apply_limits(&lua, &sandbox(0, 0, 4 * 1024 * 1024)).unwrap();
+        let err = lua
+            .load("local t = {} for i = 1, 1e8 do t[i] = string.rep('x', 64) .. i end")
+            .exec()
+            .unwrap_err();
+        assert!(matches!(err, mlua::Error::MemoryError(_)), "{err}");
+    }
+
+    #[test]
+    fn test_disabled_limits_allow_normal_execution() {
+        let lua = mlua::Lua::new();
+        assert_eq!(apply_limits(&lua, &sandbox(0, 0, 0)).unwrap(), None);
+        assert_eq!(
+            lua.load("local n = 0 for i = 1, 1000 do n = n + i end return n")
+                .eval::<i64>()
+                .unwrap(),
+            500500
+        );
+    }
+}
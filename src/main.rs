@@ -1,13 +1,19 @@
-use std::{collections::HashMap, collections::HashSet, sync::Arc};
+use std::{
+    collections::HashMap,
+    collections::HashSet,
+    sync::Arc,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use anyhow::Context as AnyhowContext;
 use mlua::LuaSerdeExt as _;
 use serenity::{
     Client,
     all::{
-        AutocompleteChoice, Command, Context, CreateAutocompleteResponse,
-        CreateInteractionResponse, CreateInteractionResponseMessage, EventHandler, Http,
-        Interaction, Message, MessageId, Ready,
+        AutocompleteChoice, ChannelId, Command, Context, CreateAutocompleteResponse, CreateCommand,
+        CreateInteractionResponse, CreateInteractionResponseMessage, EventHandler, GuildId, Http,
+        Interaction, Message, MessageId, Ready, UserId,
     },
     async_trait,
     model::prelude::GatewayIntents,
@@ -19,19 +25,29 @@ mod commands;
 mod config;
 mod constant;
 mod currency;
+mod history;
 mod interaction_context;
 mod lua;
 mod markdown_chunk;
 mod outputter;
+mod permissions;
+mod rate_limit;
 mod reply_handler;
+mod storage;
+mod usage;
 mod util;
+mod vectors;
+mod vision;
+mod voice_message;
 
 use config::Configuration;
 
 use crate::{
-    commands::lua_command::LuaCommandRegistry,
-    interaction_context::InteractionContextStore,
-    lua::{LuaReplyHandlerRegistry, create_global_lua_state},
+    cancel::CancellationRegistry,
+    interaction_context::{InteractionContext, InteractionContextStore},
+    lua::GlobalLuaState,
+    permissions::{Denial, PermissionSubject},
+    rate_limit::{ExecutionPermit, Limited, RateLimiter},
 };
 
 #[tokio::main]
@@ -44,19 +60,37 @@ async fn main() -> anyhow::Result<()> {
         .context("Expected authentication.discord_token to be filled in config")?;
 
     let ai = Arc::new(ai::Ai::load(&config).await?);
-    let currency_converter = Arc::new(currency::CurrencyConverter::new());
+    let vectors = Arc::new(vectors::VectorIndexes::load(config.vectors.clone())?);
+    let history = Arc::new(history::HistoryIndexer::new(
+        config.history.clone(),
+        ai.clone(),
+    )?);
+    if history.is_enabled() {
+        tokio::spawn(history.clone().flush_idle_chunks());
+    }
+    let usage = Arc::new(usage::UsageTracker::load(config.usage.clone())?);
+    tokio::spawn(usage.clone().flush_periodically());
+    let storage = Arc::new(storage::Storage::load(config.storage.clone())?);
+    tokio::spawn(storage.clone().flush_periodically());
+    let services = lua::Services {
+        ai,
+        currency_converter: Arc::new(currency::CurrencyConverter::new()),
+        storage: storage.clone(),
+        usage: usage.clone(),
+        vectors,
+        history: history.clone(),
+    };
 
-    let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
+    let cancellations = CancellationRegistry::default();
 
-    // Create command registry, reply handler registry, and interaction context store
-    let command_registry = LuaCommandRegistry::default();
-    let reply_handler_registry = LuaReplyHandlerRegistry::default();
-    let interaction_context_store = Arc::new(InteractionContextStore::new(
+    // Create interaction context store
+    let interaction_context_store = Arc::new(InteractionContextStore::load(
         config.discord.interaction_context_cache_size,
-    ));
+        &config.discord.interaction_context_path,
+    )?);
 
     // We intentionally do not use _output_rx/_attachment_rx, as we don't care about temporary output at the global level
-    let (output_tx, _output_rx) = flume::unbounded::<String>();
+    let (output_tx, _output_rx) = flume::unbounded::<lua::extensions::OutputUpdate>();
     let (print_tx, print_rx) = flume::unbounded::<String>();
     let (attachment_tx, _attachment_rx) = flume::unbounded::<lua::extensions::Attachment>();
 
@@ -66,48 +100,77 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    let global_lua = create_global_lua_state(
-        ai.clone(),
-        currency_converter.clone(),
+    let global_lua = Arc::new(GlobalLuaState::new(
+        services.clone(),
         output_tx,
         print_tx,
         attachment_tx,
-        command_registry.clone(),
-        reply_handler_registry.clone(),
-    )?;
+    )?);
+
+    let execute_state = Arc::new(commands::execute::SharedState::new(
+        config.discord.clone(),
+        config.sandbox.clone(),
+        cancellations.clone(),
+        services,
+    ));
 
     // Build handlers
     let handlers = build_handlers(
         &config,
-        cancel_rx.clone(),
-        ai.clone(),
-        currency_converter.clone(),
+        execute_state.clone(),
         global_lua.clone(),
-        command_registry.clone(),
         interaction_context_store.clone(),
+        cancellations.clone(),
     );
 
     let mut client = Client::builder(
         discord_token,
         GatewayIntents::default()
             | GatewayIntents::GUILD_MESSAGES
+            | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT,
     )
     .event_handler(Handler {
         config: config.clone(),
         handlers: Arc::new(std::sync::Mutex::new(handlers)),
-        cancel_tx,
-        cancel_rx,
+        registered_commands: Arc::default(),
+        cancellations,
         interaction_context_store: interaction_context_store.clone(),
-        reply_handler_registry: reply_handler_registry.clone(),
         global_lua: global_lua.clone(),
-        command_registry: command_registry.clone(),
+        execute_state,
+        script_watcher_started: Arc::default(),
+        model_watcher_started: Arc::default(),
+        rate_limiter: RateLimiter::new(config.rate_limit.clone()),
     })
     .await
     .context("Error creating client")?;
+    history.connect(client.cache.clone(), client.http.clone());
 
-    if let Err(why) = client.start().await {
-        println!("Client error: {why:?}");
+    tokio::select! {
+        result = client.start() => {
+            if let Err(why) = result {
+                println!("Client error: {why:?}");
+            }
+        }
+        result = tokio::signal::ctrl_c() => {
+            result.context("Failed to listen for Ctrl-C")?;
+            println!("Shutting down");
+        }
+    }
+
+    // Save whatever the background flushes haven't got to yet
+    history.index_pending().await;
+    let flushed = tokio::task::spawn_blocking(move || {
+        if let Err(err) = usage.flush() {
+            eprintln!("Failed to save usage records: {err:?}");
+        }
+        if let Err(err) = storage.flush() {
+            eprintln!("Failed to save storage: {err:?}");
+        }
+    })
+    .await;
+    if let Err(err) = flushed {
+        eprintln!("Final flush panicked: {err:?}");
     }
 
     Ok(())
@@ -115,22 +178,14 @@ async fn main() -> anyhow::Result<()> {
 
 fn build_handlers(
     config: &Configuration,
-    cancel_rx: flume::Receiver<MessageId>,
-    ai: Arc<ai::Ai>,
-    currency_converter: Arc<currency::CurrencyConverter>,
-    global_lua: mlua::Lua,
-    command_registry: LuaCommandRegistry,
+    execute_state: Arc<commands::execute::SharedState>,
+    global_lua: Arc<GlobalLuaState>,
     interaction_context_store: Arc<InteractionContextStore>,
+    cancellations: CancellationRegistry,
 ) -> HashMap<String, Arc<dyn commands::CommandHandler>> {
     let mut handlers: HashMap<String, Arc<dyn commands::CommandHandler>> = HashMap::new();
 
     // Add execute commands
-    let execute_state = Arc::new(commands::execute::SharedState::new(
-        config.discord.clone(),
-        cancel_rx.clone(),
-        ai.clone(),
-        currency_converter.clone(),
-    ));
     handlers.insert(
         "execute".to_string(),
         Arc::new(commands::execute::Handler::new(execute_state.clone())),
@@ -139,9 +194,36 @@ fn build_handlers(
         "executemsg".to_string(),
         Arc::new(commands::execute::MsgHandler::new(execute_state)),
     );
+    handlers.insert(
+        constant::commands::USAGE.to_string(),
+        Arc::new(commands::usage::Handler::new(
+            global_lua.services().usage.clone(),
+        )),
+    );
+    let transcribe_state = Arc::new(commands::transcribe::SharedState::new(
+        config.audio.clone(),
+        config.discord.clone(),
+        global_lua.services().ai.clone(),
+        global_lua.services().usage.clone(),
+    ));
+    handlers.insert(
+        constant::commands::TRANSCRIBE.to_string(),
+        Arc::new(commands::transcribe::Handler::new(transcribe_state.clone())),
+    );
+    handlers.insert(
+        constant::commands::TRANSCRIBE_MSG.to_string(),
+        Arc::new(commands::transcribe::MsgHandler::new(transcribe_state)),
+    );
 
     // Add Lua commands from registry
-    let command_names: Vec<String> = command_registry.lock().unwrap().keys().cloned().collect();
+    let command_names: Vec<String> = global_lua
+        .current()
+        .command_registry
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
 
     for name in command_names {
         handlers.insert(
@@ -149,9 +231,9 @@ fn build_handlers(
             Arc::new(commands::lua_command::Handler::new(
                 name,
                 config.discord.clone(),
-                command_registry.clone(),
                 global_lua.clone(),
                 interaction_context_store.clone(),
+                cancellations.clone(),
             )),
         );
     }
@@ -159,20 +241,24 @@ fn build_handlers(
     handlers
 }
 
+#[derive(Clone)]
 pub struct Handler {
     config: Configuration,
     handlers: Arc<std::sync::Mutex<HashMap<String, Arc<dyn commands::CommandHandler>>>>,
-    cancel_tx: flume::Sender<MessageId>,
-    cancel_rx: flume::Receiver<MessageId>,
+    /// The command specs we last registered with Discord, as JSON, used to skip unchanged commands
+    registered_commands: Arc<std::sync::Mutex<HashMap<String, serde_json::Value>>>,
+    cancellations: CancellationRegistry,
     interaction_context_store: Arc<InteractionContextStore>,
-    reply_handler_registry: LuaReplyHandlerRegistry,
-    global_lua: mlua::Lua,
-    command_registry: LuaCommandRegistry,
+    global_lua: Arc<GlobalLuaState>,
+    execute_state: Arc<commands::execute::SharedState>,
+    script_watcher_started: Arc<AtomicBool>,
+    model_watcher_started: Arc<AtomicBool>,
+    rate_limiter: Arc<RateLimiter>,
 }
 #[async_trait]
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
-        self.ready_impl(&ctx.http, ready)
+        self.ready_impl(ctx.http.clone(), ready)
             .await
             .expect("Error while registering commands");
     }
@@ -199,25 +285,211 @@ impl EventHandler for Handler {
             return;
         }
 
+        self.global_lua.services().history.observe(&msg);
+
+        // Every message in a conversation thread continues it, without needing to be a reply
+        if let Some(context) = self.thread_context(msg.channel_id) {
+            if let Err(err) = self
+                .run_reply_handler(ctx.http.clone(), &msg, context)
+                .await
+            {
+                eprintln!("Error handling thread message: {err}");
+            }
+            return;
+        }
+
         // Check if this is a reply to another message
         if let Some(ref msg_ref) = msg.message_reference
             && let Some(referenced_msg_id) = msg_ref.message_id
-            && let Err(err) = self
+        {
+            match self
                 .handle_reply(ctx.http.clone(), &msg, referenced_msg_id)
                 .await
-        {
-            eprintln!("Error handling reply: {err}");
+            {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(err) => {
+                    eprintln!("Error handling reply: {err}");
+                    return;
+                }
+            }
+        }
+
+        // Otherwise, the scripts may respond to being mentioned or sent a DM
+        if let Err(err) = self.handle_trigger(&ctx, &msg).await {
+            eprintln!("Error handling message trigger: {err}");
         }
     }
+
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        self.global_lua
+            .services()
+            .history
+            .forget(channel_id, &[deleted_message_id]);
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        _guild_id: Option<GuildId>,
+    ) {
+        self.global_lua
+            .services()
+            .history
+            .forget(channel_id, &multiple_deleted_messages_ids);
+    }
 }
 impl Handler {
-    async fn ready_impl(&self, http: &Http, ready: Ready) -> anyhow::Result<()> {
+    async fn ready_impl(&self, http: Arc<Http>, ready: Ready) -> anyhow::Result<()> {
         println!("{} is connected; registering commands...", ready.user.name);
-        register_all_commands(http, &self.handlers).await?;
+        sync_commands(
+            &http,
+            &self.handlers,
+            &self.registered_commands,
+            &self.config.permissions,
+        )
+        .await?;
         println!("{} is good to go!", ready.user.name);
+
+        // `ready` fires again on reconnects, so only start the watcher once
+        if self.config.scripts.hot_reload
+            && !self.script_watcher_started.swap(true, Ordering::SeqCst)
+        {
+            tokio::spawn(self.clone().watch_scripts(http.clone()));
+        }
+        if self.config.llm.models_refresh_interval_secs > 0
+            && !self.model_watcher_started.swap(true, Ordering::SeqCst)
+        {
+            tokio::spawn(self.clone().watch_models(http));
+        }
         Ok(())
     }
 
+    /// Periodically refreshes the model list, updating the scripts and re-registering any
+    /// commands whose choices changed as a result
+    async fn watch_models(self, http: Arc<Http>) {
+        let interval = Duration::from_secs(self.config.llm.models_refresh_interval_secs);
+        let ai = self.global_lua.services().ai.clone();
+        loop {
+            tokio::time::sleep(interval).await;
+            if !ai.refresh().await {
+                continue;
+            }
+
+            println!("Models changed; updating scripts and commands...");
+            let result = async {
+                self.global_lua.models_changed()?;
+                sync_commands(
+                    &http,
+                    &self.handlers,
+                    &self.registered_commands,
+                    &self.config.permissions,
+                )
+                .await
+            }
+            .await;
+            match result {
+                Ok(()) => println!("Models updated."),
+                Err(err) => eprintln!("Failed to update for the new models: {err:?}"),
+            }
+        }
+    }
+
+    /// Polls the scripts for changes and reloads them when they change
+    async fn watch_scripts(self, http: Arc<Http>) {
+        let interval = Duration::from_millis(self.config.scripts.hot_reload_poll_interval_ms);
+        let mut last_modified = lua::script_modification_times();
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let modified = lua::script_modification_times();
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            println!("Scripts changed; reloading...");
+            match self.reload_scripts(&http).await {
+                Ok(()) => println!("Scripts reloaded."),
+                Err(err) => eprintln!("Failed to reload scripts, keeping previous state: {err:?}"),
+            }
+        }
+    }
+
+    /// Rebuilds the global Lua state, swaps in the new set of handlers, and
+    /// re-registers any commands that changed
+    async fn reload_scripts(&self, http: &Http) -> anyhow::Result<()> {
+        self.global_lua.reload()?;
+        *self.handlers.lock().unwrap() = build_handlers(
+            &self.config,
+            self.execute_state.clone(),
+            self.global_lua.clone(),
+            self.interaction_context_store.clone(),
+            self.cancellations.clone(),
+        );
+        sync_commands(
+            http,
+            &self.handlers,
+            &self.registered_commands,
+            &self.config.permissions,
+        )
+        .await
+    }
+
+    /// Checks a command's config- and script-declared permission rules
+    fn check_permissions(
+        &self,
+        command_name: &str,
+        subject: &PermissionSubject,
+    ) -> Result<(), Denial> {
+        let script_rules = self
+            .global_lua
+            .current()
+            .command_registry
+            .lock()
+            .unwrap()
+            .get(command_name)
+            .map(|cmd| cmd.permissions.clone());
+
+        for rules in self
+            .config
+            .permissions
+            .rules_for(command_name)
+            .chain(script_rules.as_ref())
+        {
+            rules.check(subject)?;
+        }
+        Ok(())
+    }
+
+    /// Charges the user for running a command, using the cost from the config or the script
+    fn acquire_execution(
+        &self,
+        command_name: &str,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+    ) -> Result<ExecutionPermit, Limited> {
+        let declared_cost = self
+            .global_lua
+            .current()
+            .command_registry
+            .lock()
+            .unwrap()
+            .get(command_name)
+            .and_then(|cmd| cmd.cost);
+        let cost = self.rate_limiter.cost(command_name, declared_cost);
+        self.rate_limiter
+            .acquire(user_id, guild_id, command_name, cost)
+    }
+
     async fn interaction_create_impl(
         &self,
         http: Arc<Http>,
@@ -226,9 +498,40 @@ impl Handler {
         match interaction {
             Interaction::Command(cmd) => {
                 let name = cmd.data.name.as_str();
+                if let Err(denial) =
+                    self.check_permissions(name, &PermissionSubject::from_command(cmd))
+                {
+                    cmd.create_response(
+                        &*http,
+                        CreateInteractionResponse::Message(
+                            CreateInteractionResponseMessage::new()
+                                .content(denial.message(name))
+                                .ephemeral(true),
+                        ),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
                 let handler = self.handlers.lock().unwrap().get(name).cloned();
 
                 if let Some(handler) = handler {
+                    let _permit = match self.acquire_execution(name, cmd.user.id, cmd.guild_id) {
+                        Ok(permit) => permit,
+                        Err(limited) => {
+                            cmd.create_response(
+                                &*http,
+                                CreateInteractionResponse::Message(
+                                    CreateInteractionResponseMessage::new()
+                                        .content(limited.message())
+                                        .ephemeral(true),
+                                ),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                    };
+
                     handler.run(http.clone(), cmd).await?;
                 } else {
                     anyhow::bail!("no handler found for command: {name}");
@@ -240,7 +543,10 @@ impl Handler {
                         return Ok(());
                     }
 
-                    self.cancel_tx.send(message_id).ok();
+                    let cancellation = self.cancellations.lock().unwrap().get(&message_id).cloned();
+                    if let Some(cancellation) = cancellation {
+                        cancellation.cancel();
+                    }
                     cmp.create_response(
                         &*http,
                         CreateInteractionResponse::UpdateMessage(
@@ -260,6 +566,8 @@ impl Handler {
 
                     // Look up the command and option to get suggestions
                     let suggestions = self
+                        .global_lua
+                        .current()
                         .command_registry
                         .lock()
                         .unwrap()
@@ -292,17 +600,23 @@ impl Handler {
         Ok(())
     }
 
+    /// The context of the conversation held in `channel_id`, if it's a thread one of our
+    /// commands opened. A thread opened on a message shares its ID, so the context is the one
+    /// stored for the response the thread was opened on.
+    fn thread_context(&self, channel_id: ChannelId) -> Option<InteractionContext> {
+        self.interaction_context_store
+            .get(&MessageId::new(channel_id.get()))
+            .filter(|context| context.thread_id == Some(channel_id))
+    }
+
+    /// Continues the conversation the reply belongs to, returning whether it belonged to one
     async fn handle_reply(
         &self,
         http: Arc<Http>,
         user_msg: &Message,
         referenced_msg_id: MessageId,
-    ) -> anyhow::Result<()> {
-        use crate::{
-            lua::extensions::{Attachment, TemporaryChannelUpdate},
-            lua::{LuaOutputChannels, execute_lua_reply_thread},
-            reply_handler::{LuaReplyChain, ReplyChain, build_message_chain},
-        };
+    ) -> anyhow::Result<bool> {
+        use crate::reply_handler::{MAX_REPLY_CHAIN_DEPTH, build_message_chain};
 
         // First, check if the referenced message is in our interaction context store
         let context = match self.interaction_context_store.get(&referenced_msg_id) {
@@ -315,7 +629,8 @@ impl Handler {
                     .await?;
 
                 // Build the chain and look for any cached context
-                let chain = build_message_chain(&http, &referenced_msg, 50).await?;
+                let chain =
+                    build_message_chain(&http, &referenced_msg, MAX_REPLY_CHAIN_DEPTH).await?;
 
                 // Find a bot message with cached context
                 let mut found_context = None;
@@ -332,14 +647,43 @@ impl Handler {
                     Some(ctx) => ctx,
                     None => {
                         // No cached context found - ignore this reply
-                        return Ok(());
+                        return Ok(false);
                     }
                 }
             }
         };
 
+        self.run_reply_handler(http, user_msg, context).await?;
+        Ok(true)
+    }
+
+    /// Runs the reply handler of the command that started the conversation `user_msg`
+    /// continues
+    async fn run_reply_handler(
+        &self,
+        http: Arc<Http>,
+        user_msg: &Message,
+        context: InteractionContext,
+    ) -> anyhow::Result<()> {
+        use crate::reply_handler::{
+            LuaReplyChain, MAX_REPLY_CHAIN_DEPTH, MAX_THREAD_MESSAGES, ReplyChain,
+            build_message_chain, build_thread_chain,
+        };
+
+        // Replies run the command's handler again, so they're subject to the same rules
+        if self
+            .check_permissions(
+                &context.command_name,
+                &PermissionSubject::from_message(user_msg),
+            )
+            .is_err()
+        {
+            return Ok(());
+        }
+
         // Check if there's a handler for this command
-        let handler = self
+        let scripts = self.global_lua.current();
+        let handler = scripts
             .reply_handler_registry
             .lock()
             .unwrap()
@@ -351,8 +695,30 @@ impl Handler {
             return Ok(());
         };
 
-        // Build the full message chain
-        let chain = build_message_chain(&http, user_msg, 50).await?;
+        let _permit = match self.acquire_execution(
+            &context.command_name,
+            user_msg.author.id,
+            user_msg.guild_id,
+        ) {
+            Ok(permit) => permit,
+            Err(limited) => {
+                user_msg.reply(&http, limited.message()).await?;
+                return Ok(());
+            }
+        };
+
+        // Build the full message chain. A conversation in a thread is the thread itself,
+        // rather than whatever the messages happen to reply to.
+        let chain = if context.thread_id == Some(user_msg.channel_id) {
+            let starter = context
+                .channel_id
+                .message(&http, MessageId::new(user_msg.channel_id.get()))
+                .await
+                .ok();
+            build_thread_chain(&http, starter.as_ref(), user_msg, MAX_THREAD_MESSAGES).await?
+        } else {
+            build_message_chain(&http, user_msg, MAX_REPLY_CHAIN_DEPTH).await?
+        };
 
         // Create the ReplyChain
         let reply_chain = ReplyChain {
@@ -361,25 +727,127 @@ impl Handler {
             messages: chain,
         };
 
+        // Build the Lua table for the reply chain using serde
+        let chain_table = scripts.lua.to_value(&LuaReplyChain::from(&reply_chain))?;
+        let response_msg_id = self
+            .execute_message_handler(
+                http,
+                user_msg,
+                &scripts.lua,
+                handler,
+                chain_table,
+                context.command_name.clone(),
+            )
+            .await?;
+
+        // Store the context for the new response message so the chain can continue
+        self.interaction_context_store
+            .store(response_msg_id, context);
+
+        Ok(())
+    }
+
+    /// Runs the scripts' `discord.on_dm` or `discord.on_mention` handler, if the message is a
+    /// DM or mentions the bot and the scripts registered one
+    async fn handle_trigger(&self, ctx: &Context, user_msg: &Message) -> anyhow::Result<()> {
+        use crate::reply_handler::{LuaMessageTrigger, MAX_REPLY_CHAIN_DEPTH, build_message_chain};
+
+        let bot_id = ctx.cache.current_user().id;
+        let scripts = self.global_lua.current();
+        let (trigger_name, handler) = {
+            let triggers = scripts.trigger_registry.lock().unwrap();
+            if user_msg.guild_id.is_none() {
+                (constant::triggers::DM, triggers.dm.clone())
+            } else if user_msg.mentions_user_id(bot_id) {
+                (constant::triggers::MENTION, triggers.mention.clone())
+            } else {
+                return Ok(());
+            }
+        };
+        let Some(handler) = handler else {
+            return Ok(());
+        };
+
+        // Triggers are permitted and rate limited like commands of the same name
+        if self
+            .check_permissions(trigger_name, &PermissionSubject::from_message(user_msg))
+            .is_err()
+        {
+            return Ok(());
+        }
+        let _permit =
+            match self.acquire_execution(trigger_name, user_msg.author.id, user_msg.guild_id) {
+                Ok(permit) => permit,
+                Err(limited) => {
+                    user_msg.reply(&ctx.http, limited.message()).await?;
+                    return Ok(());
+                }
+            };
+
+        // Replying to the bot's last response keeps the conversation going
+        let chain = build_message_chain(&ctx.http, user_msg, MAX_REPLY_CHAIN_DEPTH).await?;
+        let channel_name = ctx
+            .cache
+            .channel(user_msg.channel_id)
+            .map(|c| c.name.clone());
+        let guild_name = user_msg.guild(&ctx.cache).map(|g| g.name.clone());
+        let trigger = LuaMessageTrigger::new(user_msg, bot_id, &chain, channel_name, guild_name);
+
+        let trigger_table = scripts.lua.to_value(&trigger)?;
+        self.execute_message_handler(
+            ctx.http.clone(),
+            user_msg,
+            &scripts.lua,
+            handler,
+            trigger_table,
+            trigger_name.to_string(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Runs a handler from the scripts with `args`, responding to `user_msg`, and returns the
+    /// ID of the response. The execution is accounted to `command_name`.
+    async fn execute_message_handler(
+        &self,
+        http: Arc<Http>,
+        user_msg: &Message,
+        lua: &mlua::Lua,
+        handler: mlua::Function,
+        args: mlua::Value,
+        command_name: String,
+    ) -> anyhow::Result<MessageId> {
+        use crate::{
+            cancel::Cancellation,
+            lua::extensions::{Attachment, Invoker, OutputUpdate, TemporaryChannelUpdate},
+            lua::{Accounting, LuaOutputChannels, execute_lua_reply_thread},
+        };
+
         // Create output channels for this execution
-        let (output_tx, output_rx) = flume::unbounded::<String>();
+        let (output_tx, output_rx) = flume::unbounded::<OutputUpdate>();
         let (print_tx, print_rx) = flume::unbounded::<String>();
         let (attachment_tx, attachment_rx) = flume::unbounded::<Attachment>();
-
-        // Build the Lua table for the reply chain using serde
-        let lua = &self.global_lua;
-        let chain_table = lua.to_value(&LuaReplyChain::from(&reply_chain))?;
+        let cancellation = Cancellation::new();
 
         // Create the thread and register channels
         let thread = lua.create_thread(handler)?;
-        let _temporary_channel_update =
-            TemporaryChannelUpdate::new(lua.clone(), &thread, output_tx, print_tx, attachment_tx)?;
-
-        let thread = thread.into_async::<Option<String>>(chain_table)?;
+        let invoker = Invoker::from_message(user_msg, command_name);
+        let _temporary_channel_update = TemporaryChannelUpdate::new(
+            lua.clone(),
+            &thread,
+            output_tx,
+            print_tx,
+            attachment_tx,
+            cancellation.clone(),
+            Some(invoker.clone()),
+        )?;
+
+        let thread = thread.into_async::<Option<String>>(args)?;
 
         // Execute and get the response message ID
-        let response_msg_id = execute_lua_reply_thread(
-            http.clone(),
+        execute_lua_reply_thread(
+            http,
             user_msg,
             user_msg.author.id,
             &self.config.discord,
@@ -388,38 +856,66 @@ impl Handler {
                 output_rx,
                 print_rx,
                 attachment_rx,
+                cancellation,
+                accounting: Accounting {
+                    tracker: self.global_lua.services().usage.clone(),
+                    invoker,
+                },
             },
-            Some(self.cancel_rx.clone()),
+            &self.cancellations,
         )
-        .await?;
-
-        // Store the context for the new response message so the chain can continue
-        self.interaction_context_store
-            .store(response_msg_id, context);
-
-        Ok(())
+        .await
     }
 }
 
-/// Registers all commands with Discord, clearing existing commands if they differ
-async fn register_all_commands(
+/// Brings Discord's global commands in line with `handlers`. Commands Discord has that we don't
+/// are deleted, and ours are only (re-)created if they differ from what we last registered.
+async fn sync_commands(
     http: &Http,
     handlers: &Arc<std::sync::Mutex<HashMap<String, Arc<dyn commands::CommandHandler>>>>,
+    registered_commands: &std::sync::Mutex<HashMap<String, serde_json::Value>>,
+    permissions: &config::CommandPermissions,
 ) -> anyhow::Result<()> {
-    // Check if we need to reset our registered commands
-    let registered_commands: HashSet<_> = {
-        let cmds = Command::get_global_commands(http).await?;
-        cmds.iter().map(|c| c.name.clone()).collect()
-    };
-    let our_commands: HashSet<_> = handlers.lock().unwrap().keys().cloned().collect();
-    if registered_commands != our_commands {
-        Command::set_global_commands(http, vec![]).await?;
+    // Collect specs to avoid holding lock across await
+    let mut specs: HashMap<String, CreateCommand> = handlers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(name, handler)| Some((name.clone(), handler.create_command()?)))
+        .collect();
+
+    // Config-declared member permissions take precedence over the script's
+    for (name, spec) in specs.iter_mut() {
+        for rules in permissions.rules_for(name) {
+            if let Some(member_permissions) = rules
+                .member_permissions()
+                .with_context(|| format!("invalid permissions for command `{name}`"))?
+            {
+                *spec = spec.clone().default_member_permissions(member_permissions);
+            }
+        }
     }
 
-    // Collect handlers to avoid holding lock across await
-    let handlers_vec: Vec<_> = handlers.lock().unwrap().values().cloned().collect();
-    for handler in handlers_vec {
-        handler.register(http).await?;
+    let mut discord_commands = HashSet::new();
+    for command in Command::get_global_commands(http).await? {
+        if specs.contains_key(&command.name) {
+            discord_commands.insert(command.name);
+        } else {
+            Command::delete_global_command(http, command.id).await?;
+            registered_commands.lock().unwrap().remove(&command.name);
+        }
+    }
+
+    for (name, spec) in specs {
+        let json = serde_json::to_value(&spec)?;
+        let unchanged = discord_commands.contains(&name)
+            && registered_commands.lock().unwrap().get(&name) == Some(&json);
+        if unchanged {
+            continue;
+        }
+
+        Command::create_global_command(http, spec).await?;
+        registered_commands.lock().unwrap().insert(name, json);
     }
 
     Ok(())
@@ -2,16 +2,18 @@ use std::sync::Arc;
 
 use serenity::all::{
     CommandInteraction, CreateAllowedMentions, CreateAttachment, CreateInteractionResponse,
-    CreateInteractionResponseMessage, CreateMessage, EditMessage, Http, Message, MessageId, UserId,
+    CreateInteractionResponseMessage, CreateMessage, EditMessage, Http, Message, MessageFlags,
+    MessageId, UserId,
 };
 use tokio::sync::oneshot;
 
-use crate::lua::extensions::Attachment;
+use crate::{lua::extensions::Attachment, voice_message::VoiceMetadata};
 
 /// Commands that can be sent to the outputter task
 enum OutputterCommand {
     Update(String),
     AddAttachment(Attachment),
+    AddVoiceMessage(Attachment),
     SetPreview(Attachment),
     Error(String),
     Cancelled,
@@ -56,6 +58,7 @@ impl OutputterHandle {
                 messages: vec![starting_message],
                 chunks: vec![],
                 pending_attachments: vec![],
+                pending_voice_messages: vec![],
                 live_preview: None,
                 live_preview_dirty: false,
                 in_terminal_state: false,
@@ -109,6 +112,7 @@ impl OutputterHandle {
                 messages: vec![starting_message],
                 chunks: vec![],
                 pending_attachments: vec![],
+                pending_voice_messages: vec![],
                 live_preview: None,
                 live_preview_dirty: false,
                 in_terminal_state: false,
@@ -144,6 +148,10 @@ impl OutputterHandle {
         let _ = self.tx.send(OutputterCommand::AddAttachment(attachment));
     }
 
+    pub fn add_voice_message(&self, attachment: Attachment) {
+        let _ = self.tx.send(OutputterCommand::AddVoiceMessage(attachment));
+    }
+
     pub fn set_preview(&self, attachment: Attachment) {
         let _ = self.tx.send(OutputterCommand::SetPreview(attachment));
     }
@@ -175,6 +183,8 @@ struct Outputter {
     messages: Vec<Message>,
     chunks: Vec<String>,
     pending_attachments: Vec<CreateAttachment>,
+    /// Sent as separate messages at finish, as voice messages can't have any content
+    pending_voice_messages: Vec<Attachment>,
 
     /// Latest live-preview image (e.g. an in-progress render). Shown on the
     /// last message during streaming and superseded by any final attachments.
@@ -211,6 +221,9 @@ impl Outputter {
                         Some(OutputterCommand::AddAttachment(attachment)) => {
                             self.add_attachment(attachment);
                         }
+                        Some(OutputterCommand::AddVoiceMessage(attachment)) => {
+                            self.pending_voice_messages.push(attachment);
+                        }
                         Some(OutputterCommand::SetPreview(attachment)) => {
                             self.set_preview(attachment).await?;
                         }
@@ -292,6 +305,12 @@ impl Outputter {
             last.edit(&self.http, edit).await?;
         }
 
+        if let Some(last) = self.messages.last() {
+            for attachment in self.pending_voice_messages.drain(..) {
+                send_voice_message(&self.http, last, attachment).await?;
+            }
+        }
+
         Ok(())
     }
 
@@ -399,3 +418,51 @@ async fn reply_to_message_without_mentions(
         )
         .await?)
 }
+
+/// Sends Ogg Opus audio as a voice message replying to `msg`. Serenity has no way to set the
+/// voice message fields, so the payload is built by hand. Audio that can't be read is sent as
+/// an ordinary attachment instead.
+async fn send_voice_message(
+    http: &Http,
+    msg: &Message,
+    attachment: Attachment,
+) -> anyhow::Result<()> {
+    let metadata = match VoiceMetadata::from_ogg_opus(&attachment.data) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            eprintln!("Sending `{}` as a file: {err:?}", attachment.filename);
+            msg.channel_id
+                .send_message(
+                    http,
+                    CreateMessage::new()
+                        .reference_message(msg)
+                        .add_file(CreateAttachment::bytes(
+                            attachment.data,
+                            attachment.filename,
+                        ))
+                        .allowed_mentions(CreateAllowedMentions::new()),
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let payload = serde_json::json!({
+        "flags": MessageFlags::IS_VOICE_MESSAGE.bits(),
+        "attachments": [{
+            "id": 0,
+            "filename": attachment.filename,
+            "duration_secs": metadata.duration_secs,
+            "waveform": data_encoding::BASE64.encode(&metadata.waveform),
+        }],
+        "message_reference": {
+            "message_id": msg.id,
+            "channel_id": msg.channel_id,
+        },
+        "allowed_mentions": { "parse": [] },
+    });
+    let file = CreateAttachment::bytes(attachment.data, attachment.filename);
+    http.send_message(msg.channel_id, vec![file], &payload)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+use serenity::all::{ChannelId, CommandInteraction, GuildId, Message, Permissions, RoleId, UserId};
+
+/// Allow/deny lists restricting who can run a command, and where.
+///
+/// Deny lists always win. A non-empty allow list means only what it lists is allowed; users
+/// and roles are both identity checks, so matching either allow list is enough.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct PermissionRules {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_users: Vec<UserId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deny_users: Vec<UserId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_roles: Vec<RoleId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deny_roles: Vec<RoleId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_guilds: Vec<GuildId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deny_guilds: Vec<GuildId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_channels: Vec<ChannelId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deny_channels: Vec<ChannelId>,
+    /// Discord permissions a member needs for the command to be shown to them by default
+    /// (e.g. `["MANAGE_GUILD"]`). Server admins can override this in the integration settings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_member_permissions: Option<Vec<String>>,
+}
+
+/// Who is trying to run a command, and where
+pub struct PermissionSubject {
+    pub user_id: UserId,
+    pub role_ids: Vec<RoleId>,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: ChannelId,
+}
+impl PermissionSubject {
+    pub fn from_command(cmd: &CommandInteraction) -> Self {
+        Self {
+            user_id: cmd.user.id,
+            role_ids: cmd
+                .member
+                .as_ref()
+                .map(|m| m.roles.clone())
+                .unwrap_or_default(),
+            guild_id: cmd.guild_id,
+            channel_id: cmd.channel_id,
+        }
+    }
+
+    pub fn from_message(msg: &Message) -> Self {
+        Self {
+            user_id: msg.author.id,
+            role_ids: msg
+                .member
+                .as_ref()
+                .map(|m| m.roles.clone())
+                .unwrap_or_default(),
+            guild_id: msg.guild_id,
+            channel_id: msg.channel_id,
+        }
+    }
+}
+
+/// Why a subject was denied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    User,
+    Guild,
+    Channel,
+}
+impl Denial {
+    /// A user-facing explanation of the denial
+    pub fn message(self, command_name: &str) -> String {
+        match self {
+            Denial::User => format!("You don't have permission to use `/{command_name}`."),
+            Denial::Guild => format!("`/{command_name}` can't be used in this server."),
+            Denial::Channel => format!("`/{command_name}` can't be used in this channel."),
+        }
+    }
+}
+
+impl PermissionRules {
+    pub fn check(&self, subject: &PermissionSubject) -> Result<(), Denial> {
+        if self.deny_users.contains(&subject.user_id)
+            || subject.role_ids.iter().any(|r| self.deny_roles.contains(r))
+        {
+            return Err(Denial::User);
+        }
+        if !self.allow_users.is_empty() || !self.allow_roles.is_empty() {
+            let allowed = self.allow_users.contains(&subject.user_id)
+                || subject
+                    .role_ids
+                    .iter()
+                    .any(|r| self.allow_roles.contains(r));
+            if !allowed {
+                return Err(Denial::User);
+            }
+        }
+
+        if subject
+            .guild_id
+            .is_some_and(|g| self.deny_guilds.contains(&g))
+            || (!self.allow_guilds.is_empty()
+                && !subject
+                    .guild_id
+                    .is_some_and(|g| self.allow_guilds.contains(&g)))
+        {
+            return Err(Denial::Guild);
+        }
+
+        if self.deny_channels.contains(&subject.channel_id)
+            || (!self.allow_channels.is_empty()
+                && !self.allow_channels.contains(&subject.channel_id))
+        {
+            return Err(Denial::Channel);
+        }
+
+        Ok(())
+    }
+
+    /// Parses `default_member_permissions`, if set
+    pub fn member_permissions(&self) -> anyhow::Result<Option<Permissions>> {
+        self.default_member_permissions
+            .as_ref()
+            .map(|names| {
+                names.iter().try_fold(Permissions::empty(), |acc, name| {
+                    Permissions::from_name(&name.to_uppercase())
+                        .map(|p| acc | p)
+                        .ok_or_else(|| anyhow::anyhow!("unknown Discord permission `{name}`"))
+                })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject(user: u64, roles: &[u64], guild: Option<u64>, channel: u64) -> PermissionSubject {
+        PermissionSubject {
+            user_id: UserId::new(user),
+            role_ids: roles.iter().map(|r| RoleId::new(*r)).collect(),
+            guild_id: guild.map(GuildId::new),
+            channel_id: ChannelId::new(channel),
+        }
+    }
+
+    #[test]
+    fn test_empty_rules_allow_everyone() {
+        let rules = PermissionRules::default();
+        assert_eq!(rules.check(&subject(1, &[], None, 1)), Ok(()));
+        assert_eq!(rules.check(&subject(2, &[3], Some(4), 5)), Ok(()));
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let rules = PermissionRules {
+            allow_users: vec![UserId::new(1)],
+            deny_roles: vec![RoleId::new(10)],
+            ..Default::default()
+        };
+        assert_eq!(rules.check(&subject(1, &[], None, 1)), Ok(()));
+        assert_eq!(rules.check(&subject(1, &[10], None, 1)), Err(Denial::User));
+    }
+
+    #[test]
+    fn test_allow_users_or_roles() {
+        let rules = PermissionRules {
+            allow_users: vec![UserId::new(1)],
+            allow_roles: vec![RoleId::new(10)],
+            ..Default::default()
+        };
+        assert_eq!(rules.check(&subject(1, &[], None, 1)), Ok(()));
+        assert_eq!(rules.check(&subject(2, &[10], None, 1)), Ok(()));
+        assert_eq!(rules.check(&subject(2, &[11], None, 1)), Err(Denial::User));
+    }
+
+    #[test]
+    fn test_allow_guilds_excludes_dms() {
+        let rules = PermissionRules {
+            allow_guilds: vec![GuildId::new(100)],
+            ..Default::default()
+        };
+        assert_eq!(rules.check(&subject(1, &[], Some(100), 1)), Ok(()));
+        assert_eq!(
+            rules.check(&subject(1, &[], Some(101), 1)),
+            Err(Denial::Guild)
+        );
+        assert_eq!(rules.check(&subject(1, &[], None, 1)), Err(Denial::Guild));
+    }
+
+    #[test]
+    fn test_channel_rules() {
+        let rules = PermissionRules {
+            allow_channels: vec![ChannelId::new(5), ChannelId::new(6)],
+            deny_channels: vec![ChannelId::new(6)],
+            ..Default::default()
+        };
+        assert_eq!(rules.check(&subject(1, &[], None, 5)), Ok(()));
+        assert_eq!(rules.check(&subject(1, &[], None, 6)), Err(Denial::Channel));
+        assert_eq!(rules.check(&subject(1, &[], None, 7)), Err(Denial::Channel));
+    }
+
+    #[test]
+    fn test_member_permissions() {
+        let rules = PermissionRules {
+            default_member_permissions: Some(vec![
+                "MANAGE_GUILD".to_string(),
+                "kick_members".to_string(),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.member_permissions().unwrap(),
+            Some(Permissions::MANAGE_GUILD | Permissions::KICK_MEMBERS)
+        );
+
+        let rules = PermissionRules {
+            default_member_permissions: Some(vec!["NOT_A_PERMISSION".to_string()]),
+            ..Default::default()
+        };
+        assert!(rules.member_permissions().is_err());
+        assert_eq!(
+            PermissionRules::default().member_permissions().unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serenity::all::{GuildId, UserId};
+
+use crate::config;
+
+/// Why an execution was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limited {
+    /// One of the token buckets is empty; it will have enough tokens after this long
+    RetryAfter(Duration),
+    /// One of the token buckets is empty and doesn't refill
+    Exhausted,
+    /// The user already has the maximum number of executions running
+    TooManyConcurrent(usize),
+}
+impl Limited {
+    /// A user-facing explanation of the limit
+    pub fn message(self) -> String {
+        match self {
+            Limited::RetryAfter(duration) => format!(
+                "You're doing that too often; try again in {}s.",
+                duration.as_secs_f64().ceil().max(1.0) as u64
+            ),
+            Limited::Exhausted => "You've used up your allowance for that.".to_string(),
+            Limited::TooManyConcurrent(max) => format!(
+                "You already have {max} command(s) running; wait for one to finish and try again."
+            ),
+        }
+    }
+}
+
+/// How often buckets that have refilled to capacity are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token-bucket rate limiting per user, guild and command, plus a cap on how many
+/// executions each user can have running at once.
+pub struct RateLimiter {
+    config: config::RateLimit,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashMap<UserId, TokenBucket>,
+    guilds: HashMap<GuildId, TokenBucket>,
+    commands: HashMap<String, TokenBucket>,
+    running: HashMap<UserId, usize>,
+    last_sweep: Option<Instant>,
+}
+impl State {
+    /// Drops buckets that have refilled to capacity, as they're no different from new ones
+    fn sweep(&mut self, now: Instant) {
+        if self
+            .last_sweep
+            .is_some_and(|last_sweep| now.saturating_duration_since(last_sweep) < SWEEP_INTERVAL)
+        {
+            return;
+        }
+        self.last_sweep = Some(now);
+        sweep_buckets(&mut self.users, now);
+        sweep_buckets(&mut self.guilds, now);
+        sweep_buckets(&mut self.commands, now);
+    }
+}
+
+fn sweep_buckets<K>(buckets: &mut HashMap<K, TokenBucket>, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(now);
+        bucket.tokens < bucket.capacity
+    });
+}
+
+impl RateLimiter {
+    pub fn new(config: config::RateLimit) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            state: Mutex::default(),
+        })
+    }
+
+    /// The cost of running the given command: the configured cost if there is one,
+    /// otherwise the cost declared by the command itself
+    pub fn cost(&self, command_name: &str, declared_cost: Option<f64>) -> f64 {
+        self.config
+            .costs
+            .get(command_name)
+            .copied()
+            .or(declared_cost)
+            .unwrap_or(1.0)
+    }
+
+    /// Takes `cost` tokens from each of the relevant buckets and registers a running execution
+    /// for the user, or explains why it can't. Nothing is taken unless all checks pass.
+    pub fn acquire(
+        self: &Arc<Self>,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        command_name: &str,
+        cost: f64,
+    ) -> Result<ExecutionPermit, Limited> {
+        self.acquire_at(Instant::now(), user_id, guild_id, command_name, cost)
+    }
+
+    fn acquire_at(
+        self: &Arc<Self>,
+        now: Instant,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        command_name: &str,
+        cost: f64,
+    ) -> Result<ExecutionPermit, Limited> {
+        let config = &self.config;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.sweep(now);
+
+        let max_concurrent = config.max_concurrent_per_user;
+        if max_concurrent > 0 && state.running.get(&user_id).copied().unwrap_or(0) >= max_concurrent
+        {
+            return Err(Limited::TooManyConcurrent(max_concurrent));
+        }
+
+        let mut buckets = vec![];
+        if let Some(bucket) = bucket(&mut state.users, user_id, &config.user, now) {
+            buckets.push(bucket);
+        }
+        if let Some(guild_id) = guild_id
+            && let Some(bucket) = bucket(&mut state.guilds, guild_id, &config.guild, now)
+        {
+            buckets.push(bucket);
+        }
+        if let Some(bucket) = bucket(
+            &mut state.commands,
+            command_name.to_string(),
+            &config.command,
+            now,
+        ) {
+            buckets.push(bucket);
+        }
+
+        match buckets.iter().filter_map(|b| b.wait_time(cost)).max() {
+            Some(Wait::For(duration)) => return Err(Limited::RetryAfter(duration)),
+            Some(Wait::Forever) => return Err(Limited::Exhausted),
+            None => {}
+        }
+        for bucket in buckets {
+            bucket.take(cost);
+        }
+
+        *state.running.entry(user_id).or_default() += 1;
+        Ok(ExecutionPermit {
+            limiter: self.clone(),
+            user_id,
+        })
+    }
+}
+
+/// Looks up (creating if necessary) and refills the bucket for `key`, if that bucket is enabled
+fn bucket<'a, K: Eq + Hash>(
+    buckets: &'a mut HashMap<K, TokenBucket>,
+    key: K,
+    config: &config::Bucket,
+    now: Instant,
+) -> Option<&'a mut TokenBucket> {
+    if config.capacity <= 0.0 {
+        return None;
+    }
+    let bucket = buckets.entry(key).or_insert_with(|| TokenBucket {
+        capacity: config.capacity,
+        refill_per_second: config.refill_per_second,
+        tokens: config.capacity,
+        last_refill: now,
+    });
+    bucket.refill(now);
+    Some(bucket)
+}
+
+/// Marks an execution as running for as long as it's alive
+pub struct ExecutionPermit {
+    limiter: Arc<RateLimiter>,
+    user_id: UserId,
+}
+impl Drop for ExecutionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(running) = state.running.get_mut(&self.user_id) {
+            *running = running.saturating_sub(1);
+            if *running == 0 {
+                state.running.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+/// How long a bucket needs to refill enough for a cost
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Wait {
+    For(Duration),
+    /// The bucket doesn't refill
+    Forever,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// How long until the bucket can afford `cost`, or `None` if it already can.
+    /// Costs above the capacity only require a full bucket.
+    fn wait_time(&self, cost: f64) -> Option<Wait> {
+        let cost = cost.min(self.capacity);
+        if self.tokens >= cost {
+            return None;
+        }
+        if self.refill_per_second <= 0.0 {
+            return Some(Wait::Forever);
+        }
+        Some(
+            Duration::try_from_secs_f64((cost - self.tokens) / self.refill_per_second)
+                .map_or(Wait::Forever, Wait::For),
+        )
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens = (self.tokens - cost.min(self.capacity)).max(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(user: (f64, f64), command: (f64, f64), max_concurrent: usize) -> Arc<RateLimiter> {
+        RateLimiter::new(config::RateLimit {
+            user: config::Bucket {
+                capacity: user.0,
+                refill_per_second: user.1,
+            },
+            guild: config::Bucket::default(),
+            command: config::Bucket {
+                capacity: command.0,
+                refill_per_second: command.1,
+            },
+            max_concurrent_per_user: max_concurrent,
+            costs: HashMap::from([("paint".to_string(), 5.0)]),
+        })
+    }
+
+    #[test]
+    fn test_user_bucket_refills() {
+        let limiter = limiter((2.0, 1.0), (0.0, 0.0), 0);
+        let user = UserId::new(1);
+        let now = Instant::now();
+
+        let _a = limiter.acquire_at(now, user, None, "ask", 1.0).unwrap();
+        let _b = limiter.acquire_at(now, user, None, "ask", 1.0).unwrap();
+        let Err(Limited::RetryAfter(wait)) = limiter.acquire_at(now, user, None, "ask", 1.0) else {
+            panic!("expected to be rate limited");
+        };
+        assert_eq!(wait, Duration::from_secs(1));
+
+        // Other users have their own bucket
+        assert!(
+            limiter
+                .acquire_at(now, UserId::new(2), None, "ask", 1.0)
+                .is_ok()
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.acquire_at(later, user, None, "ask", 1.0).is_ok());
+    }
+
+    #[test]
+    fn test_limited_requests_take_nothing() {
+        let limiter = limiter((10.0, 1.0), (1.0, 1.0), 0);
+        let user = UserId::new(1);
+        let now = Instant::now();
+
+        let _a = limiter.acquire_at(now, user, None, "ask", 1.0).unwrap();
+        // The command bucket is empty, so the user bucket must not be charged either
+        for _ in 0..5 {
+            assert!(limiter.acquire_at(now, user, None, "ask", 1.0).is_err());
+        }
+        assert!(limiter.acquire_at(now, user, None, "paint", 9.0).is_ok());
+    }
+
+    #[test]
+    fn test_max_concurrent_per_user() {
+        let limiter = limiter((0.0, 0.0), (0.0, 0.0), 1);
+        let user = UserId::new(1);
+        let now = Instant::now();
+
+        let permit = limiter.acquire_at(now, user, None, "ask", 1.0).unwrap();
+        assert_eq!(
+            limiter.acquire_at(now, user, None, "ask", 1.0).err(),
+            Some(Limited::TooManyConcurrent(1))
+        );
+        drop(permit);
+        assert!(limiter.acquire_at(now, user, None, "ask", 1.0).is_ok());
+    }
+
+    #[test]
+    fn test_bucket_without_refill() {
+        let limiter = limiter((1.0, 0.0), (0.0, 0.0), 0);
+        let user = UserId::new(1);
+        let now = Instant::now();
+
+        let _a = limiter.acquire_at(now, user, None, "ask", 1.0).unwrap();
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(
+            limiter.acquire_at(later, user, None, "ask", 1.0).err(),
+            Some(Limited::Exhausted)
+        );
+    }
+
+    #[test]
+    fn test_full_buckets_are_swept() {
+        let limiter = limiter((2.0, 1.0), (0.0, 0.0), 0);
+        let now = Instant::now();
+
+        let _a = limiter
+            .acquire_at(now, UserId::new(1), None, "ask", 1.0)
+            .unwrap();
+        let _b = limiter
+            .acquire_at(now, UserId::new(2), None, "ask", 2.0)
+            .unwrap();
+        assert_eq!(limiter.state.lock().unwrap().users.len(), 2);
+
+        // By the next sweep both buckets are full again, so only the new user's remains
+        let later = now + SWEEP_INTERVAL;
+        let _c = limiter
+            .acquire_at(later, UserId::new(3), None, "ask", 1.0)
+            .unwrap();
+        let users = &limiter.state.lock().unwrap().users;
+        assert_eq!(users.keys().collect::<Vec<_>>(), [&UserId::new(3)]);
+    }
+
+    #[test]
+    fn test_cost() {
+        let limiter = limiter((0.0, 0.0), (0.0, 0.0), 0);
+        assert_eq!(limiter.cost("paint", Some(2.0)), 5.0);
+        assert_eq!(limiter.cost("ask", Some(2.0)), 2.0);
+        assert_eq!(limiter.cost("ask", None), 1.0);
+    }
+}
@@ -1,10 +1,19 @@
 use std::collections::HashMap;
 
 use serde::Serialize;
-use serenity::all::{ChannelId, GuildId, Http, Message, MessageId, UserId};
+use serenity::all::{
+    ChannelId, GetMessages, GuildId, Http, Message, MessageId, MessageType, UserId,
+};
 
 use crate::interaction_context::OptionValue;
 
+/// How many messages back a reply chain is followed
+pub const MAX_REPLY_CHAIN_DEPTH: usize = 50;
+/// How many of a thread's most recent messages make up its conversation
+pub const MAX_THREAD_MESSAGES: usize = 200;
+/// The most messages Discord returns per request
+const MESSAGES_PER_PAGE: usize = 100;
+
 /// A message in the conversation chain
 #[derive(Clone, Debug)]
 pub struct ChainMessage {
@@ -100,6 +109,88 @@ impl From<&ReplyChain> for LuaReplyChain {
     }
 }
 
+/// What a `discord.on_mention` or `discord.on_dm` handler is called with
+#[derive(Serialize)]
+pub struct LuaMessageTrigger {
+    pub message: LuaChainMessage,
+    /// The message's content, without any mentions of the bot
+    pub text: String,
+    pub author: LuaAuthor,
+    pub channel: LuaChannel,
+    /// Absent for DMs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guild: Option<LuaGuild>,
+    /// The chain of replies the message ends, from oldest to newest
+    pub messages: Vec<LuaChainMessage>,
+}
+
+#[derive(Serialize)]
+pub struct LuaAuthor {
+    pub id: String,
+    pub name: String,
+    /// The author's nickname in the server, or their display name
+    pub display_name: String,
+}
+
+/// A channel; its name is only known if it's a cached server channel
+#[derive(Serialize)]
+pub struct LuaChannel {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A server; its name is only known if it's cached
+#[derive(Serialize)]
+pub struct LuaGuild {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl LuaMessageTrigger {
+    pub fn new(
+        msg: &Message,
+        bot_id: UserId,
+        chain: &[ChainMessage],
+        channel_name: Option<String>,
+        guild_name: Option<String>,
+    ) -> Self {
+        let display_name = msg
+            .member
+            .as_ref()
+            .and_then(|member| member.nick.clone())
+            .unwrap_or_else(|| msg.author.display_name().to_string());
+        Self {
+            message: LuaChainMessage::from(&ChainMessage::from_message(msg)),
+            text: strip_mentions(&msg.content, bot_id),
+            author: LuaAuthor {
+                id: msg.author.id.get().to_string(),
+                name: msg.author.name.clone(),
+                display_name,
+            },
+            channel: LuaChannel {
+                id: msg.channel_id.get().to_string(),
+                name: channel_name,
+            },
+            guild: msg.guild_id.map(|id| LuaGuild {
+                id: id.get().to_string(),
+                name: guild_name,
+            }),
+            messages: chain.iter().map(LuaChainMessage::from).collect(),
+        }
+    }
+}
+
+/// Removes mentions of `user_id` from a message's content, in either of the forms Discord uses
+fn strip_mentions(content: &str, user_id: UserId) -> String {
+    content
+        .replace(&format!("<@{user_id}>"), "")
+        .replace(&format!("<@!{user_id}>"), "")
+        .trim()
+        .to_string()
+}
+
 /// Build a message chain by walking up the reference chain
 pub async fn build_message_chain(
     http: &Http,
@@ -146,3 +237,72 @@ pub async fn build_message_chain(
     chain.reverse();
     Ok(chain)
 }
+
+/// Build a message chain from the messages in a conversation thread, up to and including
+/// `latest`. The message the thread was opened on comes first, if it's still there.
+pub async fn build_thread_chain(
+    http: &Http,
+    starter: Option<&Message>,
+    latest: &Message,
+    max_messages: usize,
+) -> anyhow::Result<Vec<ChainMessage>> {
+    // Pages come newest first
+    let mut messages: Vec<Message> = vec![];
+    while messages.len() < max_messages {
+        let before = messages.last().map_or(latest.id, |message| message.id);
+        let limit = (max_messages - messages.len()).min(MESSAGES_PER_PAGE);
+        let page = latest
+            .channel_id
+            .messages(http, GetMessages::new().before(before).limit(limit as u8))
+            .await?;
+        let exhausted = page.len() < limit;
+        messages.extend(page);
+        if exhausted {
+            break;
+        }
+    }
+
+    let mut chain: Vec<_> = starter
+        .into_iter()
+        .map(ChainMessage::from_message)
+        .collect();
+    chain.extend(
+        messages
+            .iter()
+            .rev()
+            // Leave out system messages, like the one announcing the thread
+            .filter(|message| {
+                matches!(
+                    message.kind,
+                    MessageType::Regular | MessageType::InlineReply
+                )
+            })
+            .map(ChainMessage::from_message),
+    );
+    chain.push(ChainMessage::from_message(latest));
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_mentions() {
+        let bot = UserId::new(42);
+        assert_eq!(strip_mentions("<@42> hello there", bot), "hello there");
+        assert_eq!(
+            strip_mentions("hey <@!42>, what's up?", bot),
+            "hey , what's up?"
+        );
+        assert_eq!(
+            strip_mentions("ask <@7> about it", bot),
+            "ask <@7> about it"
+        );
+        assert_eq!(strip_mentions("<@42>", bot), "");
+        assert_eq!(
+            strip_mentions("<@42>\nline one\nline two", bot),
+            "line one\nline two"
+        );
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use serenity::all::{GuildId, UserId};
+
+use crate::config;
+
+/// How often changed scopes are written out
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Who a piece of stored data belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Global,
+    User(UserId),
+    Guild(GuildId),
+}
+impl Scope {
+    fn file_name(self) -> String {
+        match self {
+            Scope::Global => "global.json".to_string(),
+            Scope::User(id) => format!("user-{id}.json"),
+            Scope::Guild(id) => format!("guild-{id}.json"),
+        }
+    }
+
+    fn from_file_name(file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(".json")?;
+        if stem == "global" {
+            return Some(Scope::Global);
+        }
+        if let Some(id) = stem.strip_prefix("user-") {
+            return Some(Scope::User(UserId::new(id.parse().ok()?)));
+        }
+        if let Some(id) = stem.strip_prefix("guild-") {
+            return Some(Scope::Guild(GuildId::new(id.parse().ok()?)));
+        }
+        None
+    }
+}
+
+/// Namespace -> key -> value
+type ScopeData = BTreeMap<String, BTreeMap<String, serde_json::Value>>;
+
+/// Persistent key-value store for scripts.
+///
+/// Each scope is kept in its own JSON file, loaded on first use and rewritten in full by
+/// [`Storage::flush_periodically`] shortly after it changes. Quotas are measured in bytes of that
+/// file, so they bound disk usage directly.
+pub struct Storage {
+    config: config::Storage,
+    state: Mutex<State>,
+    /// Held while flushing, so an older copy of a scope can't be written over a newer one
+    flushing: Mutex<()>,
+}
+
+struct State {
+    /// Scopes that have been loaded from disk or written to. Scopes that are only read and have
+    /// no file are not kept, so looking up arbitrary users doesn't grow this.
+    loaded: HashMap<Scope, ScopeData>,
+    /// Size of every scope once written, loaded or not
+    sizes: HashMap<Scope, usize>,
+    /// Scopes that have changed since they were last written
+    unsaved: HashSet<Scope>,
+}
+
+impl Storage {
+    pub fn load(config: config::Storage) -> anyhow::Result<Self> {
+        let directory = Path::new(&config.directory);
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("failed to create storage directory {directory:?}"))?;
+
+        let mut sizes = HashMap::new();
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            if let Some(scope) = entry.file_name().to_str().and_then(Scope::from_file_name) {
+                sizes.insert(scope, entry.metadata()?.len() as usize);
+            }
+        }
+
+        Ok(Self {
+            config,
+            state: Mutex::new(State {
+                loaded: HashMap::new(),
+                sizes,
+                unsaved: HashSet::new(),
+            }),
+            flushing: Mutex::new(()),
+        })
+    }
+
+    pub fn get(
+        &self,
+        scope: Scope,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        self.with_scope(scope, |data| {
+            Ok(data.get(namespace).and_then(|ns| ns.get(key)).cloned())
+        })
+    }
+
+    /// Returns the keys in `namespace` that start with `prefix`, in sorted order
+    pub fn list(&self, scope: Scope, namespace: &str, prefix: &str) -> anyhow::Result<Vec<String>> {
+        self.with_scope(scope, |data| {
+            Ok(data
+                .get(namespace)
+                .map(|ns| {
+                    ns.range(prefix.to_string()..)
+                        .take_while(|(k, _)| k.starts_with(prefix))
+                        .map(|(k, _)| k.clone())
+                        .collect()
+                })
+                .unwrap_or_default())
+        })
+    }
+
+    pub fn set(
+        &self,
+        scope: Scope,
+        namespace: &str,
+        key: &str,
+        value: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        validate_name("namespace", namespace)?;
+        validate_name("key", key)?;
+        let value_size = serde_json::to_vec(&value)?.len();
+        if self.config.max_value_bytes > 0 && value_size > self.config.max_value_bytes {
+            anyhow::bail!(
+                "value is {value_size} bytes, which exceeds the limit of {} bytes",
+                self.config.max_value_bytes
+            );
+        }
+
+        self.modify(scope, |data| {
+            let previous = data
+                .entry(namespace.to_string())
+                .or_default()
+                .insert(key.to_string(), value);
+            // Undoes the change if it can't be persisted
+            Some(move |data: &mut ScopeData| {
+                let ns = data.get_mut(namespace).unwrap();
+                match previous {
+                    Some(previous) => {
+                        ns.insert(key.to_string(), previous);
+                    }
+                    None => {
+                        ns.remove(key);
+                        if ns.is_empty() {
+                            data.remove(namespace);
+                        }
+                    }
+                }
+            })
+        })
+        .map(|_| ())
+    }
+
+    /// Removes `key` from `namespace`, returning whether it existed
+    pub fn delete(&self, scope: Scope, namespace: &str, key: &str) -> anyhow::Result<bool> {
+        self.modify(scope, |data| {
+            let ns = data.get_mut(namespace)?;
+            let previous = ns.remove(key)?;
+            if ns.is_empty() {
+                data.remove(namespace);
+            }
+            Some(move |data: &mut ScopeData| {
+                data.entry(namespace.to_string())
+                    .or_default()
+                    .insert(key.to_string(), previous);
+            })
+        })
+    }
+
+    /// Writes out the changed scopes whenever there are any. Runs forever.
+    pub async fn flush_periodically(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+
+            let storage = self.clone();
+            match tokio::task::spawn_blocking(move || storage.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => eprintln!("Failed to save storage: {err:?}"),
+                Err(err) => eprintln!("Storage flush panicked: {err:?}"),
+            }
+        }
+    }
+
+    /// Writes every changed scope to disk. Each scope is serialised under the lock but written
+    /// outside it; scopes that fail to write are retried on the next flush.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        let pending: Vec<_> = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            state
+                .unsaved
+                .drain()
+                .filter_map(|scope| Some((scope, serde_json::to_vec(state.loaded.get(&scope)?))))
+                .collect()
+        };
+
+        let mut result = Ok(());
+        for (scope, serialized) in pending {
+            if let Err(err) = serialized
+                .map_err(anyhow::Error::from)
+                .and_then(|serialized| write_atomically(&self.path(scope), &serialized))
+            {
+                self.state.lock().unwrap().unsaved.insert(scope);
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    fn with_scope<T>(
+        &self,
+        scope: Scope,
+        f: impl FnOnce(&ScopeData) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if let Some(data) = self.state.lock().unwrap().loaded.get(&scope) {
+            return f(data);
+        }
+
+        let read = self.read_scope(scope)?;
+        let mut state = self.state.lock().unwrap();
+        match read {
+            Some(data) => f(state.loaded.entry(scope).or_insert(data)),
+            // Another thread may have written to the scope while it was being read
+            None => f(state.loaded.get(&scope).unwrap_or(&ScopeData::new())),
+        }
+    }
+
+    /// Applies `change` to the scope and marks it for writing, checking quotas first.
+    /// `change` returns a function that reverts it, used if the result is rejected, or `None` if
+    /// it left the scope untouched. Returns whether anything was changed.
+    fn modify<U: FnOnce(&mut ScopeData)>(
+        &self,
+        scope: Scope,
+        change: impl FnOnce(&mut ScopeData) -> Option<U>,
+    ) -> anyhow::Result<bool> {
+        let loaded = self.state.lock().unwrap().loaded.contains_key(&scope);
+        let read = if loaded {
+            None
+        } else {
+            self.read_scope(scope)?
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let data = state
+            .loaded
+            .entry(scope)
+            .or_insert_with(|| read.unwrap_or_default());
+        let result = match change(data) {
+            Some(undo) => self
+                .check_quotas(scope, data, &state.sizes)
+                .map(Some)
+                .inspect_err(|_| undo(data)),
+            None => Ok(None),
+        };
+        // Don't keep scopes around that were never written and have nothing in them
+        if data.is_empty() && !state.sizes.contains_key(&scope) {
+            state.loaded.remove(&scope);
+        }
+
+        let Some(new_size) = result? else {
+            return Ok(false);
+        };
+        state.sizes.insert(scope, new_size);
+        state.unsaved.insert(scope);
+        Ok(true)
+    }
+
+    /// Returns the size `data` will be written at, if that fits within the quotas
+    fn check_quotas(
+        &self,
+        scope: Scope,
+        data: &ScopeData,
+        sizes: &HashMap<Scope, usize>,
+    ) -> anyhow::Result<usize> {
+        let new_size = serde_json::to_vec(data)?.len();
+        let old_size = sizes.get(&scope).copied().unwrap_or(0);
+
+        // Shrinking is always allowed, so that data over a lowered quota can still be deleted
+        if new_size > old_size {
+            if self.config.max_scope_bytes > 0 && new_size > self.config.max_scope_bytes {
+                anyhow::bail!(
+                    "storage quota exceeded: this scope would use {new_size} bytes, and the limit is {} bytes",
+                    self.config.max_scope_bytes
+                );
+            }
+            let total = sizes.values().sum::<usize>() - old_size + new_size;
+            if self.config.max_total_bytes > 0 && total > self.config.max_total_bytes {
+                anyhow::bail!("storage is full");
+            }
+        }
+        Ok(new_size)
+    }
+
+    /// Reads a scope from disk without holding the lock, returning `None` if it has no file
+    fn read_scope(&self, scope: Scope) -> anyhow::Result<Option<ScopeData>> {
+        let path = self.path(scope);
+        match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .with_context(|| format!("failed to parse {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read {path:?}")),
+        }
+    }
+
+    fn path(&self, scope: Scope) -> PathBuf {
+        Path::new(&self.config.directory).join(scope.file_name())
+    }
+}
+
+fn validate_name(kind: &str, name: &str) -> anyhow::Result<()> {
+    const MAX_LENGTH: usize = 256;
+    if name.is_empty() {
+        anyhow::bail!("{kind} must not be empty");
+    }
+    if name.len() > MAX_LENGTH {
+        anyhow::bail!("{kind} must be at most {MAX_LENGTH} bytes long");
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file and renames it over `path`, so a crash can't leave a partial file
+pub fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    std::fs::write(&temporary_path, contents)
+        .with_context(|| format!("failed to write {temporary_path:?}"))?;
+    std::fs::rename(&temporary_path, path)
+        .with_context(|| format!("failed to replace {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn storage(name: &str, max_scope_bytes: usize) -> Storage {
+        let directory = std::env::temp_dir().join(format!(
+            "paxcord-storage-test-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        Storage::load(config::Storage {
+            directory: directory.to_string_lossy().into_owned(),
+            max_value_bytes: 100,
+            max_scope_bytes,
+            max_total_bytes: 0,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_set_get_delete_persists() {
+        let storage = storage("persist", 0);
+        let user = Scope::User(UserId::new(1));
+        storage.set(user, "notes", "a", json!({"x": 1})).unwrap();
+        assert_eq!(
+            storage.get(user, "notes", "a").unwrap(),
+            Some(json!({"x": 1}))
+        );
+        assert_eq!(storage.get(Scope::Global, "notes", "a").unwrap(), None);
+
+        storage.flush().unwrap();
+        let reloaded = Storage::load(storage.config.clone()).unwrap();
+        assert_eq!(
+            reloaded.get(user, "notes", "a").unwrap(),
+            Some(json!({"x": 1}))
+        );
+
+        assert!(reloaded.delete(user, "notes", "a").unwrap());
+        assert!(!reloaded.delete(user, "notes", "a").unwrap());
+        assert_eq!(reloaded.get(user, "notes", "a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_reads_of_missing_scopes_are_not_kept() {
+        let storage = storage("misses", 0);
+        for id in 1..=10 {
+            let user = Scope::User(UserId::new(id));
+            assert_eq!(storage.get(user, "notes", "a").unwrap(), None);
+            assert!(storage.list(user, "notes", "").unwrap().is_empty());
+            assert!(!storage.delete(user, "notes", "a").unwrap());
+        }
+        assert!(storage.state.lock().unwrap().loaded.is_empty());
+
+        let user = Scope::User(UserId::new(1));
+        storage.set(user, "notes", "a", json!(1)).unwrap();
+        assert!(!storage.path(user).exists());
+        storage.flush().unwrap();
+        assert!(storage.path(user).exists());
+        assert_eq!(storage.state.lock().unwrap().loaded.len(), 1);
+    }
+
+    #[test]
+    fn test_list_by_prefix() {
+        let storage = storage("list", 0);
+        for key in ["apple", "apricot", "banana", "ap"] {
+            storage
+                .set(Scope::Global, "fruit", key, json!(true))
+                .unwrap();
+        }
+        storage
+            .set(Scope::Global, "other", "apple", json!(true))
+            .unwrap();
+        assert_eq!(
+            storage.list(Scope::Global, "fruit", "ap").unwrap(),
+            vec!["ap", "apple", "apricot"]
+        );
+        assert_eq!(storage.list(Scope::Global, "fruit", "").unwrap().len(), 4);
+        assert!(
+            storage
+                .list(Scope::Global, "missing", "")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_quotas() {
+        let storage = storage("quota", 64);
+        let guild = Scope::Guild(GuildId::new(1));
+        assert!(
+            storage
+                .set(guild, "ns", "big", json!("x".repeat(200)))
+                .is_err()
+        );
+
+        storage
+            .set(guild, "ns", "a", json!("x".repeat(30)))
+            .unwrap();
+        let err = storage
+            .set(guild, "ns", "b", json!("y".repeat(30)))
+            .unwrap_err();
+        assert!(err.to_string().contains("quota"), "{err}");
+        // The rejected value must not linger in memory
+        assert_eq!(storage.get(guild, "ns", "b").unwrap(), None);
+        assert_eq!(storage.list(guild, "ns", "").unwrap(), vec!["a"]);
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, UserId};
+
+use crate::{config, storage::write_atomically};
+
+/// How often changed records are written out
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What one or more executions consumed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Usage {
+    /// Executions that ran to completion, were cancelled or failed
+    pub invocations: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Workflows run through ComfyUI and images made through the images endpoint
+    pub image_generations: u64,
+    pub wall_time_ms: u64,
+}
+impl Usage {
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.invocations += other.invocations;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.image_generations += other.image_generations;
+        self.wall_time_ms += other.wall_time_ms;
+    }
+}
+
+/// A resource that can have a daily budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Tokens,
+    ImageGenerations,
+}
+impl Resource {
+    fn used(self, usage: &Usage) -> u64 {
+        match self {
+            Resource::Tokens => usage.tokens(),
+            Resource::ImageGenerations => usage.image_generations,
+        }
+    }
+
+    fn limit(self, budget: &config::Budget) -> u64 {
+        match self {
+            Resource::Tokens => budget.daily_tokens,
+            Resource::ImageGenerations => budget.daily_image_generations,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Resource::Tokens => "tokens",
+            Resource::ImageGenerations => "image generations",
+        }
+    }
+}
+
+/// A daily budget that has been used up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    pub resource: Resource,
+    pub limit: u64,
+    /// Whether it's the server's budget rather than the user's
+    pub guild: bool,
+}
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} daily budget of {} {} has been used up; it resets at midnight UTC.",
+            if self.guild { "This server's" } else { "Your" },
+            self.limit,
+            self.resource.name()
+        )
+    }
+}
+impl std::error::Error for BudgetExceeded {}
+
+/// A user's usage, as shown by `/usage` and returned by `usage.get`
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct UsageReport {
+    pub today: Usage,
+    /// Usage over every day that is still retained
+    pub total: Usage,
+    pub retention_days: u64,
+    /// Today's usage, by command
+    pub commands: BTreeMap<String, Usage>,
+    /// How much of each daily budget is left today, if there is one
+    pub remaining_tokens: Option<u64>,
+    pub remaining_image_generations: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    /// Days since the Unix epoch, in UTC
+    day: u64,
+    user_id: UserId,
+    guild_id: Option<GuildId>,
+    command: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    key: Key,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+/// Records what each user consumes, by day, server and command, and enforces the daily budgets.
+///
+/// The records are kept in memory and rewritten to a single JSON file by
+/// [`UsageTracker::flush_periodically`] shortly after they change; days older than the retention
+/// period are dropped as they're recorded.
+pub struct UsageTracker {
+    config: config::Usage,
+    path: PathBuf,
+    entries: Mutex<HashMap<Key, Usage>>,
+    /// Whether the records have changed since they were last written
+    dirty: AtomicBool,
+}
+impl UsageTracker {
+    pub fn load(config: config::Usage) -> anyhow::Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let entries = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<Vec<Entry>>(&contents)
+                .with_context(|| format!("failed to parse usage records at {path:?}"))?
+                .into_iter()
+                .map(|entry| (entry.key, entry.usage))
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {path:?}"));
+            }
+        };
+
+        Ok(Self {
+            config,
+            path,
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Adds to what a user has consumed today
+    pub fn record(&self, user_id: UserId, guild_id: Option<GuildId>, command: &str, usage: Usage) {
+        self.record_on(today(), user_id, guild_id, command, usage);
+    }
+
+    fn record_on(
+        &self,
+        day: u64,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        command: &str,
+        usage: Usage,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .entry(Key {
+                day,
+                user_id,
+                guild_id,
+                command: command.to_string(),
+            })
+            .or_default()
+            .add(&usage);
+
+        let retention_days = self.config.retention_days;
+        if retention_days > 0 {
+            entries.retain(|key, _| day.saturating_sub(key.day) < retention_days);
+        }
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Writes the records out whenever they've changed. Runs forever.
+    pub async fn flush_periodically(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+
+            let tracker = self.clone();
+            match tokio::task::spawn_blocking(move || tracker.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => eprintln!("Failed to save usage records: {err:?}"),
+                Err(err) => eprintln!("Usage record flush panicked: {err:?}"),
+            }
+        }
+    }
+
+    /// Checks that neither the user nor the server has used up today's budget for `resource`
+    pub fn check_budget(
+        &self,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        resource: Resource,
+    ) -> Result<(), BudgetExceeded> {
+        self.check_budget_on(today(), user_id, guild_id, resource)
+    }
+
+    fn check_budget_on(
+        &self,
+        day: u64,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        resource: Resource,
+    ) -> Result<(), BudgetExceeded> {
+        let entries = self.entries.lock().unwrap();
+
+        let limit = resource.limit(&self.config.user_budget);
+        if limit > 0 {
+            let used = sum(&entries, |key| key.day == day && key.user_id == user_id);
+            if resource.used(&used) >= limit {
+                return Err(BudgetExceeded {
+                    resource,
+                    limit,
+                    guild: false,
+                });
+            }
+        }
+
+        let limit = resource.limit(&self.config.guild_budget);
+        if let Some(guild_id) = guild_id
+            && limit > 0
+        {
+            let used = sum(&entries, |key| {
+                key.day == day && key.guild_id == Some(guild_id)
+            });
+            if resource.used(&used) >= limit {
+                return Err(BudgetExceeded {
+                    resource,
+                    limit,
+                    guild: true,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn report(&self, user_id: UserId) -> UsageReport {
+        self.report_on(today(), user_id)
+    }
+
+    fn report_on(&self, day: u64, user_id: UserId) -> UsageReport {
+        let entries = self.entries.lock().unwrap();
+
+        let mut report = UsageReport {
+            retention_days: self.config.retention_days,
+            ..Default::default()
+        };
+        for (key, usage) in entries.iter().filter(|(key, _)| key.user_id == user_id) {
+            report.total.add(usage);
+            if key.day == day {
+                report.today.add(usage);
+                report
+                    .commands
+                    .entry(key.command.clone())
+                    .or_default()
+                    .add(usage);
+            }
+        }
+
+        let remaining = |resource: Resource| {
+            let limit = resource.limit(&self.config.user_budget);
+            (limit > 0).then(|| limit.saturating_sub(resource.used(&report.today)))
+        };
+        report.remaining_tokens = remaining(Resource::Tokens);
+        report.remaining_image_generations = remaining(Resource::ImageGenerations);
+        report
+    }
+
+    /// Writes the records out if they've changed since they were last written. The file is
+    /// written outside the lock, so that recording isn't held up by it.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut records: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, usage)| Entry {
+                key: key.clone(),
+                usage: *usage,
+            })
+            .collect();
+        records.sort_by(|a, b| {
+            (a.key.day, a.key.user_id, &a.key.command).cmp(&(
+                b.key.day,
+                b.key.user_id,
+                &b.key.command,
+            ))
+        });
+        let result = serde_json::to_vec(&records)
+            .map_err(anyhow::Error::from)
+            .and_then(|serialized| write_atomically(&self.path, &serialized));
+        if result.is_err() {
+            // Try again next time
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+}
+
+fn sum(entries: &HashMap<Key, Usage>, filter: impl Fn(&Key) -> bool) -> Usage {
+    let mut total = Usage::default();
+    for (_, usage) in entries.iter().filter(|(key, _)| filter(key)) {
+        total.add(usage);
+    }
+    total
+}
+
+/// Days since the Unix epoch, in UTC
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / (24 * 60 * 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(
+        name: &str,
+        user_budget: config::Budget,
+        guild_budget: config::Budget,
+    ) -> UsageTracker {
+        let path = std::env::temp_dir().join(format!(
+            "paxcord-usage-test-{name}-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        UsageTracker::load(config::Usage {
+            path: path.to_string_lossy().to_string(),
+            retention_days: 7,
+            user_budget,
+            guild_budget,
+        })
+        .unwrap()
+    }
+
+    fn tokens(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_budgets() {
+        let tracker = tracker(
+            "budgets",
+            config::Budget {
+                daily_tokens: 100,
+                daily_image_generations: 0,
+            },
+            config::Budget {
+                daily_tokens: 150,
+                daily_image_generations: 0,
+            },
+        );
+        let (alice, bob) = (UserId::new(1), UserId::new(2));
+        let guild = Some(GuildId::new(10));
+
+        tracker.record_on(5, alice, guild, "ask", tokens(40, 59));
+        assert_eq!(
+            tracker.check_budget_on(5, alice, guild, Resource::Tokens),
+            Ok(())
+        );
+
+        tracker.record_on(5, alice, None, "ask", tokens(0, 1));
+        let exceeded = tracker
+            .check_budget_on(5, alice, None, Resource::Tokens)
+            .unwrap_err();
+        assert!(!exceeded.guild);
+        // Budgets are daily, and image generations have none
+        assert_eq!(
+            tracker.check_budget_on(6, alice, None, Resource::Tokens),
+            Ok(())
+        );
+        assert_eq!(
+            tracker.check_budget_on(5, alice, None, Resource::ImageGenerations),
+            Ok(())
+        );
+
+        // Only the 99 tokens alice used in the server count towards its budget
+        tracker.record_on(5, bob, guild, "ask", tokens(50, 0));
+        assert_eq!(
+            tracker.check_budget_on(5, bob, guild, Resource::Tokens),
+            Ok(())
+        );
+        tracker.record_on(5, bob, guild, "ask", tokens(1, 0));
+        assert!(
+            tracker
+                .check_budget_on(5, bob, guild, Resource::Tokens)
+                .unwrap_err()
+                .guild
+        );
+    }
+
+    #[test]
+    fn test_report_and_persistence() {
+        let tracker = tracker(
+            "report",
+            config::Budget {
+                daily_tokens: 1000,
+                daily_image_generations: 0,
+            },
+            config::Budget::default(),
+        );
+        let user = UserId::new(1);
+
+        tracker.record_on(1, user, None, "ask", tokens(500, 500));
+        tracker.record_on(
+            9,
+            user,
+            None,
+            "paint",
+            Usage {
+                invocations: 1,
+                image_generations: 2,
+                ..Default::default()
+            },
+        );
+        tracker.record_on(9, user, None, "ask", tokens(100, 200));
+        tracker.record_on(9, UserId::new(2), None, "ask", tokens(1, 1));
+
+        // Day 1 is outside the 7-day retention window by day 9
+        let report = tracker.report_on(9, user);
+        assert_eq!(report.total, report.today);
+        assert_eq!(report.today.tokens(), 300);
+        assert_eq!(report.today.image_generations, 2);
+        assert_eq!(report.commands.len(), 2);
+        assert_eq!(report.remaining_tokens, Some(700));
+        assert_eq!(report.remaining_image_generations, None);
+
+        tracker.flush().unwrap();
+        let reloaded = UsageTracker::load(tracker.config.clone()).unwrap();
+        assert_eq!(reloaded.report_on(9, user).today, report.today);
+        let _ = std::fs::remove_file(&tracker.path);
+    }
+}
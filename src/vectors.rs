@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{config, storage::write_atomically};
+
+/// A vector and whatever the script wants back when it's found
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    vector: Vec<f32>,
+    #[serde(default)]
+    metadata: serde_json::Value,
+}
+
+/// Id -> entry
+type Index = BTreeMap<String, Entry>;
+
+/// An entry found by [`VectorIndexes::search`]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub id: String,
+    /// Cosine similarity to the query, from -1 to 1
+    pub score: f32,
+    pub metadata: serde_json::Value,
+}
+
+/// Named vector indexes for scripts to search by cosine similarity.
+///
+/// Each index is kept in its own JSON file, loaded on first use and rewritten in full on every
+/// change, or by [`VectorIndexes::flush`] if loaded with [`VectorIndexes::load_batched`].
+/// Searches compare against every entry, which is fast enough for the few thousand entries a
+/// script is likely to keep.
+pub struct VectorIndexes {
+    config: config::Vectors,
+    loaded: Mutex<HashMap<String, Index>>,
+    /// Indexes changed since they were last written, if changes are batched
+    unsaved: Option<Mutex<HashSet<String>>>,
+}
+impl VectorIndexes {
+    pub fn load(config: config::Vectors) -> anyhow::Result<Self> {
+        Self::load_with(config, None)
+    }
+
+    /// Like [`Self::load`], but changes are only written out by [`Self::flush`]
+    pub fn load_batched(config: config::Vectors) -> anyhow::Result<Self> {
+        Self::load_with(config, Some(Mutex::default()))
+    }
+
+    fn load_with(
+        config: config::Vectors,
+        unsaved: Option<Mutex<HashSet<String>>>,
+    ) -> anyhow::Result<Self> {
+        let directory = Path::new(&config.directory);
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("failed to create vector directory {directory:?}"))?;
+
+        Ok(Self {
+            config,
+            loaded: Mutex::new(HashMap::new()),
+            unsaved,
+        })
+    }
+
+    /// Adds an entry, replacing any with the same id. Every vector in an index must have the
+    /// same number of dimensions.
+    pub fn add(
+        &self,
+        index: &str,
+        id: &str,
+        vector: Vec<f32>,
+        metadata: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        self.add_with(index, id, vector, metadata, None)
+    }
+
+    /// Like [`Self::add`], but if the index is full, the entry whose metadata `age` ranks
+    /// lowest is removed to make room instead of failing
+    pub fn add_evicting(
+        &self,
+        index: &str,
+        id: &str,
+        vector: Vec<f32>,
+        metadata: serde_json::Value,
+        age: impl Fn(&serde_json::Value) -> i64,
+    ) -> anyhow::Result<()> {
+        self.add_with(index, id, vector, metadata, Some(&age))
+    }
+
+    fn add_with(
+        &self,
+        index: &str,
+        id: &str,
+        vector: Vec<f32>,
+        metadata: serde_json::Value,
+        age: Option<&dyn Fn(&serde_json::Value) -> i64>,
+    ) -> anyhow::Result<()> {
+        if id.is_empty() {
+            anyhow::bail!("id must not be empty");
+        }
+        if vector.is_empty() || vector.iter().any(|x| !x.is_finite()) {
+            anyhow::bail!("vector must be non-empty and only contain finite numbers");
+        }
+
+        self.modify(index, |entries| {
+            // The entry being replaced doesn't count, so a lone entry can be replaced by any vector
+            if let Some((_, existing)) = entries.iter().find(|(key, _)| key.as_str() != id)
+                && existing.vector.len() != vector.len()
+            {
+                anyhow::bail!(
+                    "vector has {} dimensions, but this index's vectors have {}",
+                    vector.len(),
+                    existing.vector.len()
+                );
+            }
+            let max_entries = self.config.max_entries_per_index;
+            if max_entries > 0 && !entries.contains_key(id) && entries.len() >= max_entries {
+                let Some(age) = age else {
+                    anyhow::bail!("index is full; it can hold at most {max_entries} entries");
+                };
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| age(&entry.metadata))
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            entries.insert(id.to_string(), Entry { vector, metadata });
+            Ok(true)
+        })
+    }
+
+    /// Removes an entry, returning whether it existed
+    pub fn remove(&self, index: &str, id: &str) -> anyhow::Result<bool> {
+        let mut removed = false;
+        self.modify(index, |entries| {
+            removed = entries.remove(id).is_some();
+            Ok(removed)
+        })?;
+        Ok(removed)
+    }
+
+    /// Removes every entry whose metadata passes `filter`, returning how many there were
+    pub fn remove_matching(
+        &self,
+        index: &str,
+        filter: impl Fn(&serde_json::Value) -> bool,
+    ) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        self.modify(index, |entries| {
+            let before = entries.len();
+            entries.retain(|_, entry| !filter(&entry.metadata));
+            removed = before - entries.len();
+            Ok(removed > 0)
+        })?;
+        Ok(removed)
+    }
+
+    /// Returns the `k` entries most similar to `query`, most similar first
+    pub fn search(
+        &self,
+        index: &str,
+        query: &[f32],
+        k: usize,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.search_matching(index, query, k, |_| true)
+    }
+
+    /// Like [`Self::search`], but only considers entries whose metadata passes `filter`
+    pub fn search_matching(
+        &self,
+        index: &str,
+        query: &[f32],
+        k: usize,
+        filter: impl Fn(&serde_json::Value) -> bool,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        self.with_index(index, |entries| {
+            let mut results: Vec<_> = entries
+                .iter()
+                .filter(|(_, entry)| entry.vector.len() == query.len() && filter(&entry.metadata))
+                .map(|(id, entry)| SearchResult {
+                    id: id.clone(),
+                    score: cosine_similarity(query, &entry.vector),
+                    metadata: entry.metadata.clone(),
+                })
+                .collect();
+            results.sort_by(|a, b| b.score.total_cmp(&a.score));
+            results.truncate(k);
+            Ok(results)
+        })
+    }
+
+    /// Returns the number of entries in an index
+    pub fn len(&self, index: &str) -> anyhow::Result<usize> {
+        self.with_index(index, |entries| Ok(entries.len()))
+    }
+
+    fn with_index<T>(
+        &self,
+        index: &str,
+        f: impl FnOnce(&Index) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut loaded = self.loaded.lock().unwrap();
+        f(self.load_index(&mut loaded, index)?)
+    }
+
+    /// Writes out the indexes changed since they were last written, if changes are batched.
+    /// Only serialising an index holds the lock; writing it doesn't.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let Some(unsaved) = &self.unsaved else {
+            return Ok(());
+        };
+        let indexes = std::mem::take(&mut *unsaved.lock().unwrap());
+        let mut result = Ok(());
+        for index in indexes {
+            let serialized = self.with_index(&index, |entries| Ok(serde_json::to_vec(entries)?));
+            let written =
+                serialized.and_then(|serialized| write_atomically(&self.path(&index), &serialized));
+            if let Err(err) = written {
+                // Try again next time
+                unsaved.lock().unwrap().insert(index);
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Applies `change` to the index, writing it out (or marking it to be, if changes are
+    /// batched) if `change` returns true
+    fn modify(
+        &self,
+        index: &str,
+        change: impl FnOnce(&mut Index) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let entries = self.load_index(&mut loaded, index)?;
+        if change(entries)? {
+            match &self.unsaved {
+                Some(unsaved) => {
+                    unsaved.lock().unwrap().insert(index.to_string());
+                }
+                None => write_atomically(&self.path(index), &serde_json::to_vec(&*entries)?)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn load_index<'a>(
+        &self,
+        loaded: &'a mut HashMap<String, Index>,
+        index: &str,
+    ) -> anyhow::Result<&'a mut Index> {
+        validate_index_name(index)?;
+        if !loaded.contains_key(index) {
+            let path = self.path(index);
+            let entries = match std::fs::read(&path) {
+                Ok(bytes) => serde_json::from_slice(&bytes)
+                    .with_context(|| format!("failed to parse {path:?}"))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Index::new(),
+                Err(err) => return Err(err).with_context(|| format!("failed to read {path:?}")),
+            };
+            loaded.insert(index.to_string(), entries);
+        }
+        Ok(loaded.get_mut(index).unwrap())
+    }
+
+    fn path(&self, index: &str) -> PathBuf {
+        Path::new(&self.config.directory).join(format!("{index}.json"))
+    }
+}
+
+/// Index names become file names, so they're kept to a safe set of characters
+fn validate_index_name(name: &str) -> anyhow::Result<()> {
+    const MAX_LENGTH: usize = 64;
+    if name.is_empty() || name.len() > MAX_LENGTH {
+        anyhow::bail!("index name must be between 1 and {MAX_LENGTH} bytes long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        anyhow::bail!("index name may only contain letters, digits, `-` and `_`");
+    }
+    Ok(())
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn indexes(name: &str, max_entries_per_index: usize) -> VectorIndexes {
+        let directory = std::env::temp_dir().join(format!(
+            "paxcord-vectors-test-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        VectorIndexes::load(config::Vectors {
+            directory: directory.to_string_lossy().into_owned(),
+            max_entries_per_index,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_search_and_persistence() {
+        let indexes = indexes("search", 0);
+        indexes
+            .add("faq", "north", vec![0.0, 1.0], json!("up"))
+            .unwrap();
+        indexes
+            .add("faq", "east", vec![1.0, 0.0], json!("right"))
+            .unwrap();
+        indexes
+            .add("faq", "northeast", vec![1.0, 1.0], json!(null))
+            .unwrap();
+
+        let results = indexes.search("faq", &[0.1, 1.0], 2).unwrap();
+        let ids: Vec<_> = results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["north", "northeast"]);
+        assert_eq!(results[0].metadata, json!("up"));
+
+        assert!(indexes.remove("faq", "north").unwrap());
+        assert!(!indexes.remove("faq", "north").unwrap());
+
+        let reloaded = VectorIndexes::load(indexes.config.clone()).unwrap();
+        assert_eq!(reloaded.len("faq").unwrap(), 2);
+        assert_eq!(
+            reloaded.search("faq", &[1.0, 0.0], 1).unwrap()[0].id,
+            "east"
+        );
+        let _ = std::fs::remove_dir_all(&indexes.config.directory);
+    }
+
+    #[test]
+    fn test_validation() {
+        let indexes = indexes("validation", 2);
+        assert!(
+            indexes
+                .add("../escape", "a", vec![1.0], json!(null))
+                .is_err()
+        );
+        assert!(indexes.add("ok", "a", vec![], json!(null)).is_err());
+        assert!(indexes.add("ok", "a", vec![f32::NAN], json!(null)).is_err());
+
+        indexes.add("ok", "a", vec![1.0, 0.0], json!(null)).unwrap();
+        // A lone entry can be replaced with a different number of dimensions
+        indexes
+            .add("ok", "a", vec![1.0, 0.0, 0.0], json!(null))
+            .unwrap();
+        assert!(indexes.add("ok", "b", vec![1.0, 0.0], json!(null)).is_err());
+        indexes
+            .add("ok", "b", vec![0.0, 1.0, 0.0], json!(null))
+            .unwrap();
+        assert!(
+            indexes
+                .add("ok", "c", vec![0.0, 0.0, 1.0], json!(null))
+                .is_err()
+        );
+        let _ = std::fs::remove_dir_all(&indexes.config.directory);
+    }
+
+    #[test]
+    fn test_eviction_and_batched_writes() {
+        let config = indexes("batched", 2).config.clone();
+        let indexes = VectorIndexes::load_batched(config.clone()).unwrap();
+        let age = |metadata: &serde_json::Value| metadata.as_i64().unwrap_or_default();
+        for (id, time) in [("b", 2), ("a", 1), ("c", 3)] {
+            indexes
+                .add_evicting("log", id, vec![1.0], json!(time), age)
+                .unwrap();
+        }
+        let ids: Vec<_> = indexes
+            .search("log", &[1.0], 10)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(ids, ["b", "c"]);
+
+        // Nothing is written until the indexes are flushed
+        assert_eq!(
+            VectorIndexes::load(config.clone())
+                .unwrap()
+                .len("log")
+                .unwrap(),
+            0
+        );
+        indexes.flush().unwrap();
+        assert_eq!(
+            VectorIndexes::load(config.clone())
+                .unwrap()
+                .len("log")
+                .unwrap(),
+            2
+        );
+        let _ = std::fs::remove_dir_all(&config.directory);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{borrow::Cow, io::Cursor};
+
+use image::{ImageFormat, imageops::FilterType};
+
+/// Images larger than this on either side are scaled down before being sent. Vision models
+/// downscale anything bigger anyway, so sending the full image only costs upload time and
+/// request size.
+const MAX_DIMENSION: u32 = 2048;
+/// JPEG quality used when re-encoding a scaled down photo
+const JPEG_QUALITY: u8 = 85;
+
+/// The image formats vision models accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisionFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+impl VisionFormat {
+    /// Works out the format from the data's magic bytes
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Self::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Self::Png => ImageFormat::Png,
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Gif => ImageFormat::Gif,
+            Self::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// Encodes image data as a data URL for a vision model, scaling it down first if it's larger
+/// than [`MAX_DIMENSION`]
+pub fn to_data_url(data: &[u8]) -> anyhow::Result<String> {
+    let Some(format) = VisionFormat::sniff(data) else {
+        anyhow::bail!("unsupported image format; expected PNG, JPEG, GIF or WebP");
+    };
+
+    let (format, data) = downscale(data, format)?;
+    let encoded = data_encoding::BASE64.encode(&data);
+    Ok(format!("data:{};base64,{encoded}", format.mime_type()))
+}
+
+/// Scales the image down to fit within [`MAX_DIMENSION`], if it doesn't already. Photos stay
+/// JPEGs; everything else becomes a PNG, keeping only a GIF's first frame.
+fn downscale(data: &[u8], format: VisionFormat) -> anyhow::Result<(VisionFormat, Cow<'_, [u8]>)> {
+    let (width, height) = image::ImageReader::with_format(Cursor::new(data), format.image_format())
+        .into_dimensions()?;
+    if width <= MAX_DIMENSION && height <= MAX_DIMENSION {
+        return Ok((format, Cow::Borrowed(data)));
+    }
+
+    let image = image::load_from_memory_with_format(data, format.image_format())?.resize(
+        MAX_DIMENSION,
+        MAX_DIMENSION,
+        FilterType::Triangle,
+    );
+    let mut output = vec![];
+    let format = if format == VisionFormat::Jpeg {
+        image.into_rgb8().write_with_encoder(
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY),
+        )?;
+        VisionFormat::Jpeg
+    } else {
+        image.write_to(&mut Cursor::new(&mut output), ImageFormat::Png)?;
+        VisionFormat::Png
+    };
+    Ok((format, Cow::Owned(output)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut output = vec![];
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut output), format)
+            .unwrap();
+        output
+    }
+
+    fn decode_data_url(url: &str) -> (String, image::DynamicImage) {
+        let (mime_type, encoded) = url
+            .strip_prefix("data:")
+            .and_then(|url| url.split_once(";base64,"))
+            .unwrap();
+        let data = data_encoding::BASE64.decode(encoded.as_bytes()).unwrap();
+        (
+            mime_type.to_string(),
+            image::load_from_memory(&data).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(
+            VisionFormat::sniff(&encode(1, 1, ImageFormat::Png)),
+            Some(VisionFormat::Png)
+        );
+        assert_eq!(
+            VisionFormat::sniff(&encode(1, 1, ImageFormat::Jpeg)),
+            Some(VisionFormat::Jpeg)
+        );
+        assert_eq!(
+            VisionFormat::sniff(&encode(1, 1, ImageFormat::Gif)),
+            Some(VisionFormat::Gif)
+        );
+        assert_eq!(
+            VisionFormat::sniff(&encode(1, 1, ImageFormat::WebP)),
+            Some(VisionFormat::Webp)
+        );
+        assert_eq!(VisionFormat::sniff(b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(VisionFormat::sniff(b""), None);
+    }
+
+    #[test]
+    fn test_small_images_pass_through() {
+        let data = encode(64, 32, ImageFormat::WebP);
+        let url = to_data_url(&data).unwrap();
+        assert_eq!(
+            url,
+            format!(
+                "data:image/webp;base64,{}",
+                data_encoding::BASE64.encode(&data)
+            )
+        );
+    }
+
+    #[test]
+    fn test_large_images_are_downscaled() {
+        let (mime_type, image) =
+            decode_data_url(&to_data_url(&encode(4032, 3024, ImageFormat::Jpeg)).unwrap());
+        assert_eq!(mime_type, "image/jpeg");
+        assert_eq!((image.width(), image.height()), (2048, 1536));
+
+        let (mime_type, image) =
+            decode_data_url(&to_data_url(&encode(1000, 3000, ImageFormat::Gif)).unwrap());
+        assert_eq!(mime_type, "image/png");
+        assert_eq!((image.width(), image.height()), (683, 2048));
+    }
+
+    #[test]
+    fn test_unsupported_formats_are_rejected() {
+        assert!(to_data_url(b"BM not really a bitmap").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+/// Opus granule positions always count samples at 48 kHz, whatever the input's rate was
+const OPUS_SAMPLE_RATE: f64 = 48_000.0;
+/// The most waveform samples Discord will show
+const MAX_WAVEFORM_SAMPLES: usize = 256;
+
+/// What Discord needs to show an Ogg Opus attachment as a voice message
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceMetadata {
+    pub duration_secs: f64,
+    /// Loudness over time, from 0 to 255
+    pub waveform: Vec<u8>,
+}
+impl VoiceMetadata {
+    /// Reads the duration and an approximate waveform from an Ogg Opus file.
+    ///
+    /// The audio isn't decoded; instead, the waveform follows the size of the Opus packets,
+    /// which is small for silence and grows with the amount of sound being encoded.
+    pub fn from_ogg_opus(data: &[u8]) -> anyhow::Result<Self> {
+        let (packets, last_granule) = read_ogg_packets(data)?;
+        let mut packets = packets.into_iter();
+
+        let head = packets
+            .next()
+            .filter(|head| head.starts_with(b"OpusHead") && head.len() >= 19)
+            .ok_or_else(|| anyhow::anyhow!("not an Ogg Opus file"))?;
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]);
+        // The second packet holds the comments; everything after it is audio
+        let sizes: Vec<usize> = packets.skip(1).map(|packet| packet.len()).collect();
+        if sizes.is_empty() {
+            anyhow::bail!("the file has no audio");
+        }
+
+        let samples = last_granule.saturating_sub(u64::from(pre_skip));
+        Ok(Self {
+            duration_secs: samples as f64 / OPUS_SAMPLE_RATE,
+            waveform: waveform(&sizes),
+        })
+    }
+}
+
+/// Splits an Ogg stream into its packets, also returning the final granule position
+fn read_ogg_packets(data: &[u8]) -> anyhow::Result<(Vec<Vec<u8>>, u64)> {
+    const HEADER_LENGTH: usize = 27;
+
+    let mut packets = vec![];
+    let mut current = vec![];
+    let mut last_granule = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data
+            .get(offset..offset + HEADER_LENGTH)
+            .filter(|header| header.starts_with(b"OggS"))
+            .ok_or_else(|| anyhow::anyhow!("invalid Ogg page at byte {offset}"))?;
+        let granule = i64::from_le_bytes(header[6..14].try_into().unwrap());
+        // -1 marks a page on which no packet finishes
+        if granule >= 0 {
+            last_granule = last_granule.max(granule as u64);
+        }
+
+        let segment_count = usize::from(header[26]);
+        let segments = data
+            .get(offset + HEADER_LENGTH..offset + HEADER_LENGTH + segment_count)
+            .ok_or_else(|| anyhow::anyhow!("truncated Ogg page at byte {offset}"))?;
+        offset += HEADER_LENGTH + segment_count;
+
+        for &length in segments {
+            let length = usize::from(length);
+            let segment = data
+                .get(offset..offset + length)
+                .ok_or_else(|| anyhow::anyhow!("truncated Ogg page at byte {offset}"))?;
+            current.extend_from_slice(segment);
+            offset += length;
+            // A segment shorter than 255 bytes ends its packet
+            if length < 255 {
+                packets.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    Ok((packets, last_granule))
+}
+
+/// Averages the packet sizes into at most [`MAX_WAVEFORM_SAMPLES`] buckets, scaled so that the
+/// largest is 255
+fn waveform(sizes: &[usize]) -> Vec<u8> {
+    let buckets = sizes.len().min(MAX_WAVEFORM_SAMPLES);
+    let averages: Vec<f64> = (0..buckets)
+        .map(|i| {
+            let bucket = &sizes[i * sizes.len() / buckets..(i + 1) * sizes.len() / buckets];
+            bucket.iter().sum::<usize>() as f64 / bucket.len() as f64
+        })
+        .collect();
+    let max = averages.iter().copied().fold(0.0, f64::max);
+    averages
+        .into_iter()
+        .map(|average| {
+            if max > 0.0 {
+                (average / max * 255.0).round() as u8
+            } else {
+                0
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an Ogg page holding the given packets, each of which must be under 255 bytes
+    fn page(granule: i64, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend([0, 0]);
+        page.extend(granule.to_le_bytes());
+        page.extend([0; 12]);
+        page.push(packets.len() as u8);
+        page.extend(packets.iter().map(|packet| packet.len() as u8));
+        for packet in packets {
+            page.extend(packet);
+        }
+        page
+    }
+
+    fn opus_head(pre_skip: u16) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend([1, 1]);
+        head.extend(pre_skip.to_le_bytes());
+        head.extend([0; 7]);
+        head
+    }
+
+    #[test]
+    fn test_from_ogg_opus() {
+        let audio: Vec<Vec<u8>> = [3, 3, 100, 200, 100, 3].map(|size| vec![0; size]).into();
+        let data = [
+            page(0, &[opus_head(312)]),
+            page(0, &[b"OpusTags".to_vec()]),
+            page(96_312, &audio),
+        ]
+        .concat();
+
+        let metadata = VoiceMetadata::from_ogg_opus(&data).unwrap();
+        assert_eq!(metadata.duration_secs, 2.0);
+        assert_eq!(metadata.waveform, [4, 4, 128, 255, 128, 4]);
+    }
+
+    #[test]
+    fn test_from_ogg_opus_rejects_other_files() {
+        assert!(VoiceMetadata::from_ogg_opus(b"ID3\x03 not an ogg file").is_err());
+        let vorbis = [page(0, &[b"\x01vorbis".to_vec()]), page(0, &[vec![1]])].concat();
+        assert!(VoiceMetadata::from_ogg_opus(&vorbis).is_err());
+    }
+
+    #[test]
+    fn test_waveform_buckets() {
+        let sizes: Vec<usize> = (0..1024).map(|i| if i < 512 { 10 } else { 20 }).collect();
+        let waveform = waveform(&sizes);
+        assert_eq!(waveform.len(), MAX_WAVEFORM_SAMPLES);
+        assert_eq!(waveform[0], 128);
+        assert_eq!(waveform[255], 255);
+    }
+}